@@ -0,0 +1,368 @@
+use crate::formats::{decoder_for, image_dimensions, image_reader, open_image, FormatDecoder};
+use crate::image_processing::{resize_thumbnail, thumbnail_cache_key, to_data_url};
+use crate::models::{AppState, ThumbnailSettings};
+use crate::utils::get_variant_cache_path;
+use gif::{DecodeOptions, Repeat};
+use image::codecs::gif::{GifDecoder, GifEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat, ImageOutputFormat};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use tauri::State;
+
+/// アニメーションプレビューに含める最大のフレーム数
+pub const MAX_PREVIEW_FRAMES: usize = 200;
+
+/// プレビューのGIFの減色の速さ (1-30、大きいほど速く粗い)
+const PREVIEW_GIF_SPEED: i32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnimationInfo {
+    pub width: u32,
+    pub height: u32,
+    pub frame_count: usize,
+    /// フレームごとの表示時間 (ミリ秒)。ファイルに書かれた値をそのまま返すので0もありうる。
+    pub delays: Vec<u32>,
+    /// 再生する回数。0は無限ループ。
+    pub loop_count: u32,
+}
+
+impl AnimationInfo {
+    fn still(width: u32, height: u32) -> Self {
+        AnimationInfo { width, height, frame_count: 1, delays: vec![0], loop_count: 1 }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frame_count > 1
+    }
+}
+
+fn image_format(path: &str) -> Result<Option<ImageFormat>, String> {
+    match decoder_for(Path::new(path)) {
+        Some(FormatDecoder::Heif | FormatDecoder::Raw | FormatDecoder::Svg | FormatDecoder::Pdf | FormatDecoder::Video) => Ok(None),
+        _ => image_reader(path).map(|reader| reader.format()),
+    }
+}
+
+/// フレームをデコードせずに、フレーム数・表示時間・ループ回数を調べる。
+/// GIFとWebP以外の画像は1フレームの画像として扱う。
+pub fn read_animation_info(path: &str) -> Result<AnimationInfo, String> {
+    match image_format(path)? {
+        Some(ImageFormat::Gif) => read_gif_info(path),
+        Some(ImageFormat::WebP) => read_webp_info(&fs::read(path).map_err(|e| e.to_string())?),
+        _ => {
+            let (width, height) = image_dimensions(path)?;
+            Ok(AnimationInfo::still(width, height))
+        }
+    }
+}
+
+fn read_gif_info(path: &str) -> Result<AnimationInfo, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = DecodeOptions::new().read_info(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.next_frame_info().map_err(|e| e.to_string())? {
+        // GIFの表示時間は1/100秒単位
+        delays.push(frame.delay as u32 * 10);
+    }
+    // NETSCAPE拡張の値は「初回の後に繰り返す回数」なので、再生回数に直す
+    let loop_count = match decoder.repeat() {
+        Repeat::Infinite => 0,
+        Repeat::Finite(repeat) => repeat as u32 + 1,
+    };
+    Ok(AnimationInfo {
+        width: decoder.width() as u32,
+        height: decoder.height() as u32,
+        frame_count: delays.len(),
+        delays,
+        loop_count,
+    })
+}
+
+fn read_u24(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16
+}
+
+/// RIFFのチャンクを順に読み、VP8X・ANIM・ANMFチャンクから情報を取り出す。
+fn read_webp_info(data: &[u8]) -> Result<AnimationInfo, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("Invalid WebP file".to_string());
+    }
+    let mut canvas = None;
+    let mut loop_count = 0;
+    let mut delays = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let fourcc = &data[offset..offset + 4];
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let payload = data.get(offset + 8..offset + 8 + size).ok_or("Truncated WebP chunk")?;
+        match fourcc {
+            b"VP8X" if size >= 10 => canvas = Some((read_u24(&payload[4..7]) + 1, read_u24(&payload[7..10]) + 1)),
+            b"ANIM" if size >= 6 => loop_count = u16::from_le_bytes([payload[4], payload[5]]) as u32,
+            b"ANMF" if size >= 16 => delays.push(read_u24(&payload[12..15])),
+            _ => {}
+        }
+        // チャンクは偶数バイトに揃えられている
+        offset += 8 + size + (size & 1);
+    }
+
+    match canvas {
+        Some((width, height)) if !delays.is_empty() => Ok(AnimationInfo {
+            width,
+            height,
+            frame_count: delays.len(),
+            delays,
+            loop_count,
+        }),
+        _ => {
+            let (width, height) = image::load_from_memory_with_format(data, ImageFormat::WebP)
+                .map(|image| (image.width(), image.height()))
+                .map_err(|e| e.to_string())?;
+            Ok(AnimationInfo::still(width, height))
+        }
+    }
+}
+
+/// 合成済みのフレームを順に返す。アニメーションでない画像は1フレームとして返す。
+fn decode_frames(path: &str) -> Result<Box<dyn Iterator<Item = Result<DynamicImage, String>>>, String> {
+    let reader = || File::open(path).map(BufReader::new).map_err(|e| e.to_string());
+    let frames = match image_format(path)? {
+        Some(ImageFormat::Gif) => GifDecoder::new(reader()?).map_err(|e| e.to_string())?.into_frames(),
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader()?).map_err(|e| e.to_string())?;
+            if decoder.has_animation() {
+                decoder.into_frames()
+            } else {
+                let image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+                return Ok(Box::new(std::iter::once(Ok(image))));
+            }
+        }
+        _ => {
+            let image = open_image(path)?;
+            return Ok(Box::new(std::iter::once(Ok(image))));
+        }
+    };
+    Ok(Box::new(frames.map(|frame| {
+        frame
+            .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()))
+            .map_err(|e| e.to_string())
+    })))
+}
+
+/// `index` 番目のフレームをPNGのデータURLで返す。
+/// 先頭から合成し直すので、コマ送りには前後のフレームをまとめて要求する方が速い。
+pub fn extract_frame(path: &str, index: usize) -> Result<String, String> {
+    let frame = decode_frames(path)?
+        .nth(index)
+        .ok_or_else(|| format!("Frame {} out of range", index))??;
+    let mut buffer = Vec::new();
+    frame.write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(to_data_url(&buffer))
+}
+
+/// 静止画のサムネイルと同じキーに `animated` を付けて区別する。
+fn animated_thumbnail_cache_path(path: &str, settings: &ThumbnailSettings) -> PathBuf {
+    get_variant_cache_path(path, &format!("animated{}", thumbnail_cache_key(path, settings)), "gif")
+}
+
+/// 縮小したフレームを無限ループのGIFにまとめたプレビューを作る。
+/// 長いアニメーションは先頭の `MAX_PREVIEW_FRAMES` フレームだけを使う。
+pub fn create_animated_thumbnail(path: &str, settings: &ThumbnailSettings) -> Result<String, String> {
+    let cache_path = animated_thumbnail_cache_path(path, settings);
+    if cache_path.exists() {
+        return fs::read(&cache_path)
+            .map(|cached| to_data_url(&cached))
+            .map_err(|e| e.to_string());
+    }
+
+    let info = read_animation_info(path)?;
+    if !info.is_animated() {
+        return Err("Not an animated image".to_string());
+    }
+
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buffer, PREVIEW_GIF_SPEED);
+        encoder.set_repeat(image::codecs::gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+        for (frame, delay) in decode_frames(path)?.zip(&info.delays).take(MAX_PREVIEW_FRAMES) {
+            let thumbnail = resize_thumbnail(&frame?, settings).to_rgba8();
+            let delay = image::Delay::from_numer_denom_ms(*delay, 1);
+            encoder.encode_frame(Frame::from_parts(thumbnail, 0, 0, delay)).map_err(|e| e.to_string())?;
+        }
+    }
+
+    fs::write(&cache_path, &buffer).map_err(|e| e.to_string())?;
+    Ok(to_data_url(&buffer))
+}
+
+#[tauri::command]
+pub async fn get_animation_info(path: String) -> Result<AnimationInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || read_animation_info(&path))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn get_animation_frame(path: String, index: usize) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || extract_frame(&path, index))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn generate_animated_thumbnail(path: String, state: State<'_, AppState>) -> Result<String, String> {
+    let settings = state.thumbnail_settings.lock().unwrap().clone();
+    tauri::async_runtime::spawn_blocking(move || create_animated_thumbnail(&path, &settings))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, Rgba, RgbaImage};
+    use tempfile::TempDir;
+
+    fn solid_frame(color: [u8; 4], delay_ms: u32) -> Frame {
+        Frame::from_parts(
+            RgbaImage::from_pixel(40, 30, Rgba(color)),
+            0,
+            0,
+            Delay::from_numer_denom_ms(delay_ms, 1),
+        )
+    }
+
+    fn write_gif(path: &std::path::Path, repeat: image::codecs::gif::Repeat) {
+        let mut encoder = GifEncoder::new(File::create(path).unwrap());
+        encoder.set_repeat(repeat).unwrap();
+        encoder.encode_frames(vec![
+            solid_frame([255, 0, 0, 255], 100),
+            solid_frame([0, 255, 0, 255], 200),
+            solid_frame([0, 0, 255, 255], 50),
+        ]).unwrap();
+    }
+
+    fn chunk(fourcc: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = fourcc.to_vec();
+        chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn u24(value: u32) -> [u8; 3] {
+        let bytes = value.to_le_bytes();
+        [bytes[0], bytes[1], bytes[2]]
+    }
+
+    /// 可逆WebPで書き出したVP8Lチャンクを、ANMFチャンクに入れてアニメーションWebPを組み立てる
+    fn animated_webp(colors: &[[u8; 4]], delay_ms: u32, loop_count: u16) -> Vec<u8> {
+        let (width, height) = (40, 30);
+        let mut vp8x = vec![0x12, 0, 0, 0];
+        vp8x.extend_from_slice(&u24(width - 1));
+        vp8x.extend_from_slice(&u24(height - 1));
+        let mut anim = vec![0, 0, 0, 0];
+        anim.extend_from_slice(&loop_count.to_le_bytes());
+
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &vp8x));
+        body.extend(chunk(b"ANIM", &anim));
+        for color in colors {
+            let mut still = Vec::new();
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(*color)))
+                .write_to(&mut Cursor::new(&mut still), ImageOutputFormat::WebP)
+                .unwrap();
+            let mut anmf = Vec::new();
+            anmf.extend_from_slice(&u24(0));
+            anmf.extend_from_slice(&u24(0));
+            anmf.extend_from_slice(&u24(width - 1));
+            anmf.extend_from_slice(&u24(height - 1));
+            anmf.extend_from_slice(&u24(delay_ms));
+            anmf.push(0);
+            // RIFFヘッダーの後ろがVP8Lチャンク
+            anmf.extend_from_slice(&still[12..]);
+            body.extend(chunk(b"ANMF", &anmf));
+        }
+
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn decode_data_url(data_url: &str) -> DynamicImage {
+        use base64::{engine::general_purpose, Engine as _};
+        let encoded = data_url.split_once(";base64,").unwrap().1;
+        image::load_from_memory(&general_purpose::STANDARD.decode(encoded).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_gif_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("anim.gif");
+        write_gif(&path, image::codecs::gif::Repeat::Infinite);
+        let info = read_animation_info(path.to_str().unwrap()).unwrap();
+        assert_eq!(info, AnimationInfo { width: 40, height: 30, frame_count: 3, delays: vec![100, 200, 50], loop_count: 0 });
+
+        write_gif(&path, image::codecs::gif::Repeat::Finite(2));
+        assert_eq!(read_animation_info(path.to_str().unwrap()).unwrap().loop_count, 3);
+    }
+
+    #[test]
+    fn test_webp_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("anim.webp");
+        fs::write(&path, animated_webp(&[[255, 0, 0, 255], [0, 0, 255, 255]], 120, 4)).unwrap();
+        let info = read_animation_info(path.to_str().unwrap()).unwrap();
+        assert_eq!(info, AnimationInfo { width: 40, height: 30, frame_count: 2, delays: vec![120, 120], loop_count: 4 });
+
+        let still = temp_dir.path().join("still.webp");
+        DynamicImage::ImageRgba8(RgbaImage::new(8, 6)).save(&still).unwrap();
+        assert_eq!(read_animation_info(still.to_str().unwrap()).unwrap(), AnimationInfo::still(8, 6));
+    }
+
+    #[test]
+    fn test_extract_frame() {
+        let temp_dir = TempDir::new().unwrap();
+        let gif = temp_dir.path().join("anim.gif");
+        write_gif(&gif, image::codecs::gif::Repeat::Infinite);
+        let frame = decode_data_url(&extract_frame(gif.to_str().unwrap(), 1).unwrap());
+        assert_eq!(frame.to_rgba8().get_pixel(10, 10).0, [0, 255, 0, 255]);
+        assert!(extract_frame(gif.to_str().unwrap(), 3).is_err());
+
+        let webp = temp_dir.path().join("anim.webp");
+        fs::write(&webp, animated_webp(&[[255, 0, 0, 255], [0, 0, 255, 255]], 120, 0)).unwrap();
+        let frame = decode_data_url(&extract_frame(webp.to_str().unwrap(), 1).unwrap());
+        assert_eq!(frame.to_rgba8().get_pixel(10, 10).0, [0, 0, 255, 255]);
+
+        let png = temp_dir.path().join("still.png");
+        RgbaImage::from_pixel(4, 4, Rgba([1, 2, 3, 255])).save(&png).unwrap();
+        assert!(extract_frame(png.to_str().unwrap(), 0).is_ok());
+        assert!(extract_frame(png.to_str().unwrap(), 1).is_err());
+    }
+
+    #[test]
+    fn test_animated_thumbnail() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("anim.gif");
+        write_gif(&path, image::codecs::gif::Repeat::Finite(1));
+        let settings = ThumbnailSettings::default();
+
+        let thumbnail = create_animated_thumbnail(path.to_str().unwrap(), &settings).unwrap();
+        assert!(thumbnail.starts_with("data:image/gif;base64,"));
+        let cache_path = animated_thumbnail_cache_path(path.to_str().unwrap(), &settings);
+        let info = read_animation_info(cache_path.to_str().unwrap()).unwrap();
+        assert_eq!(info.frame_count, 3);
+        assert_eq!(info.delays, vec![100, 200, 50]);
+        assert_eq!(info.loop_count, 0);
+        fs::remove_file(cache_path).unwrap();
+
+        let still = temp_dir.path().join("still.png");
+        RgbaImage::new(4, 4).save(&still).unwrap();
+        assert!(create_animated_thumbnail(still.to_str().unwrap(), &settings).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use crate::models::{SortBy, SortOrder};
+use crate::utils::is_image;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// 書庫から取り出す1ファイルの大きさの上限。圧縮爆弾でメモリを使い切らないようにする。
+const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    SevenZ,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    /// 書庫内のパス。区切りは `/`
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+impl ArchiveEntry {
+    pub fn file_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+}
+
+/// 仮想フォルダとして開く書庫の拡張子 (小文字) と種類
+const ARCHIVE_KINDS: &[(&str, ArchiveKind)] = &[
+    ("zip", ArchiveKind::Zip),
+    ("cbz", ArchiveKind::Zip),
+    ("tar", ArchiveKind::Tar),
+    ("cbt", ArchiveKind::Tar),
+    ("7z", ArchiveKind::SevenZ),
+    ("cb7", ArchiveKind::SevenZ),
+];
+
+/// 書庫の種類を拡張子から決める。
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    ARCHIVE_KINDS.iter().find(|(known, _)| *known == extension).map(|(_, kind)| *kind)
+}
+
+/// 書庫として開く拡張子の一覧。フロントエンドが書庫の中のパスを見分けるのに使う。
+pub fn archive_extensions() -> Vec<&'static str> {
+    ARCHIVE_KINDS.iter().map(|(extension, _)| *extension).collect()
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+/// 書庫の中を指すパス (`/photos/book.cbz/ch1/001.jpg`) を書庫のパスと書庫内のパス (`ch1/001.jpg`) に分ける。
+/// 書庫そのもののパスなら書庫内のパスは空になる。書庫の中を指していなければ `None`。
+pub fn split_path(path: &Path) -> Option<(PathBuf, String)> {
+    let archive = path.ancestors().find(|ancestor| is_archive(ancestor) && ancestor.is_file())?;
+    let entry = path.strip_prefix(archive).ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((archive.to_path_buf(), entry))
+}
+
+/// 更新日時などを調べるための実際のファイルのパス。書庫の中なら書庫のパスになる。
+pub fn source_path(path: &Path) -> PathBuf {
+    split_path(path).map(|(archive, _)| archive).unwrap_or_else(|| path.to_path_buf())
+}
+
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(source_path(path)).and_then(|meta| meta.modified()).ok()
+}
+
+/// 書庫ごとの区切り文字や先頭の `./` の違いをなくす。
+fn normalize_name(name: &str) -> String {
+    name.replace('\\', "/")
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn read_limited<R: Read>(reader: R) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader.take(MAX_ENTRY_SIZE + 1).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    if data.len() as u64 > MAX_ENTRY_SIZE {
+        return Err("Archive entry is too large".to_string());
+    }
+    Ok(data)
+}
+
+/// 書庫内の全エントリー。フォルダのエントリーが無い書庫もあるので、フォルダは `list_dir` でファイル名から補う。
+pub fn list_entries(archive: &Path) -> Result<Vec<ArchiveEntry>, String> {
+    let kind = archive_kind(archive).ok_or("Not an archive")?;
+    let file = File::open(archive).map_err(|e| e.to_string())?;
+    let mut entries = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index_raw(index).map_err(|e| e.to_string())?;
+                entries.push(ArchiveEntry { name: normalize_name(entry.name()), size: entry.size(), is_dir: entry.is_dir() });
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut tar = tar::Archive::new(BufReader::new(file));
+            for entry in tar.entries().map_err(|e| e.to_string())? {
+                let entry = entry.map_err(|e| e.to_string())?;
+                let name = entry.path().map_err(|e| e.to_string())?.to_string_lossy().into_owned();
+                let entry_type = entry.header().entry_type();
+                if entry_type.is_file() || entry_type.is_dir() {
+                    entries.push(ArchiveEntry { name: normalize_name(&name), size: entry.size(), is_dir: entry_type.is_dir() });
+                }
+            }
+        }
+        ArchiveKind::SevenZ => {
+            let len = file.metadata().map_err(|e| e.to_string())?.len();
+            let reader = sevenz_rust::SevenZReader::new(BufReader::new(file), len, sevenz_rust::Password::empty())
+                .map_err(|e| e.to_string())?;
+            for entry in &reader.archive().files {
+                entries.push(ArchiveEntry { name: normalize_name(&entry.name), size: entry.size, is_dir: entry.is_directory });
+            }
+        }
+    }
+    entries.retain(|entry| !entry.name.is_empty());
+    Ok(entries)
+}
+
+/// 書庫から1ファイルを取り出す。TARと7zは先頭から順に読むので、後ろのエントリーほど時間がかかる。
+pub fn read_entry(archive: &Path, name: &str) -> Result<Vec<u8>, String> {
+    let kind = archive_kind(archive).ok_or("Not an archive")?;
+    let file = File::open(archive).map_err(|e| e.to_string())?;
+    let not_found = || format!("{} not found in {}", name, archive.display());
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+            let index = (0..zip.len())
+                .find(|index| zip.by_index_raw(*index).is_ok_and(|entry| !entry.is_dir() && normalize_name(entry.name()) == name))
+                .ok_or_else(not_found)?;
+            let entry = zip.by_index(index).map_err(|e| e.to_string())?;
+            read_limited(entry)
+        }
+        ArchiveKind::Tar => {
+            let mut tar = tar::Archive::new(BufReader::new(file));
+            for entry in tar.entries().map_err(|e| e.to_string())? {
+                let entry = entry.map_err(|e| e.to_string())?;
+                let matches = entry.header().entry_type().is_file()
+                    && entry.path().is_ok_and(|path| normalize_name(&path.to_string_lossy()) == name);
+                if matches {
+                    return read_limited(entry);
+                }
+            }
+            Err(not_found())
+        }
+        ArchiveKind::SevenZ => {
+            let len = file.metadata().map_err(|e| e.to_string())?.len();
+            let mut reader = sevenz_rust::SevenZReader::new(BufReader::new(file), len, sevenz_rust::Password::empty())
+                .map_err(|e| e.to_string())?;
+            let mut data = None;
+            reader.for_each_entries(|entry, entry_reader| {
+                if entry.is_directory || normalize_name(&entry.name) != name {
+                    // ソリッド書庫は前のエントリーを展開しないと先に進めない
+                    io::copy(entry_reader, &mut io::sink())?;
+                    return Ok(true);
+                }
+                data = Some(read_limited(entry_reader));
+                Ok(false)
+            }).map_err(|e| e.to_string())?;
+            data.ok_or_else(not_found)?
+        }
+    }
+}
+
+/// 書庫を先頭から1回だけ読み、`names` にあるファイルを見つけるたびに名前と中身を `visit` に渡す。
+/// TARと7zで `read_entry` を繰り返すと毎回先頭から読み直すので、複数のファイルを取り出すときに使う。
+pub fn for_each_entry<F: FnMut(&str, Vec<u8>)>(archive: &Path, names: &HashSet<String>, mut visit: F) -> Result<(), String> {
+    let kind = archive_kind(archive).ok_or("Not an archive")?;
+    let file = File::open(archive).map_err(|e| e.to_string())?;
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index).map_err(|e| e.to_string())?;
+                let name = normalize_name(entry.name());
+                if !entry.is_dir() && names.contains(&name) {
+                    visit(&name, read_limited(entry)?);
+                }
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut tar = tar::Archive::new(BufReader::new(file));
+            for entry in tar.entries().map_err(|e| e.to_string())? {
+                let entry = entry.map_err(|e| e.to_string())?;
+                let name = normalize_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+                if entry.header().entry_type().is_file() && names.contains(&name) {
+                    visit(&name, read_limited(entry)?);
+                }
+            }
+        }
+        ArchiveKind::SevenZ => {
+            let len = file.metadata().map_err(|e| e.to_string())?.len();
+            let mut reader = sevenz_rust::SevenZReader::new(BufReader::new(file), len, sevenz_rust::Password::empty())
+                .map_err(|e| e.to_string())?;
+            let mut error = None;
+            reader.for_each_entries(|entry, entry_reader| {
+                let name = normalize_name(&entry.name);
+                if entry.is_directory || !names.contains(&name) {
+                    io::copy(entry_reader, &mut io::sink())?;
+                    return Ok(true);
+                }
+                match read_limited(entry_reader) {
+                    Ok(data) => visit(&name, data),
+                    Err(e) => error = Some(e),
+                }
+                Ok(error.is_none())
+            }).map_err(|e| e.to_string())?;
+            if let Some(e) = error {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// ファイルを読む。書庫の中を指していれば書庫から取り出す。
+pub fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    match split_path(path) {
+        Some((archive, entry)) => read_entry(&archive, &entry),
+        None => fs::read(path).map_err(|e| e.to_string()),
+    }
+}
+
+/// 書庫内のフォルダ `dir` の直下にあるファイルとフォルダ
+pub fn list_dir(archive: &Path, dir: &str) -> Result<Vec<ArchiveEntry>, String> {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+    let mut files = Vec::new();
+    let mut dirs = BTreeSet::new();
+    for entry in list_entries(archive)? {
+        let Some(relative) = entry.name.strip_prefix(&prefix) else { continue };
+        match relative.split_once('/') {
+            Some((child, _)) => {
+                dirs.insert(child.to_string());
+            }
+            None if entry.is_dir => {
+                dirs.insert(relative.to_string());
+            }
+            None => files.push(entry),
+        }
+    }
+    let mut children: Vec<ArchiveEntry> = dirs.into_iter()
+        .map(|child| ArchiveEntry { name: format!("{}{}", prefix, child), size: 0, is_dir: true })
+        .collect();
+    children.extend(files);
+    Ok(children)
+}
+
+/// 書庫内のフォルダ `dir` の直下の画像を並べ替えて、仮想パスで返す。
+/// 書庫内の画像の更新日時はすべて書庫のものとみなすので、日付順は名前順になる。
+pub fn list_images(archive: &Path, dir: &str, sort_by: &SortBy, sort_order: &SortOrder) -> Result<Vec<String>, String> {
+    let mut images: Vec<ArchiveEntry> = list_dir(archive, dir)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && is_image(Path::new(&entry.name)))
+        .collect();
+    images.sort_by(|a, b| {
+        let ordering = match sort_by {
+            SortBy::Name | SortBy::Date => a.file_name().cmp(b.file_name()),
+            SortBy::Type => {
+                let ext_a = Path::new(&a.name).extension().and_then(|s| s.to_str()).unwrap_or("");
+                let ext_b = Path::new(&b.name).extension().and_then(|s| s.to_str()).unwrap_or("");
+                ext_a.cmp(ext_b)
+            },
+            SortBy::Size => a.size.cmp(&b.size),
+        };
+        match sort_order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+    Ok(images.into_iter()
+        .map(|entry| archive.join(&entry.name).to_string_lossy().into_owned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::io::{Cursor, Write};
+    use tempfile::TempDir;
+
+    fn png(width: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        RgbImage::from_fn(width, 4, |x, y| image::Rgb([(x * 37 + y * 11) as u8, (x * x) as u8, y as u8]))
+            .write_to(&mut Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+        data
+    }
+
+    const NAMES: &[&str] = &["b.png", "a.png", "notes.txt", "ch2/c.png"];
+
+    fn write_zip(path: &Path) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (index, name) in NAMES.iter().enumerate() {
+            zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&png((index as u32 + 1) * 16)).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar(path: &Path) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (index, name) in NAMES.iter().enumerate() {
+            let data = png((index as u32 + 1) * 16);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, format!("./{}", name), data.as_slice()).unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    fn write_7z(path: &Path) {
+        let mut writer = sevenz_rust::SevenZWriter::create(path).unwrap();
+        for (index, name) in NAMES.iter().enumerate() {
+            let mut entry = sevenz_rust::SevenZArchiveEntry::new();
+            entry.name = name.to_string();
+            entry.has_stream = true;
+            writer.push_archive_entry(entry, Some(png((index as u32 + 1) * 16).as_slice())).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_split_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = temp_dir.path().join("book.cbz");
+        write_zip(&archive);
+
+        assert_eq!(split_path(&archive.join("ch2").join("c.png")), Some((archive.clone(), "ch2/c.png".to_string())));
+        assert_eq!(split_path(&archive), Some((archive.clone(), String::new())));
+        assert_eq!(source_path(&archive.join("a.png")), archive);
+        // 書庫の拡張子でもフォルダなら書庫として扱わない
+        let folder = temp_dir.path().join("folder.zip");
+        fs::create_dir(&folder).unwrap();
+        assert_eq!(split_path(&folder.join("a.png")), None);
+        assert_eq!(normalize_name(".\\ch1\\/001.jpg"), "ch1/001.jpg");
+    }
+
+    #[test]
+    fn test_archives() {
+        let temp_dir = TempDir::new().unwrap();
+        for name in ["photos.zip", "photos.tar", "photos.7z"] {
+            let archive = temp_dir.path().join(name);
+            match archive_kind(&archive).unwrap() {
+                ArchiveKind::Zip => write_zip(&archive),
+                ArchiveKind::Tar => write_tar(&archive),
+                ArchiveKind::SevenZ => write_7z(&archive),
+            }
+
+            let children = list_dir(&archive, "").unwrap();
+            let names: Vec<&str> = children.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["ch2", "b.png", "a.png", "notes.txt"], "{}", name);
+            assert!(children[0].is_dir);
+
+            let images = list_images(&archive, "", &SortBy::Name, &SortOrder::Asc).unwrap();
+            let expected: Vec<String> = ["a.png", "b.png"].iter().map(|image| archive.join(image).to_string_lossy().into_owned()).collect();
+            assert_eq!(images, expected, "{}", name);
+            let by_size = list_images(&archive, "", &SortBy::Size, &SortOrder::Desc).unwrap();
+            assert_eq!(by_size, expected, "{}", name);
+            assert_eq!(list_images(&archive, "ch2", &SortBy::Name, &SortOrder::Asc).unwrap().len(), 1);
+
+            let data = read_file(&archive.join("ch2").join("c.png")).unwrap();
+            assert_eq!(image::load_from_memory(&data).unwrap().width(), 64, "{}", name);
+            assert!(read_entry(&archive, "missing.png").is_err());
+
+            let names: HashSet<String> = ["a.png", "ch2/c.png", "missing.png"].map(String::from).into();
+            let mut visited = Vec::new();
+            for_each_entry(&archive, &names, |name, data| visited.push((name.to_string(), image::load_from_memory(&data).unwrap().width()))).unwrap();
+            visited.sort();
+            assert_eq!(visited, [("a.png".to_string(), 32), ("ch2/c.png".to_string(), 64)], "{}", name);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use crate::export::{export_image_impl, ExportError, ExportOptions};
+use crate::models::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tauri::{State, Window};
+use log::{error, info};
+
+pub const DEFAULT_BATCH_WORKERS: usize = 4;
+
+pub const BATCH_PROGRESS_EVENT: &str = "batch-progress";
+pub const BATCH_COMPLETE_EVENT: &str = "batch-complete";
+
+/// 実行中のジョブのIDと取り消しフラグ
+pub type BatchJobs = Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>;
+
+/// 一括変換の手順。各画像を `options` で書き出し、`rename` から作った名前で `output_dir` に保存する。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchRecipe {
+    pub output_dir: String,
+    #[serde(flatten)]
+    pub options: ExportOptions,
+    /// 拡張子を除いた出力ファイル名。`{name}` は元のファイル名 (拡張子なし)、
+    /// `{index}` は1始まりの通し番号 (全体の桁数に0埋め) に置き換える。
+    #[serde(default = "default_rename")]
+    pub rename: String,
+}
+
+fn default_rename() -> String {
+    "{name}".to_string()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchItemResult {
+    pub job_id: String,
+    pub source: String,
+    pub destination: String,
+    pub error: Option<ExportError>,
+    /// 処理済みの件数 (このファイルを含む)
+    pub completed: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchSummary {
+    pub job_id: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// 取り消しにより処理しなかった件数
+    pub skipped: usize,
+    pub cancelled: bool,
+    pub elapsed_ms: u64,
+    /// 書き込んだレポートのパス
+    pub report: Option<String>,
+    /// ジョブ全体が失敗した理由。出力先を作れなかった場合や、処理中に異常終了した場合。
+    pub error: Option<String>,
+}
+
+/// `output_dir` に書き込むJSONのレポート
+#[derive(Serialize)]
+pub struct BatchReport<'a> {
+    pub finished_at: u64,
+    pub recipe: &'a BatchRecipe,
+    pub summary: &'a BatchSummary,
+    pub results: &'a [BatchItemResult],
+}
+
+pub enum BatchEvent {
+    Item(BatchItemResult),
+    Complete(BatchSummary),
+}
+
+/// 出力ファイル名を決める。ジョブ内で名前が重複したら `-2`, `-3` ... を付ける。
+pub fn plan_destinations(paths: &[String], recipe: &BatchRecipe) -> Vec<String> {
+    let width = paths.len().to_string().len();
+    let extension = recipe.options.format.extension();
+    let mut used = HashSet::new();
+    paths.iter().enumerate().map(|(index, path)| {
+        let name = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let base = recipe.rename
+            .replace("{name}", &name)
+            .replace("{index}", &format!("{:0width$}", index + 1, width = width));
+        let mut file_name = format!("{}.{}", base, extension);
+        let mut suffix = 2;
+        while !used.insert(file_name.to_lowercase()) {
+            file_name = format!("{}-{}.{}", base, suffix, extension);
+            suffix += 1;
+        }
+        Path::new(&recipe.output_dir).join(file_name).to_string_lossy().into_owned()
+    }).collect()
+}
+
+fn validate_recipe(recipe: &BatchRecipe) -> Result<(), String> {
+    if recipe.rename.trim().is_empty() || recipe.rename.contains(['/', '\\']) {
+        return Err(format!("Invalid rename pattern: {:?}", recipe.rename));
+    }
+    Ok(())
+}
+
+fn write_report(job_id: &str, recipe: &BatchRecipe, summary: &BatchSummary, results: &[BatchItemResult]) -> Result<String, String> {
+    let file_name: String = job_id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    let path = Path::new(&recipe.output_dir).join(format!("batch-report-{}.json", file_name));
+    let report = BatchReport {
+        finished_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        recipe,
+        summary,
+        results,
+    };
+    let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    fs::write(&path, json).map_err(|e| e.to_string())?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// `workers` 本のスレッドで画像を順に書き出し、1件ごとと最後に `emit` を呼ぶ。
+/// `cancel` が立つと、処理中のファイルを終えたところで止める。
+pub fn run_batch_job<F>(job_id: &str, paths: Vec<String>, recipe: &BatchRecipe, workers: usize, cancel: &AtomicBool, emit: F) -> BatchSummary
+where
+    F: Fn(BatchEvent) + Sync,
+{
+    let started = Instant::now();
+    let destinations = plan_destinations(&paths, recipe);
+    let total = paths.len();
+    let next = AtomicUsize::new(0);
+    let completed = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(total));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, total.max(1)) {
+            scope.spawn(|| loop {
+                if cancel.load(Ordering::SeqCst) {
+                    return;
+                }
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let (Some(source), Some(destination)) = (paths.get(index), destinations.get(index)) else {
+                    return;
+                };
+                let error = export_image_impl(source, destination, &recipe.options).err();
+                let result = BatchItemResult {
+                    job_id: job_id.to_string(),
+                    source: source.clone(),
+                    destination: destination.clone(),
+                    error,
+                    completed: completed.fetch_add(1, Ordering::SeqCst) + 1,
+                    total,
+                };
+                results.lock().unwrap().push(result.clone());
+                emit(BatchEvent::Item(result));
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let failed = results.iter().filter(|result| result.error.is_some()).count();
+    let mut summary = BatchSummary {
+        job_id: job_id.to_string(),
+        succeeded: results.len() - failed,
+        failed,
+        skipped: total - results.len(),
+        cancelled: cancel.load(Ordering::SeqCst),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        report: None,
+        error: None,
+    };
+    match write_report(job_id, recipe, &summary, &results) {
+        Ok(path) => summary.report = Some(path),
+        Err(e) => error!("Failed to write batch report: {}", e),
+    }
+    info!("Batch job {} finished: {} succeeded, {} failed, {} skipped", job_id, summary.succeeded, summary.failed, summary.skipped);
+    emit(BatchEvent::Complete(summary.clone()));
+    summary
+}
+
+/// ジョブの登録を必ず消す。完了を通知しないまま終わった場合 (ワーカーのパニックなど) は失敗として通知する。
+struct BatchJobGuard<'a, F: Fn(BatchEvent)> {
+    job_id: &'a str,
+    jobs: &'a BatchJobs,
+    total: usize,
+    started: Instant,
+    emit: &'a F,
+    notified: bool,
+}
+
+impl<F: Fn(BatchEvent)> BatchJobGuard<'_, F> {
+    fn fail(&mut self, error: String) {
+        error!("Batch job {} failed: {}", self.job_id, error);
+        self.notified = true;
+        (self.emit)(BatchEvent::Complete(BatchSummary {
+            job_id: self.job_id.to_string(),
+            succeeded: 0,
+            failed: 0,
+            skipped: self.total,
+            cancelled: false,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            report: None,
+            error: Some(error),
+        }));
+    }
+}
+
+impl<F: Fn(BatchEvent)> Drop for BatchJobGuard<'_, F> {
+    fn drop(&mut self) {
+        if !self.notified {
+            self.fail("Batch job stopped unexpectedly".to_string());
+        }
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.remove(self.job_id);
+        }
+    }
+}
+
+/// 出力先を作ってから `run_batch_job` を実行し、終わったら `jobs` から取り除く。
+fn run_registered_job<F>(job_id: &str, jobs: &BatchJobs, paths: Vec<String>, recipe: &BatchRecipe, cancel: &AtomicBool, emit: F)
+where
+    F: Fn(BatchEvent) + Sync,
+{
+    let mut guard = BatchJobGuard { job_id, jobs, total: paths.len(), started: Instant::now(), emit: &emit, notified: false };
+    if let Err(e) = fs::create_dir_all(&recipe.output_dir) {
+        guard.fail(e.to_string());
+        return;
+    }
+    run_batch_job(job_id, paths, recipe, DEFAULT_BATCH_WORKERS, cancel, &emit);
+    guard.notified = true;
+}
+
+/// 一括変換を開始する。進み具合は1件ごとに `batch-progress` イベントで、
+/// 終了は `batch-complete` イベントで呼び出し元のウィンドウに送られる。
+/// 画像の一覧には `get_full_image_list` の結果を渡す。
+#[tauri::command]
+pub fn start_batch_job(job_id: String, paths: Vec<String>, recipe: BatchRecipe, window: Window, state: State<'_, AppState>) -> Result<usize, String> {
+    validate_recipe(&recipe)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let mut jobs = state.batch_jobs.lock().unwrap();
+        if jobs.contains_key(&job_id) {
+            return Err(format!("Batch job is already running: {}", job_id));
+        }
+        jobs.insert(job_id.clone(), cancel.clone());
+    }
+
+    let count = paths.len();
+    let jobs = state.batch_jobs.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        run_registered_job(&job_id, &jobs, paths, &recipe, &cancel, |event| {
+            let result = match event {
+                BatchEvent::Item(item) => window.emit(BATCH_PROGRESS_EVENT, item),
+                BatchEvent::Complete(summary) => window.emit(BATCH_COMPLETE_EVENT, summary),
+            };
+            if let Err(e) = result {
+                error!("Failed to emit batch event: {:?}", e);
+            }
+        });
+    });
+    Ok(count)
+}
+
+/// 実行中のジョブを取り消す。ジョブが見つからなければ `false`。
+#[tauri::command]
+pub fn cancel_batch_job(job_id: String, state: State<'_, AppState>) -> bool {
+    match state.batch_jobs.lock().unwrap().get(&job_id) {
+        Some(cancel) => {
+            cancel.store(true, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{ExportFormat, ExportResize};
+    use image::RgbImage;
+    use tempfile::TempDir;
+
+    fn recipe(output_dir: &Path, rename: &str) -> BatchRecipe {
+        let mut options = ExportOptions::new(ExportFormat::Jpeg);
+        options.resize = Some(ExportResize { max_width: Some(32), max_height: Some(32) });
+        BatchRecipe { output_dir: output_dir.to_string_lossy().into_owned(), options, rename: rename.to_string() }
+    }
+
+    #[test]
+    fn test_plan_destinations() {
+        let paths: Vec<String> = ["/a/cover.png", "/b/cover.jpg", "/a/page.png"].iter().map(|path| path.to_string()).collect();
+        let names = |rename: &str| -> Vec<String> {
+            plan_destinations(&paths, &recipe(Path::new("/out"), rename)).iter()
+                .map(|path| Path::new(path).file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(names("{name}"), vec!["cover.jpg", "cover-2.jpg", "page.jpg"]);
+        assert_eq!(names("web_{index}_{name}"), vec!["web_1_cover.jpg", "web_2_cover.jpg", "web_3_page.jpg"]);
+    }
+
+    #[test]
+    fn test_run_batch_job() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut paths = Vec::new();
+        for index in 0..5 {
+            let path = temp_dir.path().join(format!("{}.png", index));
+            RgbImage::new(64, 48).save(&path).unwrap();
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let broken = temp_dir.path().join("broken.png");
+        fs::write(&broken, b"not an image").unwrap();
+        paths.push(broken.to_string_lossy().into_owned());
+
+        let output_dir = temp_dir.path().join("web");
+        let recipe = recipe(&output_dir, "{index}-{name}");
+        validate_recipe(&recipe).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+        let events = Mutex::new(Vec::new());
+        let summary = run_batch_job("job-1", paths, &recipe, 3, &AtomicBool::new(false), |event| events.lock().unwrap().push(event));
+
+        assert_eq!((summary.succeeded, summary.failed, summary.skipped, summary.cancelled), (5, 1, 0, false));
+        let exported = image::open(output_dir.join("1-0.jpg")).unwrap();
+        assert_eq!((exported.width(), exported.height()), (32, 24));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.len(), 7);
+        let mut completed: Vec<usize> = events.iter().filter_map(|event| match event {
+            BatchEvent::Item(item) => Some(item.completed),
+            BatchEvent::Complete(_) => None,
+        }).collect();
+        completed.sort();
+        assert_eq!(completed, vec![1, 2, 3, 4, 5, 6]);
+        assert!(matches!(events.last(), Some(BatchEvent::Complete(_))));
+
+        let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(summary.report.unwrap()).unwrap()).unwrap();
+        assert_eq!(report["summary"]["failed"], 1);
+        assert_eq!(report["results"].as_array().unwrap().len(), 6);
+        assert_eq!(report["recipe"]["format"], "jpeg");
+    }
+
+    #[test]
+    fn test_cancel_batch_job() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.png");
+        RgbImage::new(8, 8).save(&path).unwrap();
+        let paths = vec![path.to_string_lossy().into_owned(); 3];
+
+        // 1件目を書き出したところで取り消す
+        let cancel = AtomicBool::new(false);
+        let recipe = recipe(&temp_dir.path().join("out"), "{index}");
+        validate_recipe(&recipe).unwrap();
+        fs::create_dir_all(&recipe.output_dir).unwrap();
+        let summary = run_batch_job("job-2", paths, &recipe, 1, &cancel, |event| {
+            if let BatchEvent::Item(_) = event {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        });
+        assert_eq!((summary.succeeded, summary.skipped, summary.cancelled), (1, 2, true));
+
+        assert!(validate_recipe(&BatchRecipe { rename: "../{name}".to_string(), ..recipe }).is_err());
+    }
+
+    #[test]
+    fn test_registered_job_cleanup() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.png");
+        RgbImage::new(8, 8).save(&path).unwrap();
+        let jobs: BatchJobs = Arc::default();
+        let register = |job_id: &str| {
+            jobs.lock().unwrap().insert(job_id.to_string(), Arc::new(AtomicBool::new(false)));
+        };
+
+        // 出力先はジョブの中で作る
+        let nested = recipe(&temp_dir.path().join("nested/out"), "{name}");
+        register("job-3");
+        let summaries = Mutex::new(Vec::new());
+        run_registered_job("job-3", &jobs, vec![path.to_string_lossy().into_owned()], &nested, &AtomicBool::new(false), |event| {
+            if let BatchEvent::Complete(summary) = event {
+                summaries.lock().unwrap().push(summary);
+            }
+        });
+        assert_eq!(summaries.lock().unwrap()[0].succeeded, 1);
+        assert!(jobs.lock().unwrap().is_empty());
+
+        // ワーカーがパニックしても登録を消し、失敗として通知する
+        register("job-4");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run_registered_job("job-4", &jobs, vec![path.to_string_lossy().into_owned()], &nested, &AtomicBool::new(false), |event| match event {
+                BatchEvent::Item(_) => panic!("emit failed"),
+                BatchEvent::Complete(summary) => summaries.lock().unwrap().push(summary),
+            });
+        }));
+        assert!(result.is_err());
+        assert!(jobs.lock().unwrap().is_empty());
+        let summaries = summaries.into_inner().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert!(summaries[1].error.is_some());
+
+        // 出力先を作れなければ失敗として通知する
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        register("job-5");
+        let failed = Mutex::new(None);
+        run_registered_job("job-5", &jobs, Vec::new(), &recipe(&file.join("out"), "{name}"), &AtomicBool::new(false), |event| {
+            if let BatchEvent::Complete(summary) = event {
+                *failed.lock().unwrap() = summary.error;
+            }
+        });
+        assert!(failed.into_inner().unwrap().is_some());
+        assert!(jobs.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{archive, heif};
+use crate::formats::{archive_reader, decoder_for, image_reader, FormatDecoder};
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::tiff::TiffDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat};
+use moxcms::{ColorProfile, DataColorSpace, Layout, ProfileText, TransformOptions};
+use serde::Serialize;
+use std::io::{BufRead, Seek};
+use std::path::Path;
+use log::{debug, error};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColorProfileInfo {
+    /// プロファイルの説明 (`Display P3` など)
+    pub name: Option<String>,
+    /// `Rgb`, `Gray`, `Cmyk` など
+    pub color_space: String,
+    pub is_srgb: bool,
+}
+
+/// 画像に埋め込まれたICCプロファイルを取り出す。ピクセルはデコードしない。
+pub fn read_icc_profile<R: BufRead + Seek>(mut reader: R, format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => JpegDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Tiff => TiffDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Avif => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).ok()?;
+            heif::icc_profile_from_bytes(&data)
+        }
+        _ => None,
+    }
+}
+
+/// `image` クレートで読む形式とHEIF/AVIFに対応する。書庫の中の画像はメモリ上に取り出して調べる。
+pub fn icc_profile_for_path(path: &str) -> Option<Vec<u8>> {
+    if let Some((archive, entry)) = archive::split_path(Path::new(path)) {
+        let reader = archive_reader(&archive, &entry).ok()?;
+        let format = reader.format()?;
+        return read_icc_profile(reader.into_inner(), format);
+    }
+    match decoder_for(Path::new(path)) {
+        Some(FormatDecoder::Heif | FormatDecoder::Image(ImageFormat::Avif)) => return heif::icc_profile(path),
+        Some(FormatDecoder::Image(_)) => {}
+        _ => return None,
+    }
+    let reader = image_reader(path).ok()?;
+    let format = reader.format()?;
+    read_icc_profile(reader.into_inner(), format)
+}
+
+pub fn profile_name(profile: &ColorProfile) -> Option<String> {
+    match profile.description.as_ref()? {
+        ProfileText::PlainString(name) => Some(name.clone()),
+        ProfileText::Localizable(names) => names.iter()
+            .find(|name| name.language == "en")
+            .or_else(|| names.first())
+            .map(|name| name.value.clone()),
+        ProfileText::Description(description) => Some(description.ascii_string.clone()),
+    }
+    .map(|name| name.trim_end_matches('\0').trim().to_string())
+    .filter(|name| !name.is_empty())
+}
+
+/// 原色とトーンカーブをsRGBと比べる。名前は見ない。
+/// ICCのXYZは固定小数点で丸められ、カーブはLUTのこともあるので、少しの誤差は許す。
+pub fn is_srgb(profile: &ColorProfile) -> bool {
+    const COLORANT_TOLERANCE: f64 = 0.002;
+    const TRC_TOLERANCE: f32 = 0.002;
+    if profile.color_space != DataColorSpace::Rgb {
+        return false;
+    }
+    let srgb = ColorProfile::new_srgb();
+    let colorants = [
+        (profile.red_colorant, srgb.red_colorant),
+        (profile.green_colorant, srgb.green_colorant),
+        (profile.blue_colorant, srgb.blue_colorant),
+    ];
+    let same_colorants = colorants.iter().all(|(colorant, expected)| {
+        (colorant.x - expected.x).abs() < COLORANT_TOLERANCE
+            && (colorant.y - expected.y).abs() < COLORANT_TOLERANCE
+            && (colorant.z - expected.z).abs() < COLORANT_TOLERANCE
+    });
+    if !same_colorants {
+        return false;
+    }
+    let Ok(expected) = srgb.build_8bit_lin_table(&srgb.red_trc) else { return false };
+    [&profile.red_trc, &profile.green_trc, &profile.blue_trc].into_iter().all(|trc| {
+        profile.build_8bit_lin_table(trc).is_ok_and(|table| {
+            table.iter().zip(expected.iter()).all(|(value, expected)| (value - expected).abs() < TRC_TOLERANCE)
+        })
+    })
+}
+
+fn needs_conversion(profile: &ColorProfile) -> bool {
+    profile.color_space == DataColorSpace::Rgb && !is_srgb(profile)
+}
+
+pub fn profile_info(icc_profile: &[u8]) -> Result<ColorProfileInfo, String> {
+    let profile = ColorProfile::new_from_slice(icc_profile).map_err(|e| e.to_string())?;
+    Ok(ColorProfileInfo {
+        name: profile_name(&profile),
+        color_space: format!("{:?}", profile.color_space),
+        is_srgb: is_srgb(&profile),
+    })
+}
+
+/// RGBの8bitと16bitの画像をsRGBに変換する。グレーや浮動小数点の画像、変換が要らない場合は `None`。
+pub fn convert_to_srgb(image: &DynamicImage, icc_profile: &[u8]) -> Result<Option<DynamicImage>, String> {
+    let profile = ColorProfile::new_from_slice(icc_profile).map_err(|e| e.to_string())?;
+    if !needs_conversion(&profile) {
+        return Ok(None);
+    }
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let transform_8bit = |layout: Layout, source: &[u8]| -> Result<Vec<u8>, String> {
+        let transform = profile.create_transform_8bit(layout, &srgb, layout, options).map_err(|e| e.to_string())?;
+        let mut converted = vec![0; source.len()];
+        transform.transform(source, &mut converted).map_err(|e| e.to_string())?;
+        Ok(converted)
+    };
+    let transform_16bit = |layout: Layout, source: &[u16]| -> Result<Vec<u16>, String> {
+        let transform = profile.create_transform_16bit(layout, &srgb, layout, options).map_err(|e| e.to_string())?;
+        let mut converted = vec![0; source.len()];
+        transform.transform(source, &mut converted).map_err(|e| e.to_string())?;
+        Ok(converted)
+    };
+    let (width, height) = (image.width(), image.height());
+    let invalid = || "Invalid converted image".to_string();
+    let converted = match image {
+        DynamicImage::ImageRgb8(buffer) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, transform_8bit(Layout::Rgb, buffer)?).ok_or_else(invalid)?),
+        DynamicImage::ImageRgba8(buffer) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, transform_8bit(Layout::Rgba, buffer)?).ok_or_else(invalid)?),
+        DynamicImage::ImageRgb16(buffer) => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, transform_16bit(Layout::Rgb, buffer)?).ok_or_else(invalid)?),
+        DynamicImage::ImageRgba16(buffer) => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, transform_16bit(Layout::Rgba, buffer)?).ok_or_else(invalid)?),
+        _ => return Ok(None),
+    };
+    Ok(Some(converted))
+}
+
+/// 埋め込みのプロファイルに従ってsRGBに変換した画像。変換が要らないか、できなければ `None`。
+pub fn to_srgb(path: &str, image: &DynamicImage) -> Option<DynamicImage> {
+    let icc_profile = icc_profile_for_path(path)?;
+    match convert_to_srgb(image, &icc_profile) {
+        Ok(converted) => {
+            if converted.is_some() {
+                debug!("Converted {} to sRGB", path);
+            }
+            converted
+        }
+        Err(e) => {
+            error!("Failed to convert {} to sRGB: {}", path, e);
+            None
+        }
+    }
+}
+
+pub fn manage_colors(path: &str, image: DynamicImage) -> DynamicImage {
+    to_srgb(path, &image).unwrap_or(image)
+}
+
+/// 埋め込みのICCプロファイルの情報。プロファイルが無ければ `None`。
+#[tauri::command]
+pub fn get_color_profile(path: String) -> Result<Option<ColorProfileInfo>, String> {
+    icc_profile_for_path(&path)
+        .map(|icc_profile| profile_info(&icc_profile))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{embed_metadata, encode_image, export_image_impl, read_source_metadata, ExportColorSpace, ExportFormat, ExportOptions};
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    /// Display P3のプロファイルを埋め込んだPNGを作る
+    fn tagged_png(temp_dir: &TempDir, image: &DynamicImage, icc_profile: &[u8]) -> String {
+        let encoded = encode_image(image, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        let encoded = embed_metadata(encoded, ExportFormat::Png, Some(icc_profile), None).unwrap();
+        let path = temp_dir.path().join("p3.png");
+        std::fs::write(&path, encoded).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_profile_info() {
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let info = profile_info(&p3).unwrap();
+        assert_eq!(info.name.as_deref(), Some("Display P3"));
+        assert_eq!((info.color_space.as_str(), info.is_srgb), ("Rgb", false));
+        assert!(profile_info(&ColorProfile::new_srgb().encode().unwrap()).unwrap().is_srgb);
+        assert!(profile_info(b"not a profile").is_err());
+    }
+
+    #[test]
+    fn test_is_srgb() {
+        use moxcms::{LocalizableString, ToneReprCurve};
+
+        // 名前ではなく原色とカーブで判定する
+        let mut renamed = ColorProfile::new_srgb();
+        renamed.description = Some(ProfileText::PlainString("Camera RGB".to_string()));
+        assert!(is_srgb(&ColorProfile::new_from_slice(&renamed.encode().unwrap()).unwrap()));
+
+        let mut fake = ColorProfile::new_display_p3();
+        fake.description = Some(ProfileText::Localizable(vec![LocalizableString::new("en".to_string(), "US".to_string(), "sRGB".to_string())]));
+        assert!(!is_srgb(&fake));
+
+        // sRGBの原色でもガンマが違えば変換する
+        let mut gamma = ColorProfile::new_srgb();
+        gamma.red_trc = Some(ToneReprCurve::Parametric(vec![1.8]));
+        assert!(!is_srgb(&gamma));
+        assert!(is_srgb(&ColorProfile::new_srgb()));
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])));
+        // P3の赤はsRGBの範囲外なので、緑と青が0のまま赤が飽和する
+        let converted = convert_to_srgb(&red, &p3).unwrap().unwrap();
+        let [r, g, b] = converted.to_rgb8().get_pixel(0, 0).0;
+        assert_eq!(r, 255);
+        assert!(g < 10 && b < 10, "{:?}", (r, g, b));
+
+        // P3の中間的な色はsRGBで彩度が上がる
+        let muted = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([40000u16, 30000, 20000])));
+        let converted = convert_to_srgb(&muted, &p3).unwrap().unwrap();
+        let [r, _, b] = converted.to_rgb16().get_pixel(0, 0).0;
+        assert!(r > 40000 && b < 20000, "{:?}", (r, b));
+
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        assert!(convert_to_srgb(&red, &srgb).unwrap().is_none());
+        assert!(convert_to_srgb(&DynamicImage::ImageLuma8(red.to_luma8()), &p3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_manage_colors() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([200, 150, 100])));
+        let path = tagged_png(&temp_dir, &image, &ColorProfile::new_display_p3().encode().unwrap());
+
+        assert_eq!(get_color_profile(path.clone()).unwrap().unwrap().name.as_deref(), Some("Display P3"));
+        let managed = manage_colors(&path, image::open(&path).unwrap());
+        assert_ne!(managed.to_rgb8().get_pixel(0, 0).0, [200, 150, 100]);
+
+        // sRGBで書き出すとプロファイルは埋め込まれない
+        let destination = temp_dir.path().join("srgb.png").to_string_lossy().into_owned();
+        let options = ExportOptions { color_space: ExportColorSpace::Srgb, ..ExportOptions::new(ExportFormat::Png) };
+        export_image_impl(&path, &destination, &options).unwrap();
+        assert!(read_source_metadata(&std::fs::read(&destination).unwrap()).icc_profile.is_none());
+        assert_eq!(image::open(&destination).unwrap().to_rgb8().get_pixel(0, 0), managed.to_rgb8().get_pixel(0, 0));
+
+        let untagged = temp_dir.path().join("untagged.png");
+        image.save(&untagged).unwrap();
+        let untagged = untagged.to_str().unwrap();
+        assert!(to_srgb(untagged, &image).is_none());
+        assert_eq!(get_color_profile(untagged.to_string()).unwrap(), None);
+    }
+}
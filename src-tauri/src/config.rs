@@ -1,106 +1,254 @@
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
-use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use crate::models::{AppState, ImageState, ReadingProgress, ThumbnailFormat, ThumbnailSettings, LOSSY_WEBP_UNSUPPORTED};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::State;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct AppConfig {
     pub last_folder: Option<String>,
+    #[serde(default, deserialize_with = "session_or_none")]
+    pub session: Option<ImageState>,
+    #[serde(default, deserialize_with = "value_or_default")]
+    pub thumbnail_settings: ThumbnailSettings,
+    /// 書庫やフォルダのパスごとの読みかけの位置
+    #[serde(default, deserialize_with = "value_or_default")]
+    pub reading_progress: HashMap<String, ReadingProgress>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// 壊れた項目があっても設定全体は捨てず、その項目だけ既定値に戻す。
+fn value_or_default<'de, D: Deserializer<'de>, T: DeserializeOwned + Default>(deserializer: D) -> Result<T, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).unwrap_or_default())
+}
+
+fn session_or_none<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ImageState>, D::Error> {
+    let session: Option<ImageState> = value_or_default(deserializer)?;
+    Ok(session.filter(|session| session.validate().is_ok()))
+}
+
+/// 保存しておく読みかけの位置の数。超えたら古いものから消す。
+pub const MAX_READING_PROGRESS: usize = 200;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StartupInfo {
     pub folder: String,
     pub file: Option<String>,
+    /// 引数なしで起動し、前回のセッションが復元できた場合のみ設定される
+    #[serde(default)]
+    pub session: Option<ImageState>,
+}
+
+/// 起動時の情報源。本番では `StartupEnv::from_env` を使い、テストでは任意の値を注入する。
+pub struct StartupEnv {
+    pub args: Vec<String>,
+    pub config_dir: Option<PathBuf>,
+}
+
+impl StartupEnv {
+    pub fn from_env() -> Self {
+        let config_dir = if cfg!(target_os = "windows") {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+        };
+        StartupEnv {
+            args: env::args().collect(),
+            config_dir,
+        }
+    }
+
+    pub fn config_dir(&self) -> Result<&Path, String> {
+        self.config_dir.as_deref().ok_or_else(|| "Config directory not found".to_string())
+    }
+
+    fn config_path(&self) -> Result<PathBuf, String> {
+        Ok(self.config_dir()?.join("image_viewer_config.json"))
+    }
 }
 
 #[tauri::command]
 pub fn get_startup_info() -> Result<StartupInfo, String> {
-    if let Ok(test_file) = env::var("TEST_FILE_PATH") {
-        // テスト環境
-        let file_path = PathBuf::from(test_file);
-        let parent = file_path.parent().ok_or("Invalid file path")?;
-        Ok(StartupInfo {
-            folder: parent.to_string_lossy().into_owned(),
-            file: Some(file_path.to_string_lossy().into_owned()),
-        })
-    } else {
-        let args: Vec<String> = env::args().collect();
-        if args.len() > 1 {
-            let file_path = PathBuf::from(&args[1]);
-            if file_path.is_file() {
-                let parent = file_path.parent().ok_or("Invalid file path")?;
-                Ok(StartupInfo {
-                    folder: parent.to_string_lossy().into_owned(),
-                    file: Some(file_path.to_string_lossy().into_owned()),
-                })
-            } else if file_path.is_dir() {
-                Ok(StartupInfo {
-                    folder: file_path.to_string_lossy().into_owned(),
-                    file: None,
-                })
-            } else {
-                Err("Invalid path".to_string())
+    get_startup_info_impl(&StartupEnv::from_env())
+}
+
+fn get_startup_info_impl(startup_env: &StartupEnv) -> Result<StartupInfo, String> {
+    match startup_info_from_args(&startup_env.args)? {
+        Some(info) => Ok(info),
+        None => {
+            let config = load_config(startup_env)?;
+            // 前回表示していた画像が残っていればセッションを復元する
+            if let Some(session) = config.session {
+                if let Some(file) = session.current_image().map(PathBuf::from).filter(|file| file.is_file()) {
+                    let folder = file.parent().ok_or("Invalid file path")?;
+                    return Ok(StartupInfo {
+                        folder: folder.to_string_lossy().into_owned(),
+                        file: Some(file.to_string_lossy().into_owned()),
+                        session: Some(session),
+                    });
+                }
             }
-        } else {
             // 前回のフォルダを読み込む
-            let config = load_config()?;
             Ok(StartupInfo {
                 folder: config.last_folder.unwrap_or_else(|| ".".to_string()),
                 file: None,
+                session: None,
             })
         }
     }
 }
 
-fn load_config() -> Result<AppConfig, String> {
-    let config_dir = if cfg!(target_os = "windows") {
-        PathBuf::from(env::var("APPDATA").map_err(|e| e.to_string())?)
+/// コマンドライン引数から起動時のフォルダとファイルを決定する。
+/// 引数が無い場合は `None` を返す。
+pub fn startup_info_from_args(args: &[String]) -> Result<Option<StartupInfo>, String> {
+    if args.len() <= 1 {
+        return Ok(None);
+    }
+    let file_path = PathBuf::from(&args[1]);
+    if file_path.is_file() {
+        let parent = file_path.parent().ok_or("Invalid file path")?;
+        Ok(Some(StartupInfo {
+            folder: parent.to_string_lossy().into_owned(),
+            file: Some(file_path.to_string_lossy().into_owned()),
+            session: None,
+        }))
+    } else if file_path.is_dir() {
+        Ok(Some(StartupInfo {
+            folder: file_path.to_string_lossy().into_owned(),
+            file: None,
+            session: None,
+        }))
     } else {
-        PathBuf::from(env::var("HOME").map_err(|e| e.to_string())?).join(".config")
-    };
-    let config_path = config_dir.join("image_viewer_config.json");
+        Err("Invalid path".to_string())
+    }
+}
+
+fn load_config(startup_env: &StartupEnv) -> Result<AppConfig, String> {
+    let config_path = startup_env.config_path()?;
     if config_path.exists() {
         let content = std::fs::read_to_string(&config_path).map_err(|e| e.to_string())?;
         serde_json::from_str(&content).map_err(|e| e.to_string())
     } else {
-        Ok(AppConfig { last_folder: None })
+        Ok(AppConfig::default())
     }
 }
 
+fn save_config(startup_env: &StartupEnv, config: &AppConfig) -> Result<(), String> {
+    let config_path = startup_env.config_path()?;
+    let content = serde_json::to_string(config).map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(startup_env.config_dir()?).map_err(|e| e.to_string())?;
+    std::fs::write(&config_path, content).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
 
 #[tauri::command]
 pub fn save_last_folder(folder: String) -> Result<(), String> {
-    let config = AppConfig {
-        last_folder: Some(folder),
-    };
-    let config_dir = if cfg!(target_os = "windows") {
-        PathBuf::from(env::var("APPDATA").map_err(|e| e.to_string())?)
-    } else {
-        PathBuf::from(env::var("HOME").map_err(|e| e.to_string())?).join(".config")
-    };
-    let config_path = config_dir.join("image_viewer_config.json");
-    let content = serde_json::to_string(&config).map_err(|e| e.to_string())?;
-    std::fs::create_dir_all(&config_dir).map_err(|e| e.to_string())?;
-    std::fs::write(&config_path, content).map_err(|e| e.to_string())?;
+    save_last_folder_impl(&StartupEnv::from_env(), folder)
+}
+
+fn save_last_folder_impl(startup_env: &StartupEnv, folder: String) -> Result<(), String> {
+    let mut config = load_config(startup_env)?;
+    config.last_folder = Some(folder);
+    save_config(startup_env, &config)
+}
+
+/// 終了時に現在のセッションを保存する。画像リストが空の場合は前回のセッションを消去する。
+pub fn save_session(startup_env: &StartupEnv, session: &ImageState) -> Result<(), String> {
+    let mut config = load_config(startup_env)?;
+    config.session = if session.images.is_empty() { None } else { Some(session.clone()) };
+    save_config(startup_env, &config)
+}
+
+/// 保存されているサムネイルの設定を読み込む。読めない場合は既定値を使う。
+pub fn load_thumbnail_settings(startup_env: &StartupEnv) -> ThumbnailSettings {
+    load_config(startup_env).map(|config| config.thumbnail_settings).unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn get_thumbnail_settings(state: State<'_, AppState>) -> ThumbnailSettings {
+    state.thumbnail_settings.lock().unwrap().clone()
+}
+
+#[tauri::command]
+pub fn set_thumbnail_settings(settings: ThumbnailSettings, state: State<'_, AppState>) -> Result<(), String> {
+    save_thumbnail_settings_impl(&StartupEnv::from_env(), &settings)?;
+    *state.thumbnail_settings.lock().unwrap() = settings;
     Ok(())
 }
 
+fn save_thumbnail_settings_impl(startup_env: &StartupEnv, settings: &ThumbnailSettings) -> Result<(), String> {
+    if let Some(sigma) = settings.sharpen {
+        if !(sigma.is_finite() && sigma >= 0.0) {
+            return Err(format!("Invalid sharpen value: {}", sigma));
+        }
+    }
+    if !(1..=100).contains(&settings.quality) {
+        return Err(format!("Invalid quality: {}", settings.quality));
+    }
+    if settings.format == ThumbnailFormat::WebpLossy && !cfg!(feature = "webp-lossy") {
+        return Err(LOSSY_WEBP_UNSUPPORTED.to_string());
+    }
+    let mut config = load_config(startup_env)?;
+    config.thumbnail_settings = settings.clone();
+    save_config(startup_env, &config)
+}
+
+#[tauri::command]
+pub fn get_reading_progress(path: String) -> Result<Option<ReadingProgress>, String> {
+    get_reading_progress_impl(&StartupEnv::from_env(), &path)
+}
+
+fn get_reading_progress_impl(startup_env: &StartupEnv, path: &str) -> Result<Option<ReadingProgress>, String> {
+    Ok(load_config(startup_env)?.reading_progress.remove(path))
+}
+
+#[tauri::command]
+pub fn save_reading_progress(path: String, progress: ReadingProgress) -> Result<(), String> {
+    save_reading_progress_impl(&StartupEnv::from_env(), path, progress)
+}
+
+fn save_reading_progress_impl(startup_env: &StartupEnv, path: String, mut progress: ReadingProgress) -> Result<(), String> {
+    progress.updated = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut config = load_config(startup_env)?;
+    config.reading_progress.insert(path, progress);
+    while config.reading_progress.len() > MAX_READING_PROGRESS {
+        let oldest = config.reading_progress.iter()
+            .min_by_key(|(_, progress)| progress.updated)
+            .map(|(path, _)| path.clone());
+        match oldest {
+            Some(oldest) => config.reading_progress.remove(&oldest),
+            None => break,
+        };
+    }
+    save_config(startup_env, &config)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{ReadingDirection, ResizeFilter, SortBy, SortOrder};
     use tempfile::TempDir;
     use std::fs;
 
+    fn test_env(temp_dir: &TempDir, args: Vec<String>) -> StartupEnv {
+        StartupEnv {
+            args,
+            config_dir: Some(temp_dir.path().join("config")),
+        }
+    }
+
     #[test]
     fn test_get_startup_info() {
         let temp_dir = TempDir::new().unwrap();
         let test_file = temp_dir.path().join("test.txt");
         fs::write(&test_file, "test content").unwrap();
 
-        // テスト用の環境変数を設定
-        env::set_var("TEST_FILE_PATH", test_file.to_str().unwrap());
-
-        let result = get_startup_info();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string(), test_file.to_str().unwrap().to_string()]);
+        let result = get_startup_info_impl(&startup_env);
         assert!(result.is_ok(), "get_startup_info failed: {:?}", result.err());
         let startup_info = result.unwrap();
         assert_eq!(startup_info.folder, temp_dir.path().to_str().unwrap());
@@ -108,29 +256,193 @@ mod tests {
     }
 
     #[test]
-    fn test_save_last_folder() {
+    fn test_get_startup_info_without_args() {
         let temp_dir = TempDir::new().unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
 
-        // テスト用の環境変数を設定
-        if cfg!(target_os = "windows") {
-            env::set_var("APPDATA", temp_dir.path());
-        } else {
-            env::set_var("HOME", temp_dir.path());
+        let startup_info = get_startup_info_impl(&startup_env).unwrap();
+        assert_eq!(startup_info.folder, ".");
+        assert_eq!(startup_info.file, None);
+
+        let last_folder = temp_dir.path().to_str().unwrap().to_string();
+        save_last_folder_impl(&startup_env, last_folder.clone()).unwrap();
+        let startup_info = get_startup_info_impl(&startup_env).unwrap();
+        assert_eq!(startup_info.folder, last_folder);
+        assert_eq!(startup_info.file, None);
+    }
+
+    #[test]
+    fn test_restore_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let first = temp_dir.path().join("a.jpg");
+        let second = temp_dir.path().join("b.jpg");
+        fs::write(&first, "").unwrap();
+        fs::write(&second, "").unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
+
+        save_last_folder_impl(&startup_env, "/somewhere/else".to_string()).unwrap();
+        let session = ImageState {
+            current_index: 1,
+            images: vec![first.to_str().unwrap().to_string(), second.to_str().unwrap().to_string()],
+            zoom: 2.5,
+            sort_by: SortBy::Date,
+            sort_order: SortOrder::Desc,
+        };
+        save_session(&startup_env, &session).unwrap();
+
+        let startup_info = get_startup_info_impl(&startup_env).unwrap();
+        assert_eq!(startup_info.folder, temp_dir.path().to_str().unwrap());
+        assert_eq!(startup_info.file, Some(second.to_str().unwrap().to_string()));
+        let restored = startup_info.session.unwrap();
+        assert_eq!(restored.current_index, 1);
+        assert_eq!(restored.zoom, 2.5);
+        assert_eq!(restored.sort_by, SortBy::Date);
+        assert_eq!(restored.sort_order, SortOrder::Desc);
+
+        // 画像が削除されていれば前回のフォルダにフォールバックする
+        fs::remove_file(&second).unwrap();
+        let startup_info = get_startup_info_impl(&startup_env).unwrap();
+        assert_eq!(startup_info.folder, "/somewhere/else");
+        assert!(startup_info.session.is_none());
+
+        // 引数がある場合はセッションを使わない
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string(), first.to_str().unwrap().to_string()]);
+        assert!(get_startup_info_impl(&startup_env).unwrap().session.is_none());
+    }
+
+    #[test]
+    fn test_load_config_with_invalid_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
+        fs::create_dir_all(startup_env.config_dir().unwrap()).unwrap();
+        let sessions = [
+            r#"{"current_index": 0, "images": ["/a.jpg"], "zoom": null}"#,
+            r#"{"current_index": 0, "images": ["/a.jpg"], "zoom": -2.0}"#,
+            r#"{"current_index": 3, "images": ["/a.jpg"]}"#,
+            r#""broken""#,
+        ];
+        for session in sessions {
+            let content = format!(r#"{{"last_folder": "/photos", "session": {}, "thumbnail_settings": {{"quality": "high"}}}}"#, session);
+            fs::write(startup_env.config_path().unwrap(), content).unwrap();
+
+            let config = load_config(&startup_env).unwrap();
+            assert_eq!(config.last_folder.as_deref(), Some("/photos"));
+            assert!(config.session.is_none());
+            assert_eq!(config.thumbnail_settings.quality, ThumbnailSettings::default().quality);
+            assert_eq!(get_startup_info_impl(&startup_env).unwrap().folder, "/photos");
         }
+    }
+
+    #[test]
+    fn test_startup_info_from_args() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.jpg");
+        fs::write(&test_file, "test content").unwrap();
+        let program = "viewer".to_string();
+
+        assert!(startup_info_from_args(&["viewer".to_string()]).unwrap().is_none());
+
+        let info = startup_info_from_args(&[program.clone(), test_file.to_str().unwrap().to_string()]).unwrap().unwrap();
+        assert_eq!(info.folder, temp_dir.path().to_str().unwrap());
+        assert_eq!(info.file, Some(test_file.to_str().unwrap().to_string()));
+
+        let info = startup_info_from_args(&[program.clone(), temp_dir.path().to_str().unwrap().to_string()]).unwrap().unwrap();
+        assert_eq!(info.folder, temp_dir.path().to_str().unwrap());
+        assert_eq!(info.file, None);
+
+        let missing = temp_dir.path().join("missing.jpg").to_str().unwrap().to_string();
+        assert!(startup_info_from_args(&[program, missing]).is_err());
+    }
+
+    #[test]
+    fn test_save_last_folder() {
+        let temp_dir = TempDir::new().unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
 
         let test_folder = temp_dir.path().join("test_folder").to_str().unwrap().to_string();
-        let result = save_last_folder(test_folder.clone());
+        let result = save_last_folder_impl(&startup_env, test_folder.clone());
         assert!(result.is_ok(), "save_last_folder failed: {:?}", result.err());
 
-        let config_path = if cfg!(target_os = "windows") {
-            temp_dir.path().join("image_viewer_config.json")
-        } else {
-            temp_dir.path().join(".config").join("image_viewer_config.json")
-        };
-
+        let config_path = temp_dir.path().join("config").join("image_viewer_config.json");
         assert!(config_path.exists(), "Config file does not exist: {:?}", config_path);
         let content = fs::read_to_string(&config_path).unwrap();
         let config: AppConfig = serde_json::from_str(&content).unwrap();
         assert_eq!(config.last_folder, Some(test_folder));
     }
+
+    #[test]
+    fn test_save_thumbnail_settings() {
+        let temp_dir = TempDir::new().unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
+        assert_eq!(load_thumbnail_settings(&startup_env), ThumbnailSettings::default());
+
+        save_last_folder_impl(&startup_env, "/images".to_string()).unwrap();
+        let settings = ThumbnailSettings { filter: ResizeFilter::Lanczos3, sharpen: Some(0.8), format: ThumbnailFormat::Jpeg, quality: 75 };
+        save_thumbnail_settings_impl(&startup_env, &settings).unwrap();
+        assert_eq!(load_thumbnail_settings(&startup_env), settings);
+        assert_eq!(load_config(&startup_env).unwrap().last_folder, Some("/images".to_string()));
+
+        let invalid = ThumbnailSettings { sharpen: Some(-1.0), ..ThumbnailSettings::default() };
+        assert!(save_thumbnail_settings_impl(&startup_env, &invalid).is_err());
+        let invalid = ThumbnailSettings { quality: 0, ..ThumbnailSettings::default() };
+        assert!(save_thumbnail_settings_impl(&startup_env, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_reading_progress() {
+        let temp_dir = TempDir::new().unwrap();
+        let startup_env = test_env(&temp_dir, vec!["viewer".to_string()]);
+        assert_eq!(get_reading_progress_impl(&startup_env, "/comics/a.cbz").unwrap(), None);
+
+        let progress = ReadingProgress { page: "/comics/a.cbz/012.jpg".to_string(), direction: ReadingDirection::RightToLeft, updated: 0 };
+        save_reading_progress_impl(&startup_env, "/comics/a.cbz".to_string(), progress).unwrap();
+        let saved = get_reading_progress_impl(&startup_env, "/comics/a.cbz").unwrap().unwrap();
+        assert_eq!(saved.page, "/comics/a.cbz/012.jpg");
+        assert_eq!(saved.direction, ReadingDirection::RightToLeft);
+        assert!(saved.updated > 0);
+
+        // 上限を超えたら古いものから消す
+        let mut config = load_config(&startup_env).unwrap();
+        for index in 0..MAX_READING_PROGRESS {
+            let progress = ReadingProgress { page: "001.jpg".to_string(), direction: ReadingDirection::default(), updated: index as u64 };
+            config.reading_progress.insert(format!("/comics/{}.cbz", index), progress);
+        }
+        save_config(&startup_env, &config).unwrap();
+        save_reading_progress_impl(&startup_env, "/comics/new.cbz".to_string(), ReadingProgress { page: "001.jpg".to_string(), direction: ReadingDirection::default(), updated: 0 }).unwrap();
+        let config = load_config(&startup_env).unwrap();
+        assert_eq!(config.reading_progress.len(), MAX_READING_PROGRESS);
+        assert!(!config.reading_progress.contains_key("/comics/0.cbz"));
+        assert!(config.reading_progress.contains_key("/comics/a.cbz"));
+        assert!(config.reading_progress.contains_key("/comics/new.cbz"));
+    }
+
+    #[test]
+    fn test_missing_config_dir() {
+        let startup_env = StartupEnv {
+            args: vec!["viewer".to_string()],
+            config_dir: None,
+        };
+        assert!(get_startup_info_impl(&startup_env).is_err());
+        assert!(save_last_folder_impl(&startup_env, "/tmp".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_startup_info_serialization() {
+        let startup_info = StartupInfo {
+            folder: "/home/user".to_string(),
+            file: Some("/home/user/image.jpg".to_string()),
+            session: None,
+        };
+
+        let serialized = serde_json::to_string(&startup_info).unwrap();
+        assert!(serialized.contains("/home/user"));
+        assert!(serialized.contains("/home/user/image.jpg"));
+
+        let deserialized: StartupInfo = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.folder, "/home/user");
+        assert_eq!(deserialized.file, Some("/home/user/image.jpg".to_string()));
+        // 古い形式 (sessionなし) も読める
+        let legacy: StartupInfo = serde_json::from_str(r#"{"folder": "/home/user", "file": null}"#).unwrap();
+        assert!(legacy.session.is_none());
+    }
 }
\ No newline at end of file
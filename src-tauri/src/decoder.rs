@@ -0,0 +1,194 @@
+use crate::formats::{archive_reader, decoder_for, image_reader, FormatDecoder};
+use crate::{archive, color, heif, pdf, raw, svg, video};
+use exif::{In, Reader, Tag};
+use image::codecs::jpeg::JpegDecoder;
+use image::{DynamicImage, ImageFormat};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use log::debug;
+
+/// 埋め込みサムネイルと本体の縦横比の許容誤差
+const ASPECT_TOLERANCE: f64 = 0.02;
+
+/// サムネイル用に画像をデコードする。返す画像は長辺が `size` 以上あるが、ちょうど `size` とは限らない。
+/// JPEGはEXIFの埋め込みサムネイルが十分な大きさならそれを使い、無ければ縮小デコードする。
+/// HEIFとRAWも同様に埋め込みのサムネイルやプレビューを優先する。SVGとPDFは `size` に合わせて描画し、動画はポスターフレームを使う。
+/// 書庫の中の画像はメモリ上に取り出してからデコードする。
+/// ICCプロファイルが埋め込まれていればsRGBに変換する。
+pub fn decode_for_thumbnail(path: &str, size: u32) -> Result<DynamicImage, String> {
+    decode_scaled(path, size).map(|image| color::manage_colors(path, image))
+}
+
+fn decode_scaled(path: &str, size: u32) -> Result<DynamicImage, String> {
+    if let Some((archive, entry)) = archive::split_path(Path::new(path)) {
+        let reader = archive_reader(&archive, &entry)?;
+        if reader.format() == Some(ImageFormat::Jpeg) {
+            return decode_jpeg_scaled(reader.into_inner(), size);
+        }
+        return reader.decode().map_err(|e| e.to_string());
+    }
+    match decoder_for(Path::new(path)) {
+        Some(FormatDecoder::Heif) => return heif::decode_thumbnail(path, size),
+        Some(FormatDecoder::Raw) => return raw::decode_thumbnail(path, size),
+        Some(FormatDecoder::Svg) => return svg::render_to_fit(path, size),
+        Some(FormatDecoder::Pdf) => return pdf::render_to_fit(path, size),
+        Some(FormatDecoder::Video) => return video::poster_frame_to_fit(path, size),
+        _ => {}
+    }
+    let reader = image_reader(path)?;
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        if let Some(thumbnail) = exif_thumbnail(path, size) {
+            debug!("Using embedded EXIF thumbnail for {}", path);
+            return Ok(thumbnail);
+        }
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        return decode_jpeg_scaled(BufReader::new(file), size);
+    }
+    reader.decode().map_err(|e| e.to_string())
+}
+
+/// EXIFのIFD1に埋め込まれたJPEGサムネイルを取り出す。
+/// 長辺が `size` 未満の場合や、縦横比が本体と異なる(黒帯付きの)場合は使わない。
+fn exif_thumbnail(path: &str, size: u32) -> Option<DynamicImage> {
+    let file = File::open(path).ok()?;
+    let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok()?;
+    let offset = exif.get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let length = exif.get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let data = exif.buf().get(offset..offset.checked_add(length)?)?;
+
+    let thumbnail = image::load_from_memory_with_format(data, ImageFormat::Jpeg).ok()?;
+    if thumbnail.width().max(thumbnail.height()) < size {
+        return None;
+    }
+    let (width, height) = image::image_dimensions(path).ok()?;
+    let aspect = width as f64 / height as f64;
+    let thumbnail_aspect = thumbnail.width() as f64 / thumbnail.height() as f64;
+    if (aspect - thumbnail_aspect).abs() / aspect > ASPECT_TOLERANCE {
+        return None;
+    }
+    Some(thumbnail)
+}
+
+/// DCT領域で 1/2, 1/4, 1/8 に縮小しながらデコードする。
+pub fn decode_jpeg_scaled<R: Read>(reader: R, size: u32) -> Result<DynamicImage, String> {
+    let mut decoder = JpegDecoder::new(reader).map_err(|e| e.to_string())?;
+    let requested = size.min(u16::MAX as u32) as u16;
+    decoder.scale(requested, requested).map_err(|e| e.to_string())?;
+    DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::{Field, Value};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+    use std::fs;
+    use std::io::Cursor;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn encode_jpeg(image: &RgbImage) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        JpegEncoder::new_with_quality(&mut buffer, 85).encode_image(image).unwrap();
+        buffer
+    }
+
+    fn test_image(width: u32, height: u32, color: Rgb<u8>) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            // 圧縮が効きすぎないように細かい模様を入れる
+            let noise = ((x * 7 + y * 13) % 32) as u8;
+            Rgb([color[0].saturating_add(noise), color[1].saturating_add(noise), color[2].saturating_add(noise)])
+        })
+    }
+
+    /// EXIFのAPP1セグメントにサムネイルを埋め込んだJPEGを作る
+    fn jpeg_with_exif_thumbnail(main: &RgbImage, thumbnail: &RgbImage) -> Vec<u8> {
+        let thumbnail_jpeg = encode_jpeg(thumbnail);
+        let orientation = Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![1]),
+        };
+        let mut writer = Writer::new();
+        writer.push_field(&orientation);
+        writer.set_jpeg(&thumbnail_jpeg, In::THUMBNAIL);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let main_jpeg = encode_jpeg(main);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        jpeg.extend_from_slice(b"Exif\0\0");
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&main_jpeg[2..]);
+        jpeg
+    }
+
+    #[test]
+    fn test_exif_thumbnail() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.jpg");
+        let main = test_image(1200, 800, Rgb([0, 0, 200]));
+        fs::write(&path, jpeg_with_exif_thumbnail(&main, &test_image(160, 107, Rgb([200, 0, 0])))).unwrap();
+
+        let decoded = decode_for_thumbnail(path.to_str().unwrap(), 100).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (160, 107));
+        let pixel = decoded.to_rgb8().get_pixel(80, 50).0;
+        assert!(pixel[0] > pixel[2], "Expected the red embedded thumbnail, got {:?}", pixel);
+    }
+
+    #[test]
+    fn test_exif_thumbnail_not_used() {
+        let temp_dir = TempDir::new().unwrap();
+        let main = test_image(1200, 800, Rgb([0, 0, 200]));
+
+        // 小さすぎる
+        let small = temp_dir.path().join("small.jpg");
+        fs::write(&small, jpeg_with_exif_thumbnail(&main, &test_image(60, 40, Rgb([200, 0, 0])))).unwrap();
+        let decoded = decode_for_thumbnail(small.to_str().unwrap(), 100).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (150, 100));
+
+        // 縦横比が違う
+        let letterboxed = temp_dir.path().join("letterboxed.jpg");
+        fs::write(&letterboxed, jpeg_with_exif_thumbnail(&main, &test_image(160, 120, Rgb([200, 0, 0])))).unwrap();
+        let decoded = decode_for_thumbnail(letterboxed.to_str().unwrap(), 100).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (150, 100));
+        let pixel = decoded.to_rgb8().get_pixel(75, 50).0;
+        assert!(pixel[2] > pixel[0], "Expected the decoded main image, got {:?}", pixel);
+    }
+
+    #[test]
+    fn test_decode_non_jpeg() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("image.png");
+        test_image(300, 200, Rgb([0, 200, 0])).save(&path).unwrap();
+        let decoded = decode_for_thumbnail(path.to_str().unwrap(), 100).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (300, 200));
+    }
+
+    /// 縮小デコードが全体のデコードより速いことを確かめるベンチマーク。
+    /// 実行時間は環境に左右されるので `cargo test -- --ignored` で明示的に走らせる。
+    #[test]
+    #[ignore = "wall-clock benchmark"]
+    fn bench_scaled_jpeg_decode() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("large.jpg");
+        fs::write(&path, encode_jpeg(&test_image(2400, 1600, Rgb([120, 80, 40])))).unwrap();
+        let path = path.to_str().unwrap();
+
+        let start = Instant::now();
+        let full = image::open(path).unwrap().thumbnail(100, 100);
+        let full_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        let scaled = decode_for_thumbnail(path, 100).unwrap().thumbnail(100, 100);
+        let scaled_elapsed = start.elapsed();
+
+        assert_eq!((full.width(), full.height()), (scaled.width(), scaled.height()));
+        assert!(scaled_elapsed < full_elapsed, "Scaled decode ({:?}) should be faster than full decode ({:?})", scaled_elapsed, full_elapsed);
+    }
+}
@@ -0,0 +1,486 @@
+use crate::archive;
+use crate::decoder::decode_for_thumbnail;
+use crate::export::{apply_orientation, check_export, load_source_metadata, write_export, ExportError, ExportOptions, ExportResult, SourceMetadata};
+use crate::formats::{image_dimensions, open_image, MAX_RASTER_PIXELS};
+use crate::image_processing::to_data_url;
+use image::imageops::{interpolate_bilinear, FilterType};
+use image::{ColorType, DynamicImage, GenericImageView, ImageOutputFormat, Rgba, Rgba32FImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+pub const EDIT_RECIPE_VERSION: u32 = 1;
+
+/// 画像の隣に置く編集手順のファイルの拡張子 (`photo.jpg.edit.json`)
+pub const SIDECAR_EXTENSION: &str = "edit.json";
+
+/// 編集の操作。大きさや位置はその操作の直前の画像の画素単位で指定する。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOperation {
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    /// 片方だけ指定すると縦横比を保つ
+    Resize { width: Option<u32>, height: Option<u32> },
+    /// 時計回りの角度。90度の倍数以外では全体が収まるように広げ、余白は透明にする。
+    Rotate { degrees: f32 },
+    /// -1.0 から 1.0
+    Brightness { amount: f32 },
+    /// -1.0 から 1.0
+    Contrast { amount: f32 },
+    /// 1.0で変化なし。大きいほど明るくなる。
+    Gamma { gamma: f32 },
+    /// -1.0 (グレー) から 1.0
+    Saturation { amount: f32 },
+    Grayscale,
+}
+
+impl EditOperation {
+    fn is_tone(&self) -> bool {
+        matches!(self, EditOperation::Brightness { .. } | EditOperation::Contrast { .. } | EditOperation::Gamma { .. } | EditOperation::Saturation { .. })
+    }
+}
+
+/// 元の画像は書き換えず、この手順をサイドカーに保存して再編集できるようにする。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EditRecipe {
+    #[serde(default = "default_recipe_version")]
+    pub version: u32,
+    pub operations: Vec<EditOperation>,
+}
+
+fn default_recipe_version() -> u32 {
+    EDIT_RECIPE_VERSION
+}
+
+pub fn sidecar_path(path: &str) -> Result<PathBuf, String> {
+    if archive::split_path(Path::new(path)).is_some() {
+        return Err("Edit recipes cannot be saved for images inside archives".to_string());
+    }
+    Ok(PathBuf::from(format!("{}.{}", path, SIDECAR_EXTENSION)))
+}
+
+pub fn validate_operations(operations: &[EditOperation]) -> Result<(), String> {
+    let in_range = |amount: f32| (-1.0..=1.0).contains(&amount);
+    for operation in operations {
+        let valid = match *operation {
+            EditOperation::Crop { width, height, .. } => width > 0 && height > 0,
+            EditOperation::Resize { width, height } => {
+                (width.is_some() || height.is_some()) && width != Some(0) && height != Some(0)
+                    && width.zip(height).is_none_or(|size| check_pixels(size).is_ok())
+            }
+            EditOperation::Rotate { degrees } => degrees.is_finite(),
+            EditOperation::Brightness { amount } | EditOperation::Contrast { amount } | EditOperation::Saturation { amount } => in_range(amount),
+            EditOperation::Gamma { gamma } => gamma.is_finite() && gamma > 0.0,
+            EditOperation::Grayscale => true,
+        };
+        if !valid {
+            return Err(format!("Invalid edit operation: {:?}", operation));
+        }
+    }
+    Ok(())
+}
+
+/// 拡大や回転で、SVGやPDFの描画と同じ画素数の上限を超えないか確かめる。
+fn check_pixels((width, height): (u32, u32)) -> Result<(), String> {
+    if width as u64 * height as u64 > MAX_RASTER_PIXELS {
+        return Err(format!("Edited image is too large: {}x{}", width, height));
+    }
+    Ok(())
+}
+
+fn scaled(value: u32, scale: f64) -> u32 {
+    ((value as f64 * scale).round() as u32).max(1)
+}
+
+fn resize_target((width, height): (u32, u32), target: (Option<u32>, Option<u32>), scale: f64) -> (u32, u32) {
+    match target {
+        (Some(target_width), Some(target_height)) => (scaled(target_width, scale), scaled(target_height, scale)),
+        (Some(target_width), None) => {
+            let target_width = scaled(target_width, scale);
+            (target_width, scaled(height, target_width as f64 / width as f64))
+        }
+        (None, Some(target_height)) => {
+            let target_height = scaled(target_height, scale);
+            (scaled(width, target_height as f64 / height as f64), target_height)
+        }
+        (None, None) => (width, height),
+    }
+}
+
+/// 0-359度に直し、90度の倍数ならその回数を返す。
+fn quarter_turns(degrees: f32) -> Option<u32> {
+    let degrees = degrees.rem_euclid(360.0);
+    let turns = (degrees / 90.0).round();
+    ((degrees - turns * 90.0).abs() < 0.01).then_some(turns as u32 % 4)
+}
+
+fn rotated_size((width, height): (u32, u32), degrees: f32) -> (u32, u32) {
+    let (sin, cos) = (degrees as f64).to_radians().sin_cos();
+    let rotated_width = width as f64 * cos.abs() + height as f64 * sin.abs();
+    let rotated_height = width as f64 * sin.abs() + height as f64 * cos.abs();
+    ((rotated_width.round() as u32).max(1), (rotated_height.round() as u32).max(1))
+}
+
+fn operation_size((width, height): (u32, u32), operation: &EditOperation) -> (u32, u32) {
+    match *operation {
+        EditOperation::Crop { x, y, width: crop_width, height: crop_height } => (
+            crop_width.min(width.saturating_sub(x)).max(1),
+            crop_height.min(height.saturating_sub(y)).max(1),
+        ),
+        EditOperation::Resize { width: target_width, height: target_height } => resize_target((width, height), (target_width, target_height), 1.0),
+        EditOperation::Rotate { degrees } => match quarter_turns(degrees) {
+            Some(1) | Some(3) => (height, width),
+            Some(_) => (width, height),
+            None => rotated_size((width, height), degrees),
+        },
+        _ => (width, height),
+    }
+}
+
+/// 画像の大きさが操作でどう変わるかを、画素を処理せずに求める。
+pub fn output_size(size: (u32, u32), operations: &[EditOperation]) -> (u32, u32) {
+    operations.iter().fold(size, operation_size)
+}
+
+/// `output_size` と同じだが、途中の大きさも含めて画素数の上限を超える操作があればデコードする前に断る。
+pub fn check_output_size(size: (u32, u32), operations: &[EditOperation]) -> Result<(u32, u32), String> {
+    operations.iter().try_fold(size, |size, operation| {
+        let size = operation_size(size, operation);
+        check_pixels(size)?;
+        Ok(size)
+    })
+}
+
+/// 8bit・16bit・浮動小数点と、グレーかどうかを元の画像に合わせる。
+fn restore_color(image: DynamicImage, color: ColorType, alpha: bool) -> DynamicImage {
+    let bits = color.bytes_per_pixel() / color.channel_count();
+    let gray = color.channel_count() <= 2;
+    match (bits, gray, alpha) {
+        (1, true, false) => DynamicImage::ImageLuma8(image.to_luma8()),
+        (1, true, true) => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        (1, false, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (1, false, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        (2, true, false) => DynamicImage::ImageLuma16(image.to_luma16()),
+        (2, true, true) => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+        (2, false, false) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        (2, false, true) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        (_, _, false) => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        (_, _, true) => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+    }
+}
+
+fn adjust_tone(pixel: &mut [f32; 4], operation: &EditOperation) {
+    let [red, green, blue, _] = *pixel;
+    match *operation {
+        EditOperation::Brightness { amount } => pixel[..3].iter_mut().for_each(|value| *value += amount),
+        EditOperation::Contrast { amount } => pixel[..3].iter_mut().for_each(|value| *value = (*value - 0.5) * (1.0 + amount) + 0.5),
+        EditOperation::Gamma { gamma } => pixel[..3].iter_mut().for_each(|value| *value = value.max(0.0).powf(1.0 / gamma)),
+        EditOperation::Saturation { amount } => {
+            let luma = 0.2126 * red + 0.7152 * green + 0.0722 * blue;
+            pixel[..3].iter_mut().for_each(|value| *value = luma + (*value - luma) * (1.0 + amount));
+        }
+        _ => {}
+    }
+    pixel[..3].iter_mut().for_each(|value| *value = value.clamp(0.0, 1.0));
+}
+
+/// 続けて並んだ色の調整は1回の走査でまとめて行う。
+fn adjust_colors(image: DynamicImage, operations: &[EditOperation]) -> DynamicImage {
+    let color = image.color();
+    let mut buffer = image.into_rgba32f();
+    for pixel in buffer.pixels_mut() {
+        for operation in operations {
+            adjust_tone(&mut pixel.0, operation);
+        }
+    }
+    restore_color(DynamicImage::ImageRgba32F(buffer), color, color.has_alpha())
+}
+
+fn rotate_arbitrary(image: DynamicImage, degrees: f32) -> DynamicImage {
+    let color = image.color();
+    let source = image.into_rgba32f();
+    let (width, height) = rotated_size(source.dimensions(), degrees);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (center_x, center_y) = (source.width() as f32 / 2.0, source.height() as f32 / 2.0);
+    let (rotated_center_x, rotated_center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let rotated = Rgba32FImage::from_fn(width, height, |x, y| {
+        // 出力の画素の中心を逆回転して元の画像の位置を求める
+        let dx = x as f32 + 0.5 - rotated_center_x;
+        let dy = y as f32 + 0.5 - rotated_center_y;
+        let source_x = dx * cos + dy * sin + center_x - 0.5;
+        let source_y = -dx * sin + dy * cos + center_y - 0.5;
+        interpolate_bilinear(&source, source_x, source_y).unwrap_or(Rgba([0.0, 0.0, 0.0, 0.0]))
+    });
+    restore_color(DynamicImage::ImageRgba32F(rotated), color, true)
+}
+
+fn apply_operation(image: DynamicImage, operation: &EditOperation, scale: f64) -> Result<DynamicImage, String> {
+    Ok(match *operation {
+        EditOperation::Crop { x, y, width, height } => {
+            let (x, y) = ((x as f64 * scale).round() as u32, (y as f64 * scale).round() as u32);
+            if x >= image.width() || y >= image.height() {
+                return Err(format!("Crop rectangle is outside the image: {:?}", operation));
+            }
+            let width = scaled(width, scale).min(image.width() - x);
+            let height = scaled(height, scale).min(image.height() - y);
+            image.crop_imm(x, y, width, height)
+        }
+        EditOperation::Resize { width, height } => {
+            let (width, height) = resize_target(image.dimensions(), (width, height), scale);
+            check_pixels((width, height))?;
+            image.resize_exact(width, height, FilterType::Lanczos3)
+        }
+        EditOperation::Rotate { degrees } => match quarter_turns(degrees) {
+            Some(1) => image.rotate90(),
+            Some(2) => image.rotate180(),
+            Some(3) => image.rotate270(),
+            Some(_) => image,
+            None => {
+                check_pixels(rotated_size(image.dimensions(), degrees))?;
+                rotate_arbitrary(image, degrees)
+            }
+        },
+        EditOperation::Grayscale => image.grayscale(),
+        _ => adjust_colors(image, std::slice::from_ref(operation)),
+    })
+}
+
+/// 操作を順に適用する。`scale` は縮小したプレビューに対して、操作の画素単位の値に掛ける倍率。
+pub fn apply_operations(mut image: DynamicImage, operations: &[EditOperation], scale: f64) -> Result<DynamicImage, String> {
+    validate_operations(operations)?;
+    let mut index = 0;
+    while index < operations.len() {
+        let tone = operations[index..].iter().take_while(|operation| operation.is_tone()).count();
+        if tone > 0 {
+            image = adjust_colors(image, &operations[index..index + tone]);
+            index += tone;
+        } else {
+            image = apply_operation(image, &operations[index], scale)?;
+            index += 1;
+        }
+    }
+    Ok(image)
+}
+
+/// EXIFの向きを反映した大きさ。編集の座標は表示されている向きで指定する。
+fn oriented_size(path: &str, metadata: &SourceMetadata) -> Result<(u32, u32), String> {
+    let (width, height) = image_dimensions(path)?;
+    Ok(if (5..=8).contains(&metadata.orientation) { (height, width) } else { (width, height) })
+}
+
+/// 結果が `max_width` x `max_height` に収まる大きさで編集結果を描画し、データURLで返す。
+pub fn render_edit_preview_impl(path: &str, operations: &[EditOperation], max_width: u32, max_height: u32) -> Result<String, String> {
+    validate_operations(operations)?;
+    let metadata = load_source_metadata(path);
+    let (width, height) = oriented_size(path, &metadata)?;
+    let (output_width, output_height) = check_output_size((width, height), operations)?;
+    let scale = (max_width as f64 / output_width as f64)
+        .min(max_height as f64 / output_height as f64)
+        .min(1.0);
+
+    let (preview_width, preview_height) = (scaled(width, scale), scaled(height, scale));
+    let image = decode_for_thumbnail(path, preview_width.max(preview_height))?;
+    let image = apply_orientation(image, metadata.orientation);
+    let image = if image.dimensions() == (preview_width, preview_height) {
+        image
+    } else {
+        image.resize_exact(preview_width, preview_height, FilterType::Triangle)
+    };
+    let preview = apply_operations(image, operations, scale)?;
+
+    let mut buffer = Vec::new();
+    let format = if preview.color().has_alpha() { ImageOutputFormat::Png } else { ImageOutputFormat::Jpeg(90) };
+    let preview = if preview.color().has_alpha() { DynamicImage::ImageRgba8(preview.to_rgba8()) } else { DynamicImage::ImageRgb8(preview.to_rgb8()) };
+    preview.write_to(&mut Cursor::new(&mut buffer), format).map_err(|e| e.to_string())?;
+    Ok(to_data_url(&buffer))
+}
+
+/// 元の解像度で編集結果を書き出す。向きは画素に反映するので、EXIFは書き込まない。
+pub fn save_edited_image_impl(path: &str, operations: &[EditOperation], destination: &str, options: &ExportOptions) -> Result<ExportResult, ExportError> {
+    check_export(destination, options)?;
+    validate_operations(operations).map_err(ExportError::InvalidOptions)?;
+    let metadata = SourceMetadata { exif: None, ..load_source_metadata(path) };
+    check_output_size(oriented_size(path, &metadata).map_err(ExportError::Decode)?, operations).map_err(ExportError::InvalidOptions)?;
+    let image = open_image(path).map_err(ExportError::Decode)?;
+    let image = apply_orientation(image, metadata.orientation);
+    let image = apply_operations(image, operations, 1.0).map_err(ExportError::InvalidOptions)?;
+    write_export(image, &metadata, destination, options)
+}
+
+pub fn load_edit_recipe(path: &str) -> Result<Option<EditRecipe>, String> {
+    let sidecar = sidecar_path(path)?;
+    if !sidecar.exists() {
+        return Ok(None);
+    }
+    let json = fs::read_to_string(&sidecar).map_err(|e| e.to_string())?;
+    serde_json::from_str(&json).map(Some).map_err(|e| e.to_string())
+}
+
+/// 操作が空なら編集を取り消したものとしてサイドカーを削除する。
+pub fn save_edit_recipe_impl(path: &str, operations: Vec<EditOperation>) -> Result<(), String> {
+    let sidecar = sidecar_path(path)?;
+    if operations.is_empty() {
+        if sidecar.exists() {
+            fs::remove_file(&sidecar).map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+    validate_operations(&operations)?;
+    let recipe = EditRecipe { version: EDIT_RECIPE_VERSION, operations };
+    let json = serde_json::to_string_pretty(&recipe).map_err(|e| e.to_string())?;
+    fs::write(&sidecar, json).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_edit_recipe(path: String) -> Result<Option<EditRecipe>, String> {
+    load_edit_recipe(&path)
+}
+
+#[tauri::command]
+pub fn save_edit_recipe(path: String, operations: Vec<EditOperation>) -> Result<(), String> {
+    save_edit_recipe_impl(&path, operations)
+}
+
+#[tauri::command]
+pub async fn render_edit_preview(path: String, operations: Vec<EditOperation>, max_width: u32, max_height: u32) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || render_edit_preview_impl(&path, &operations, max_width, max_height))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn save_edited_image(path: String, operations: Vec<EditOperation>, destination: String, options: ExportOptions) -> Result<ExportResult, ExportError> {
+    tauri::async_runtime::spawn_blocking(move || save_edited_image_impl(&path, &operations, &destination, &options))
+        .await
+        .map_err(|e| ExportError::Internal(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::ExportFormat;
+    use base64::{engine::general_purpose, Engine as _};
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])))
+    }
+
+    fn decode_data_url(data_url: &str) -> DynamicImage {
+        let (_, encoded) = data_url.split_once(";base64,").unwrap();
+        image::load_from_memory(&general_purpose::STANDARD.decode(encoded).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_geometry() {
+        let operations = vec![
+            EditOperation::Crop { x: 10, y: 20, width: 100, height: 50 },
+            EditOperation::Rotate { degrees: -90.0 },
+            EditOperation::Resize { width: Some(24), height: None },
+        ];
+        let edited = apply_operations(gradient(200, 100), &operations, 1.0).unwrap();
+        assert_eq!(edited.dimensions(), (24, 48));
+        assert_eq!(output_size((200, 100), &operations), (24, 48));
+        // プレビューでは同じ操作が縮小した画像に比例して効く
+        assert_eq!(apply_operations(gradient(100, 50), &operations, 0.5).unwrap().dimensions(), (12, 24));
+
+        // 斜めの回転では全体が収まるように広がり、角は透明になる
+        let rotated = apply_operations(gradient(100, 100), &[EditOperation::Rotate { degrees: 45.0 }], 1.0).unwrap();
+        assert_eq!(rotated.dimensions(), (141, 141));
+        assert_eq!(rotated.color(), ColorType::Rgba8);
+        assert_eq!(rotated.get_pixel(0, 0).0[3], 0);
+        assert_eq!(rotated.get_pixel(70, 70).0[3], 255);
+
+        let outside = apply_operations(gradient(10, 10), &[EditOperation::Crop { x: 10, y: 0, width: 5, height: 5 }], 1.0);
+        assert!(outside.is_err());
+        assert!(validate_operations(&[EditOperation::Gamma { gamma: 0.0 }]).is_err());
+        assert!(validate_operations(&[EditOperation::Resize { width: None, height: None }]).is_err());
+    }
+
+    #[test]
+    fn test_reject_huge_output() {
+        assert!(validate_operations(&[EditOperation::Resize { width: Some(100_000), height: Some(100_000) }]).is_err());
+
+        // 片方だけの指定や回転は元の大きさから判断する
+        let upscale = [EditOperation::Resize { width: Some(60_000), height: None }];
+        assert!(validate_operations(&upscale).is_ok());
+        assert!(check_output_size((200, 100), &upscale).is_err());
+        assert_eq!(check_output_size((200, 1), &upscale).unwrap(), (60_000, 300));
+        assert!(apply_operations(gradient(200, 100), &upscale, 1.0).is_err());
+        // 途中で上限を超えれば、最後に切り抜いても断る
+        let cropped = [EditOperation::Resize { width: Some(60_000), height: None }, EditOperation::Crop { x: 0, y: 0, width: 10, height: 10 }];
+        assert!(check_output_size((200, 100), &cropped).is_err());
+        assert!(check_output_size((8000, 8000), &[EditOperation::Rotate { degrees: 45.0 }]).is_err());
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.png");
+        gradient(200, 100).save(&path).unwrap();
+        let path = path.to_str().unwrap();
+        assert!(render_edit_preview_impl(path, &upscale, 50, 50).is_err());
+        let destination = temp_dir.path().join("huge.png");
+        let result = save_edited_image_impl(path, &upscale, destination.to_str().unwrap(), &ExportOptions::new(ExportFormat::Png));
+        assert!(matches!(result, Err(ExportError::InvalidOptions(_))));
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn test_tone() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 50])));
+        let brighter = apply_operations(image.clone(), &[EditOperation::Brightness { amount: 0.2 }], 1.0).unwrap();
+        assert_eq!(brighter.to_rgb8().get_pixel(0, 0).0, [251, 151, 101]);
+
+        let gray = apply_operations(image.clone(), &[EditOperation::Saturation { amount: -1.0 }], 1.0).unwrap();
+        let [red, green, blue] = gray.to_rgb8().get_pixel(0, 0).0;
+        assert!(red == green && green == blue);
+
+        let flat = apply_operations(image.clone(), &[EditOperation::Contrast { amount: -1.0 }, EditOperation::Gamma { gamma: 1.0 }], 1.0).unwrap();
+        assert_eq!(flat.to_rgb8().get_pixel(0, 0).0, [128, 128, 128]);
+
+        // 16bitの画像は16bitのまま処理する
+        let deep = DynamicImage::ImageRgb16(image.to_rgb16());
+        assert_eq!(apply_operations(deep, &[EditOperation::Gamma { gamma: 2.2 }], 1.0).unwrap().color(), ColorType::Rgb16);
+        assert_eq!(apply_operations(image, &[EditOperation::Grayscale], 1.0).unwrap().color(), ColorType::L8);
+    }
+
+    #[test]
+    fn test_preview_and_save() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.png");
+        gradient(400, 200).save(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let operations = vec![EditOperation::Crop { x: 0, y: 0, width: 200, height: 200 }, EditOperation::Brightness { amount: 0.1 }];
+
+        let preview = decode_data_url(&render_edit_preview_impl(path, &operations, 50, 50).unwrap());
+        assert_eq!(preview.dimensions(), (50, 50));
+
+        let destination = temp_dir.path().join("edited.png");
+        let destination = destination.to_str().unwrap();
+        let result = save_edited_image_impl(path, &operations, destination, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        assert_eq!((result.width, result.height), (200, 200));
+        assert!(matches!(save_edited_image_impl(path, &operations, destination, &ExportOptions::new(ExportFormat::Png)), Err(ExportError::AlreadyExists(_))));
+        // 元の画像は変わらない
+        assert_eq!(image::open(path).unwrap().dimensions(), (400, 200));
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("photo.jpg");
+        let path = path.to_str().unwrap();
+        assert_eq!(load_edit_recipe(path).unwrap(), None);
+
+        let operations = vec![EditOperation::Rotate { degrees: 1.5 }, EditOperation::Grayscale];
+        save_edit_recipe_impl(path, operations.clone()).unwrap();
+        assert!(temp_dir.path().join("photo.jpg.edit.json").exists());
+        let recipe = load_edit_recipe(path).unwrap().unwrap();
+        assert_eq!(recipe, EditRecipe { version: EDIT_RECIPE_VERSION, operations });
+
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(sidecar_path(path).unwrap()).unwrap()).unwrap();
+        assert_eq!(json["operations"][0], serde_json::json!({ "op": "rotate", "degrees": 1.5 }));
+
+        save_edit_recipe_impl(path, Vec::new()).unwrap();
+        assert!(!sidecar_path(path).unwrap().exists());
+    }
+}
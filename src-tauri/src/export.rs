@@ -0,0 +1,621 @@
+use crate::{archive, heif};
+use crate::color::{convert_to_srgb, read_icc_profile};
+use crate::formats::open_image;
+use crate::image_processing::encode_lossy_webp;
+use exif::{In, Reader, Tag};
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Jpeg,
+    Png,
+    /// 品質100なら可逆、それ以外は非可逆 (`webp-lossy` フィーチャー)
+    Webp,
+    /// `avif-encoder` フィーチャーが必要
+    Avif,
+    Tiff,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Png => "png",
+            ExportFormat::Webp => "webp",
+            ExportFormat::Avif => "avif",
+            ExportFormat::Tiff => "tif",
+        }
+    }
+
+    /// EXIFを埋め込める形式か
+    pub fn carries_exif(&self) -> bool {
+        matches!(self, ExportFormat::Jpeg | ExportFormat::Png)
+    }
+}
+
+/// 指定した幅・高さに収まるように縮小する。拡大はしない。
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ExportResize {
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportColorSpace {
+    /// 色の種類とビット深度を保ち、元の画像のICCプロファイルを埋め込む (JPEG・PNGのみ)
+    #[default]
+    Preserve,
+    /// ICCプロファイルを埋め込まない
+    Untagged,
+    /// 埋め込みのプロファイルからsRGBに変換し、プロファイルは埋め込まない
+    Srgb,
+    Grayscale,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// 非可逆形式の品質 (1-100)
+    #[serde(default = "default_export_quality")]
+    pub quality: u8,
+    #[serde(default)]
+    pub resize: Option<ExportResize>,
+    /// EXIFを書き込まない。その場合は向きの指定を画素に反映する。
+    /// 残す場合もJPEG・PNGにしか書き込めないので、他の形式では向きを画素に反映する。
+    #[serde(default = "default_strip_metadata")]
+    pub strip_metadata: bool,
+    #[serde(default)]
+    pub color_space: ExportColorSpace,
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+fn default_export_quality() -> u8 {
+    90
+}
+
+fn default_strip_metadata() -> bool {
+    true
+}
+
+impl ExportOptions {
+    pub fn new(format: ExportFormat) -> Self {
+        ExportOptions {
+            format,
+            quality: default_export_quality(),
+            resize: None,
+            strip_metadata: default_strip_metadata(),
+            color_space: ExportColorSpace::default(),
+            overwrite: false,
+        }
+    }
+}
+
+/// フロントエンドには `{ "kind": "...", "message": "..." }` として渡る。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum ExportError {
+    InvalidOptions(String),
+    UnsupportedFormat(String),
+    AlreadyExists(String),
+    Decode(String),
+    Encode(String),
+    Write(String),
+    Internal(String),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::InvalidOptions(message) => write!(f, "Invalid export options: {}", message),
+            ExportError::UnsupportedFormat(message) => write!(f, "Unsupported export format: {}", message),
+            ExportError::AlreadyExists(path) => write!(f, "File already exists: {}", path),
+            ExportError::Decode(message) => write!(f, "Failed to decode: {}", message),
+            ExportError::Encode(message) => write!(f, "Failed to encode: {}", message),
+            ExportError::Write(message) => write!(f, "Failed to write: {}", message),
+            ExportError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportResult {
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+}
+
+/// 元の画像から引き継ぐICCプロファイルとEXIF
+#[derive(Debug, Default)]
+pub struct SourceMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIFのTIFF構造 (`Exif\0\0` の後ろ)
+    pub exif: Option<Vec<u8>>,
+    pub orientation: u32,
+}
+
+/// HEIF・AVIFのEXIFも読む。これらの回転はコンテナの `irot`/`imir` で指定されていて
+/// デコーダーが反映するので、EXIFの向きは使わず、引き継ぐEXIFでも1にしておく。
+pub fn read_source_metadata(data: &[u8]) -> SourceMetadata {
+    let heif = is_heif_container(data);
+    let icc_profile = if heif {
+        heif::icc_profile_from_bytes(data)
+    } else {
+        image::guess_format(data).ok().and_then(|format| read_icc_profile(Cursor::new(data), format))
+    };
+    let exif = Reader::new().read_from_container(&mut Cursor::new(data)).ok();
+    let orientation = exif.as_ref()
+        .filter(|_| !heif)
+        .and_then(|exif| exif.get_field(Tag::Orientation, In::PRIMARY))
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+    let exif = exif.map(|exif| {
+        let mut buffer = exif.buf().to_vec();
+        if heif {
+            reset_orientation(&mut buffer);
+        }
+        buffer
+    });
+    SourceMetadata { icc_profile, exif, orientation }
+}
+
+/// ISOBMFF (`ftyp` で始まる) の画像か。HEIF・AVIFが該当する。
+fn is_heif_container(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp".as_slice())
+}
+
+/// EXIFのIFD0にある向き (0x0112) の値をその場で1に書き換える。
+fn reset_orientation(tiff: &mut [u8]) {
+    let read_u16 = |data: &[u8], offset: usize, big_endian: bool| -> Option<u16> {
+        let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let big_endian = match tiff.get(0..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let Some(ifd) = tiff.get(4..8).map(|b| [b[0], b[1], b[2], b[3]]) else { return };
+    let ifd = if big_endian { u32::from_be_bytes(ifd) } else { u32::from_le_bytes(ifd) } as usize;
+    let count = read_u16(tiff, ifd, big_endian).unwrap_or(0) as usize;
+    for entry in (0..count).map(|index| ifd + 2 + index * 12) {
+        // SHORT (3) の値はエントリの8バイト目に入っている
+        if read_u16(tiff, entry, big_endian) == Some(0x0112) && read_u16(tiff, entry + 2, big_endian) == Some(3) {
+            let one = if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() };
+            if let Some(value) = tiff.get_mut(entry + 8..entry + 10) {
+                value.copy_from_slice(&one);
+            }
+            return;
+        }
+    }
+}
+
+/// EXIFの向き (1-8) を画素に反映する。
+pub fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+pub fn resize_to_fit(image: DynamicImage, resize: &ExportResize) -> DynamicImage {
+    let scale = [
+        resize.max_width.map(|width| width as f64 / image.width() as f64),
+        resize.max_height.map(|height| height as f64 / image.height() as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+    if scale >= 1.0 {
+        return image;
+    }
+    let width = ((image.width() as f64 * scale).round() as u32).max(1);
+    let height = ((image.height() as f64 * scale).round() as u32).max(1);
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+fn validate_options(options: &ExportOptions) -> Result<(), ExportError> {
+    if !(1..=100).contains(&options.quality) {
+        return Err(ExportError::InvalidOptions(format!("Invalid quality: {}", options.quality)));
+    }
+    if let Some(resize) = &options.resize {
+        if resize.max_width.is_none() && resize.max_height.is_none() {
+            return Err(ExportError::InvalidOptions("Resize needs a width or height".to_string()));
+        }
+        if resize.max_width == Some(0) || resize.max_height == Some(0) {
+            return Err(ExportError::InvalidOptions("Resize dimensions must be positive".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// 透過の有無を保ったまま8bitのRGBにする
+fn to_8bit(image: &DynamicImage) -> DynamicImage {
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    }
+}
+
+/// JPEGは透過できないので白の上に合成する。
+fn flatten_alpha(image: &DynamicImage) -> DynamicImage {
+    let grayscale = matches!(image, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_));
+    let image = if image.color().has_alpha() {
+        let mut background = RgbaImage::from_pixel(image.width(), image.height(), Rgba([255, 255, 255, 255]));
+        image::imageops::overlay(&mut background, &image.to_rgba8(), 0, 0);
+        DynamicImage::ImageRgba8(background)
+    } else {
+        image.clone()
+    };
+    if grayscale {
+        DynamicImage::ImageLuma8(image.to_luma8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    }
+}
+
+/// PNGとTIFFは16bitまで保つ。浮動小数点の画像は16bitにする。
+fn to_integer(image: &DynamicImage, format: ExportFormat) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        DynamicImage::ImageLumaA8(_) if format == ExportFormat::Tiff => DynamicImage::ImageRgba8(image.to_rgba8()),
+        DynamicImage::ImageLumaA16(_) if format == ExportFormat::Tiff => DynamicImage::ImageRgba16(image.to_rgba16()),
+        _ => image.clone(),
+    }
+}
+
+#[cfg(feature = "avif-encoder")]
+fn encode_avif(image: &DynamicImage, quality: u8, buffer: &mut Vec<u8>) -> Result<(), String> {
+    use image::codecs::avif::AvifEncoder;
+    use image::ImageEncoder;
+    let image = to_8bit(image);
+    AvifEncoder::new_with_speed_quality(buffer, 6, quality)
+        .write_image(image.as_bytes(), image.width(), image.height(), image.color())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "avif-encoder"))]
+fn encode_avif(_image: &DynamicImage, _quality: u8, _buffer: &mut Vec<u8>) -> Result<(), String> {
+    Err(AVIF_ENCODER_UNSUPPORTED.to_string())
+}
+
+pub const AVIF_ENCODER_UNSUPPORTED: &str = "AVIF export requires the avif-encoder feature";
+
+pub fn encode_image(image: &DynamicImage, options: &ExportOptions) -> Result<Vec<u8>, ExportError> {
+    let mut buffer = Vec::new();
+    let mut cursor = Cursor::new(&mut buffer);
+    let encode = |e: image::ImageError| ExportError::Encode(e.to_string());
+    match options.format {
+        ExportFormat::Jpeg => JpegEncoder::new_with_quality(&mut cursor, options.quality)
+            .encode_image(&flatten_alpha(image))
+            .map_err(encode)?,
+        ExportFormat::Png => to_integer(image, options.format).write_to(&mut cursor, ImageOutputFormat::Png).map_err(encode)?,
+        ExportFormat::Tiff => to_integer(image, options.format).write_to(&mut cursor, ImageOutputFormat::Tiff).map_err(encode)?,
+        ExportFormat::Webp if options.quality == 100 => to_8bit(image).write_to(&mut cursor, ImageOutputFormat::WebP).map_err(encode)?,
+        ExportFormat::Webp => encode_lossy_webp(&to_8bit(image), options.quality, &mut buffer).map_err(ExportError::UnsupportedFormat)?,
+        ExportFormat::Avif => encode_avif(image, options.quality, &mut buffer).map_err(ExportError::UnsupportedFormat)?,
+    }
+    Ok(buffer)
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc.sum().to_be_bytes());
+    chunk
+}
+
+/// JPEGではSOIの直後にAPP1 (EXIF) とAPP2 (ICC) を、PNGではIHDRの直後にeXIfとiCCPを入れる。
+pub fn embed_metadata(encoded: Vec<u8>, format: ExportFormat, icc_profile: Option<&[u8]>, exif: Option<&[u8]>) -> Result<Vec<u8>, ExportError> {
+    let mut segments = Vec::new();
+    match format {
+        ExportFormat::Jpeg => {
+            const MAX_SEGMENT: usize = 65533;
+            if let Some(exif) = exif.filter(|exif| exif.len() + 6 <= MAX_SEGMENT) {
+                segments.extend_from_slice(&[0xFF, 0xE1]);
+                segments.extend_from_slice(&((exif.len() + 8) as u16).to_be_bytes());
+                segments.extend_from_slice(b"Exif\0\0");
+                segments.extend_from_slice(exif);
+            }
+            if let Some(icc_profile) = icc_profile {
+                // 1つのAPP2に入らないプロファイルは分割し、通し番号を付ける
+                let chunks: Vec<&[u8]> = icc_profile.chunks(MAX_SEGMENT - 14).collect();
+                if chunks.len() > 255 {
+                    return Err(ExportError::Encode("ICC profile is too large".to_string()));
+                }
+                for (index, chunk) in chunks.iter().enumerate() {
+                    segments.extend_from_slice(&[0xFF, 0xE2]);
+                    segments.extend_from_slice(&((chunk.len() + 16) as u16).to_be_bytes());
+                    segments.extend_from_slice(b"ICC_PROFILE\0");
+                    segments.extend_from_slice(&[index as u8 + 1, chunks.len() as u8]);
+                    segments.extend_from_slice(chunk);
+                }
+            }
+            insert_at(encoded, 2, &segments)
+        }
+        ExportFormat::Png => {
+            if let Some(icc_profile) = icc_profile {
+                let mut encoder = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), Compression::default());
+                encoder.write_all(icc_profile).map_err(|e| ExportError::Encode(e.to_string()))?;
+                let data = encoder.finish().map_err(|e| ExportError::Encode(e.to_string()))?;
+                segments.extend(png_chunk(b"iCCP", &data));
+            }
+            if let Some(exif) = exif {
+                segments.extend(png_chunk(b"eXIf", exif));
+            }
+            // シグネチャ8バイト + IHDR 25バイト
+            insert_at(encoded, 33, &segments)
+        }
+        _ => Ok(encoded),
+    }
+}
+
+fn insert_at(mut encoded: Vec<u8>, offset: usize, data: &[u8]) -> Result<Vec<u8>, ExportError> {
+    if encoded.len() < offset {
+        return Err(ExportError::Encode("Encoded image is too short".to_string()));
+    }
+    encoded.splice(offset..offset, data.iter().copied());
+    Ok(encoded)
+}
+
+/// 書き出しの設定と書き込み先を確かめる。
+pub fn check_export(destination: &str, options: &ExportOptions) -> Result<(), ExportError> {
+    validate_options(options)?;
+    if !options.overwrite && Path::new(destination).exists() {
+        return Err(ExportError::AlreadyExists(destination.to_string()));
+    }
+    Ok(())
+}
+
+pub fn load_source_metadata(source: &str) -> SourceMetadata {
+    archive::read_file(Path::new(source))
+        .map(|data| read_source_metadata(&data))
+        .unwrap_or_default()
+}
+
+/// 向きの処理を済ませた画像を縮小・変換して `destination` に書き込む。
+pub fn write_export(image: DynamicImage, metadata: &SourceMetadata, destination: &str, options: &ExportOptions) -> Result<ExportResult, ExportError> {
+    let image = match (&metadata.icc_profile, options.color_space) {
+        (Some(icc_profile), ExportColorSpace::Srgb) => convert_to_srgb(&image, icc_profile)
+            .map_err(ExportError::Encode)?
+            .unwrap_or(image),
+        _ => image,
+    };
+    let image = match &options.resize {
+        Some(resize) => resize_to_fit(image, resize),
+        None => image,
+    };
+    let image = if options.color_space == ExportColorSpace::Grayscale { image.grayscale() } else { image };
+
+    let encoded = encode_image(&image, options)?;
+    // グレースケールにした場合、元のRGBのプロファイルは使えない
+    let icc_profile = metadata.icc_profile.as_deref().filter(|_| options.color_space == ExportColorSpace::Preserve);
+    let exif = metadata.exif.as_deref().filter(|_| !options.strip_metadata && options.format.carries_exif());
+    let encoded = embed_metadata(encoded, options.format, icc_profile, exif)?;
+
+    fs::write(destination, &encoded).map_err(|e| ExportError::Write(e.to_string()))?;
+    Ok(ExportResult {
+        path: destination.to_string(),
+        width: image.width(),
+        height: image.height(),
+        bytes: encoded.len() as u64,
+    })
+}
+
+/// 画像を読み込み、変換して `destination` に書き込む。
+pub fn export_image_impl(source: &str, destination: &str, options: &ExportOptions) -> Result<ExportResult, ExportError> {
+    check_export(destination, options)?;
+    let image = open_image(source).map_err(ExportError::Decode)?;
+    let metadata = load_source_metadata(source);
+    let keeps_orientation = !options.strip_metadata && options.format.carries_exif();
+    let image = if keeps_orientation { image } else { apply_orientation(image, metadata.orientation) };
+    write_export(image, &metadata, destination, options)
+}
+
+#[tauri::command]
+pub async fn export_image(source: String, destination: String, options: ExportOptions) -> Result<ExportResult, ExportError> {
+    tauri::async_runtime::spawn_blocking(move || export_image_impl(&source, &destination, &options))
+        .await
+        .map_err(|e| ExportError::Internal(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::{Field, Value};
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    fn save_png(temp_dir: &TempDir, name: &str, image: &DynamicImage) -> String {
+        let path = temp_dir.path().join(name);
+        image.save(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn exif_orientation(orientation: u16) -> Vec<u8> {
+        let mut writer = Writer::new();
+        let field = Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![orientation]) };
+        writer.push_field(&field);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        tiff.into_inner()
+    }
+
+    #[test]
+    fn test_export_jpeg_with_resize() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = save_png(&temp_dir, "source.png", &DynamicImage::ImageRgba8(RgbaImage::from_pixel(400, 200, Rgba([255, 0, 0, 0]))));
+        let destination = temp_dir.path().join("out.jpg").to_string_lossy().into_owned();
+        let options = ExportOptions { resize: Some(ExportResize { max_width: Some(100), max_height: None }), ..ExportOptions::new(ExportFormat::Jpeg) };
+
+        let result = export_image_impl(&source, &destination, &options).unwrap();
+        assert_eq!((result.width, result.height), (100, 50));
+        let exported = image::open(&destination).unwrap();
+        assert_eq!(image::guess_format(&fs::read(&destination).unwrap()).unwrap(), ImageFormat::Jpeg);
+        // 透明な部分は白になる
+        assert!(exported.to_rgb8().get_pixel(50, 25).0.iter().all(|channel| *channel > 250));
+
+        assert_eq!(export_image_impl(&source, &destination, &options).unwrap_err(), ExportError::AlreadyExists(destination.clone()));
+        let overwrite = ExportOptions { overwrite: true, ..options };
+        assert!(export_image_impl(&source, &destination, &overwrite).is_ok());
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = save_png(&temp_dir, "source.png", &DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(8, 6, Rgb([1000u16, 20000, 65535]))));
+        for format in [ExportFormat::Png, ExportFormat::Tiff, ExportFormat::Webp] {
+            let destination = temp_dir.path().join(format!("out.{}", format.extension())).to_string_lossy().into_owned();
+            let options = ExportOptions { quality: 100, ..ExportOptions::new(format) };
+            export_image_impl(&source, &destination, &options).unwrap();
+            let exported = image::open(&destination).unwrap();
+            assert_eq!((exported.width(), exported.height()), (8, 6), "{:?}", format);
+            if format != ExportFormat::Webp {
+                assert_eq!(exported.color(), image::ColorType::Rgb16, "{:?}", format);
+            }
+        }
+
+        let grayscale = ExportOptions { color_space: ExportColorSpace::Grayscale, ..ExportOptions::new(ExportFormat::Png) };
+        let destination = temp_dir.path().join("gray.png").to_string_lossy().into_owned();
+        export_image_impl(&source, &destination, &grayscale).unwrap();
+        assert_eq!(image::open(&destination).unwrap().color(), image::ColorType::L16);
+
+        #[cfg(not(feature = "avif-encoder"))]
+        assert_eq!(
+            export_image_impl(&source, &temp_dir.path().join("out.avif").to_string_lossy(), &ExportOptions::new(ExportFormat::Avif)).unwrap_err(),
+            ExportError::UnsupportedFormat(AVIF_ENCODER_UNSUPPORTED.to_string())
+        );
+    }
+
+    #[test]
+    fn test_export_errors() {
+        let temp_dir = TempDir::new().unwrap();
+        let destination = temp_dir.path().join("out.png").to_string_lossy().into_owned();
+        let missing = temp_dir.path().join("missing.png").to_string_lossy().into_owned();
+        assert!(matches!(export_image_impl(&missing, &destination, &ExportOptions::new(ExportFormat::Png)), Err(ExportError::Decode(_))));
+
+        let invalid = ExportOptions { quality: 0, ..ExportOptions::new(ExportFormat::Jpeg) };
+        assert!(matches!(export_image_impl(&missing, &destination, &invalid), Err(ExportError::InvalidOptions(_))));
+        let invalid = ExportOptions { resize: Some(ExportResize::default()), ..ExportOptions::new(ExportFormat::Jpeg) };
+        assert!(matches!(export_image_impl(&missing, &destination, &invalid), Err(ExportError::InvalidOptions(_))));
+
+        let source = save_png(&temp_dir, "source.png", &DynamicImage::ImageRgb8(RgbImage::new(4, 4)));
+        let unwritable = temp_dir.path().join("missing_dir").join("out.png").to_string_lossy().into_owned();
+        assert!(matches!(export_image_impl(&source, &unwritable, &ExportOptions::new(ExportFormat::Png)), Err(ExportError::Write(_))));
+
+        let error = serde_json::to_value(ExportError::AlreadyExists("/a.png".to_string())).unwrap();
+        assert_eq!(error, serde_json::json!({ "kind": "already_exists", "message": "/a.png" }));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        // 横長の画像を「90度回転して表示」とし、ICCプロファイルを付けたPNGにする
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 20, Rgb([10, 20, 30])));
+        let icc_profile = vec![7u8; 100_000];
+        let encoded = encode_image(&image, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        let encoded = embed_metadata(encoded, ExportFormat::Png, Some(&icc_profile), Some(&exif_orientation(6))).unwrap();
+        let source = temp_dir.path().join("tagged.png");
+        fs::write(&source, encoded).unwrap();
+        let source = source.to_string_lossy().into_owned();
+
+        let metadata = read_source_metadata(&fs::read(&source).unwrap());
+        assert_eq!(metadata.icc_profile.as_ref(), Some(&icc_profile));
+        assert_eq!(metadata.orientation, 6);
+
+        // 既定ではEXIFを捨てて向きを画素に反映し、プロファイルは残す
+        let destination = temp_dir.path().join("stripped.jpg").to_string_lossy().into_owned();
+        let result = export_image_impl(&source, &destination, &ExportOptions::new(ExportFormat::Jpeg)).unwrap();
+        assert_eq!((result.width, result.height), (20, 40));
+        let exported = read_source_metadata(&fs::read(&destination).unwrap());
+        assert_eq!(exported.icc_profile, Some(icc_profile));
+        assert!(exported.exif.is_none());
+
+        let destination = temp_dir.path().join("kept.jpg").to_string_lossy().into_owned();
+        let options = ExportOptions { strip_metadata: false, color_space: ExportColorSpace::Untagged, ..ExportOptions::new(ExportFormat::Jpeg) };
+        let result = export_image_impl(&source, &destination, &options).unwrap();
+        assert_eq!((result.width, result.height), (40, 20));
+        let exported = read_source_metadata(&fs::read(&destination).unwrap());
+        assert_eq!(exported.orientation, 6);
+        assert!(exported.icc_profile.is_none());
+
+        // WebPにはEXIFを書き込めないので、残す指定でも向きは画素に反映する
+        let destination = temp_dir.path().join("kept.webp").to_string_lossy().into_owned();
+        let options = ExportOptions { strip_metadata: false, quality: 100, ..ExportOptions::new(ExportFormat::Webp) };
+        let result = export_image_impl(&source, &destination, &options).unwrap();
+        assert_eq!((result.width, result.height), (20, 40));
+    }
+
+    #[test]
+    fn test_heif_metadata() {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources");
+        // 向き6のEXIFを付けたAVIF。向きは使わず、引き継ぐEXIFでは1にする。
+        let metadata = read_source_metadata(&fs::read(resources.join("test_image.avif")).unwrap());
+        assert_eq!(metadata.orientation, 1);
+        let exif = Reader::new().read_raw(metadata.exif.unwrap()).unwrap();
+        assert_eq!(exif.get_field(Tag::Make, In::PRIMARY).unwrap().display_value().to_string(), "\"Test\"");
+        assert_eq!(exif.get_field(Tag::Orientation, In::PRIMARY).unwrap().value.get_uint(0), Some(1));
+
+        let metadata = read_source_metadata(&fs::read(resources.join("test_image.heic")).unwrap());
+        let exif = Reader::new().read_raw(metadata.exif.unwrap()).unwrap();
+        assert!(exif.get_field(Tag::Software, In::PRIMARY).is_some());
+
+        // IFD0の向きだけを書き換える
+        let mut tiff = exif_orientation(6);
+        assert_eq!(Reader::new().read_raw(tiff.clone()).unwrap().get_field(Tag::Orientation, In::PRIMARY).unwrap().value.get_uint(0), Some(6));
+        reset_orientation(&mut tiff);
+        assert_eq!(Reader::new().read_raw(tiff).unwrap().get_field(Tag::Orientation, In::PRIMARY).unwrap().value.get_uint(0), Some(1));
+    }
+
+    #[test]
+    fn test_export_srgb() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([200, 150, 100])));
+        let p3 = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        let encoded = encode_image(&image, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        let encoded = embed_metadata(encoded, ExportFormat::Png, Some(&p3), None).unwrap();
+        let source = temp_dir.path().join("p3.png");
+        fs::write(&source, encoded).unwrap();
+        let source = source.to_string_lossy().into_owned();
+
+        // sRGBに変換した画素を書き、プロファイルは埋め込まない
+        let destination = temp_dir.path().join("srgb.png").to_string_lossy().into_owned();
+        let options = ExportOptions { color_space: ExportColorSpace::Srgb, ..ExportOptions::new(ExportFormat::Png) };
+        export_image_impl(&source, &destination, &options).unwrap();
+        assert!(read_source_metadata(&fs::read(&destination).unwrap()).icc_profile.is_none());
+        let expected = convert_to_srgb(&image, &p3).unwrap().unwrap();
+        assert_eq!(image::open(&destination).unwrap().to_rgb8().get_pixel(0, 0), expected.to_rgb8().get_pixel(0, 0));
+
+        // プロファイルの無い画像はそのまま
+        let untagged = save_png(&temp_dir, "untagged.png", &image);
+        let destination = temp_dir.path().join("untagged-srgb.png").to_string_lossy().into_owned();
+        export_image_impl(&untagged, &destination, &options).unwrap();
+        assert_eq!(image::open(&destination).unwrap().to_rgb8().get_pixel(0, 0).0, [200, 150, 100]);
+    }
+}
@@ -1,5 +1,9 @@
 use crate::models::{FileItem, AppState, SortBy, SortOrder};
+use crate::archive::{is_archive, list_dir as list_archive_dir, list_images as list_archive_images, split_path};
+use crate::raw::find_raw_pairs;
 use crate::utils::is_image;
+use crate::formats::is_video;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -15,6 +19,9 @@ pub fn get_directory_contents(path: &str, state: State<'_, AppState>) -> Result<
 }
 
 fn get_directory_contents_impl(path: &str, image_paths: &Mutex<std::collections::HashMap<String, String>>) -> Result<Vec<FileItem>, String> {
+    if let Some((archive, dir)) = split_path(Path::new(path)) {
+        return get_archive_contents(&archive, &dir, image_paths);
+    }
     let mut items = Vec::new();
     let mut image_paths = image_paths.lock().unwrap();
     
@@ -28,13 +35,15 @@ fn get_directory_contents_impl(path: &str, image_paths: &Mutex<std::collections:
                 };
                 
                 let name = path.file_name().unwrap().to_string_lossy().into_owned();
-                let is_dir = metadata.is_dir();
+                // 書庫はフォルダとして開けるようにする
+                let is_dir = metadata.is_dir() || is_archive(&path);
                 let date_modified = metadata.modified()
                     .unwrap_or(SystemTime::UNIX_EPOCH)
                     .duration_since(UNIX_EPOCH)
                     .unwrap_or_default()
                     .as_secs();
-                let size = if is_dir { 0 } else { metadata.len() };
+                let size = if metadata.is_dir() { 0 } else { metadata.len() };
+                let is_video = !is_dir && is_video(&path);
 
                 let _id = if is_dir {
                     name.clone()
@@ -50,13 +59,63 @@ fn get_directory_contents_impl(path: &str, image_paths: &Mutex<std::collections:
                     is_dir,
                     date_modified,
                     size,
+                    raw_pair: None,
+                    is_video,
                 });
             }
+            pair_raw_items(&mut items);
             Ok(items)
         },
         Err(e) => Err(format!("Failed to read directory: {}", e)),
     }
 }
+
+/// 書庫の中のフォルダの一覧。更新日時はすべて書庫のものを使う。
+fn get_archive_contents(archive: &Path, dir: &str, image_paths: &Mutex<HashMap<String, String>>) -> Result<Vec<FileItem>, String> {
+    let date_modified = fs::metadata(archive)
+        .and_then(|meta| meta.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut image_paths = image_paths.lock().unwrap();
+    let items = list_archive_dir(archive, dir)?
+        .into_iter()
+        .map(|entry| {
+            let path = archive.join(&entry.name).to_string_lossy().into_owned();
+            if !entry.is_dir {
+                let file_id = format!("{:x}", Sha256::digest(path.as_bytes()));
+                image_paths.insert(file_id, path.clone());
+            }
+            FileItem {
+                name: entry.file_name().to_string(),
+                path,
+                is_dir: entry.is_dir,
+                date_modified,
+                size: entry.size,
+                raw_pair: None,
+                is_video: false,
+            }
+        })
+        .collect();
+    Ok(items)
+}
+
+/// 同じ名前のJPEGがあるRAWファイルを一覧から外し、JPEGの項目の `raw_pair` に入れる。
+fn pair_raw_items(items: &mut Vec<FileItem>) {
+    let pairs = find_raw_pairs(items.iter().filter(|item| !item.is_dir).map(|item| Path::new(&item.path)));
+    if pairs.is_empty() {
+        return;
+    }
+    let jpeg_to_raw: HashMap<String, String> = pairs.iter()
+        .map(|(raw, jpeg)| (jpeg.to_string_lossy().into_owned(), raw.to_string_lossy().into_owned()))
+        .collect();
+    items.retain(|item| !pairs.contains_key(Path::new(&item.path)));
+    for item in items.iter_mut() {
+        item.raw_pair = jpeg_to_raw.get(&item.path).cloned();
+    }
+}
+
 #[tauri::command]
 pub fn get_root_folders() -> Vec<FileItem> {
     let mut roots = Vec::new();
@@ -68,6 +127,8 @@ pub fn get_root_folders() -> Vec<FileItem> {
             is_dir: true,
             date_modified: 0,
             size: 0,
+            raw_pair: None,
+            is_video: false,
         });
     }
 
@@ -77,6 +138,8 @@ pub fn get_root_folders() -> Vec<FileItem> {
         is_dir: true,
         date_modified: 0,
         size: 0,
+        raw_pair: None,
+        is_video: false,
     });
 
     #[cfg(target_os = "windows")]
@@ -90,6 +153,8 @@ pub fn get_root_folders() -> Vec<FileItem> {
                     is_dir: true,
                     date_modified: 0,
                     size: 0,
+                    raw_pair: None,
+                    is_video: false,
                 });
             }
         }
@@ -102,8 +167,14 @@ pub fn get_root_folders() -> Vec<FileItem> {
 #[tauri::command]
 pub fn get_full_image_list(path: &str, sort_by: SortBy, sort_order: SortOrder) -> Result<Vec<String>, String> {
     info!("get_full_image_list called with path: {}", path);
-    let dir_path = Path::new(path);
+    list_images(Path::new(path), &sort_by, &sort_order)
+}
+
+pub fn list_images(dir_path: &Path, sort_by: &SortBy, sort_order: &SortOrder) -> Result<Vec<String>, String> {
     debug!("Directory path: {:?}", dir_path);
+    if let Some((archive, dir)) = split_path(dir_path) {
+        return list_archive_images(&archive, &dir, sort_by, sort_order);
+    }
     
     let entries = match fs::read_dir(dir_path) {
         Ok(entries) => entries,
@@ -151,7 +222,11 @@ pub fn get_full_image_list(path: &str, sort_by: SortBy, sort_order: SortOrder) -
 
     debug!("Collected images: {:?}", images);
 
-    sort_images(&mut images, &sort_by, &sort_order);
+    // RAW+JPEGはJPEGの方だけを残す
+    let pairs = find_raw_pairs(images.iter().map(|(path, _)| path.as_path()));
+    images.retain(|(path, _)| !pairs.contains_key(path));
+
+    sort_images(&mut images, sort_by, sort_order);
 
     let result: Vec<String> = images.into_iter()
         .map(|(path, _)| path.to_string_lossy().into_owned())
@@ -236,6 +311,55 @@ mod tests {
         assert!(contents.iter().any(|item| item.name == "file3.png"));
     }
 
+    #[test]
+    fn test_raw_pairs() {
+        let temp_dir = TempDir::new().unwrap();
+        for name in ["DSC_0001.NEF", "DSC_0001.JPG", "DSC_0002.NEF", "DSC_0003.jpg"] {
+            File::create(temp_dir.path().join(name)).unwrap();
+        }
+        let image_paths = Mutex::new(HashMap::new());
+
+        let contents = get_directory_contents_impl(temp_dir.path().to_str().unwrap(), &image_paths).unwrap();
+        let mut names: Vec<&str> = contents.iter().map(|item| item.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["DSC_0001.JPG", "DSC_0002.NEF", "DSC_0003.jpg"]);
+        let paired = contents.iter().find(|item| item.name == "DSC_0001.JPG").unwrap();
+        assert!(paired.raw_pair.as_ref().unwrap().ends_with("DSC_0001.NEF"));
+
+        let images = list_images(temp_dir.path(), &SortBy::Name, &SortOrder::Asc).unwrap();
+        let names: Vec<&str> = images.iter().map(|path| Path::new(path).file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["DSC_0001.JPG", "DSC_0002.NEF", "DSC_0003.jpg"]);
+    }
+
+    #[test]
+    fn test_archive_contents() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = temp_dir.path().join("comic.cbz");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for name in ["002.png", "001.png", "extras/cover.png", "info.txt"] {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            let mut data = Vec::new();
+            image::RgbImage::new(2, 2).write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap();
+        let image_paths = Mutex::new(HashMap::new());
+
+        let contents = get_directory_contents_impl(temp_dir.path().to_str().unwrap(), &image_paths).unwrap();
+        assert!(contents[0].is_dir, "Archives should be listed as folders");
+
+        let contents = get_directory_contents_impl(archive.to_str().unwrap(), &image_paths).unwrap();
+        let names: Vec<&str> = contents.iter().map(|item| item.name.as_str()).collect();
+        assert_eq!(names, vec!["extras", "002.png", "001.png", "info.txt"]);
+        assert!(contents[0].is_dir);
+        assert_eq!(contents[1].path, archive.join("002.png").to_string_lossy());
+
+        let images = get_full_image_list(archive.to_str().unwrap(), SortBy::Name, SortOrder::Asc).unwrap();
+        assert_eq!(images, vec![archive.join("001.png").to_string_lossy(), archive.join("002.png").to_string_lossy()]);
+        let extras = get_full_image_list(archive.join("extras").to_str().unwrap(), SortBy::Name, SortOrder::Asc).unwrap();
+        assert_eq!(extras.len(), 1);
+    }
+
     #[test]
     fn test_get_full_image_list() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -271,10 +395,10 @@ mod tests {
 
         let mut images: Vec<(PathBuf, std::fs::Metadata)> = fs::read_dir(&base_path)
             .unwrap()
-            .filter_map(|entry| {
+            .map(|entry| {
                 let entry = entry.unwrap();
                 let metadata = entry.metadata().unwrap();
-                Some((entry.path(), metadata))
+                (entry.path(), metadata)
             })
             .collect();
 
@@ -291,10 +415,10 @@ mod tests {
 
         let mut images: Vec<(PathBuf, std::fs::Metadata)> = fs::read_dir(&base_path)
             .unwrap()
-            .filter_map(|entry| {
+            .map(|entry| {
                 let entry = entry.unwrap();
                 let metadata = entry.metadata().unwrap();
-                Some((entry.path(), metadata))
+                (entry.path(), metadata)
             })
             .collect();
 
@@ -315,10 +439,10 @@ mod tests {
 
         let mut images: Vec<(PathBuf, std::fs::Metadata)> = fs::read_dir(&base_path)
             .unwrap()
-            .filter_map(|entry| {
+            .map(|entry| {
                 let entry = entry.unwrap();
                 let metadata = entry.metadata().unwrap();
-                Some((entry.path(), metadata))
+                (entry.path(), metadata)
             })
             .collect();
 
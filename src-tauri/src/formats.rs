@@ -0,0 +1,340 @@
+use crate::{archive, heif, pdf, raw, svg, video};
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageFormat};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatDecoder {
+    Image(ImageFormat),
+    /// libheifでデコードする (`heic` フィーチャー)
+    #[cfg_attr(not(feature = "heic"), allow(dead_code))]
+    Heif,
+    /// カメラのRAW。埋め込みプレビューを使い、`raw-demosaic` フィーチャーがあれば現像する。
+    Raw,
+    /// resvgでラスタライズする (`svg` フィーチャー)
+    #[cfg_attr(not(feature = "svg"), allow(dead_code))]
+    Svg,
+    /// PDFiumでページを描画する (`pdf` フィーチャー)
+    #[cfg_attr(not(feature = "pdf"), allow(dead_code))]
+    Pdf,
+    /// ffmpegでポスターフレームを取り出す (`video` フィーチャー)
+    #[cfg_attr(not(feature = "video"), allow(dead_code))]
+    Video,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SupportedFormat {
+    pub name: &'static str,
+    /// 小文字の拡張子 (ドットなし)
+    pub extensions: &'static [&'static str],
+    pub mime_type: &'static str,
+    /// WebViewがそのまま表示できるか。できない形式はバックエンドでデコードして渡す。
+    pub browser_native: bool,
+    #[serde(skip)]
+    pub decoder: FormatDecoder,
+}
+
+/// 常に対応している画像形式。フィーチャーで追加される形式は `supported_formats` が加える。
+const BUILTIN_FORMATS: &[SupportedFormat] = &[
+    SupportedFormat { name: "JPEG", extensions: &["jpg", "jpeg"], mime_type: "image/jpeg", browser_native: true, decoder: FormatDecoder::Image(ImageFormat::Jpeg) },
+    SupportedFormat { name: "PNG", extensions: &["png"], mime_type: "image/png", browser_native: true, decoder: FormatDecoder::Image(ImageFormat::Png) },
+    SupportedFormat { name: "GIF", extensions: &["gif"], mime_type: "image/gif", browser_native: true, decoder: FormatDecoder::Image(ImageFormat::Gif) },
+    SupportedFormat { name: "BMP", extensions: &["bmp"], mime_type: "image/bmp", browser_native: true, decoder: FormatDecoder::Image(ImageFormat::Bmp) },
+    SupportedFormat { name: "WebP", extensions: &["webp"], mime_type: "image/webp", browser_native: true, decoder: FormatDecoder::Image(ImageFormat::WebP) },
+    SupportedFormat { name: "TIFF", extensions: &["tif", "tiff"], mime_type: "image/tiff", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Tiff) },
+    SupportedFormat { name: "ICO", extensions: &["ico"], mime_type: "image/x-icon", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Ico) },
+    SupportedFormat { name: "TGA", extensions: &["tga"], mime_type: "image/x-tga", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Tga) },
+    SupportedFormat { name: "PNM", extensions: &["pbm", "pgm", "ppm", "pnm", "pam"], mime_type: "image/x-portable-anymap", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Pnm) },
+    SupportedFormat { name: "QOI", extensions: &["qoi"], mime_type: "image/x-qoi", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Qoi) },
+    SupportedFormat { name: "Radiance HDR", extensions: &["hdr"], mime_type: "image/vnd.radiance", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Hdr) },
+    SupportedFormat { name: "OpenEXR", extensions: &["exr"], mime_type: "image/x-exr", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::OpenExr) },
+    SupportedFormat { name: "DDS", extensions: &["dds"], mime_type: "image/vnd-ms.dds", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Dds) },
+    SupportedFormat { name: "Canon RAW", extensions: &["cr2"], mime_type: "image/x-canon-cr2", browser_native: false, decoder: FormatDecoder::Raw },
+    SupportedFormat { name: "Nikon RAW", extensions: &["nef"], mime_type: "image/x-nikon-nef", browser_native: false, decoder: FormatDecoder::Raw },
+    SupportedFormat { name: "Sony RAW", extensions: &["arw"], mime_type: "image/x-sony-arw", browser_native: false, decoder: FormatDecoder::Raw },
+    SupportedFormat { name: "DNG", extensions: &["dng"], mime_type: "image/x-adobe-dng", browser_native: false, decoder: FormatDecoder::Raw },
+    SupportedFormat { name: "Fujifilm RAW", extensions: &["raf"], mime_type: "image/x-fuji-raf", browser_native: false, decoder: FormatDecoder::Raw },
+];
+
+/// 対応している画像形式の一覧。`is_image` もデコードもこの一覧を使う。
+pub fn supported_formats() -> &'static [SupportedFormat] {
+    static FORMATS: OnceLock<Vec<SupportedFormat>> = OnceLock::new();
+    FORMATS.get_or_init(|| {
+        #[allow(unused_mut)]
+        let mut formats = BUILTIN_FORMATS.to_vec();
+        #[cfg(feature = "avif")]
+        formats.push(SupportedFormat { name: "AVIF", extensions: &["avif"], mime_type: "image/avif", browser_native: false, decoder: FormatDecoder::Image(ImageFormat::Avif) });
+        #[cfg(feature = "heic")]
+        formats.push(SupportedFormat { name: "HEIF", extensions: &["heic", "heif"], mime_type: "image/heic", browser_native: false, decoder: FormatDecoder::Heif });
+        // 外部参照を読まないよう、WebViewには渡さずにラスタライズしたものを表示する
+        #[cfg(feature = "svg")]
+        formats.push(SupportedFormat { name: "SVG", extensions: &["svg", "svgz"], mime_type: "image/svg+xml", browser_native: false, decoder: FormatDecoder::Svg });
+        #[cfg(feature = "pdf")]
+        formats.push(SupportedFormat { name: "PDF", extensions: &["pdf"], mime_type: "application/pdf", browser_native: false, decoder: FormatDecoder::Pdf });
+        // 動画はポスターフレームを画像として扱う
+        #[cfg(feature = "video")]
+        formats.extend([
+            SupportedFormat { name: "MP4", extensions: &["mp4", "m4v"], mime_type: "video/mp4", browser_native: false, decoder: FormatDecoder::Video },
+            SupportedFormat { name: "QuickTime", extensions: &["mov"], mime_type: "video/quicktime", browser_native: false, decoder: FormatDecoder::Video },
+            SupportedFormat { name: "WebM", extensions: &["webm"], mime_type: "video/webm", browser_native: false, decoder: FormatDecoder::Video },
+            SupportedFormat { name: "Matroska", extensions: &["mkv"], mime_type: "video/x-matroska", browser_native: false, decoder: FormatDecoder::Video },
+        ]);
+        formats
+    })
+}
+
+pub fn format_from_extension(extension: &str) -> Option<&'static SupportedFormat> {
+    let extension = extension.to_lowercase();
+    supported_formats().iter().find(|format| format.extensions.contains(&extension.as_str()))
+}
+
+/// PDFのページ指定 (`#page=N`) 付きのパスはPDFとして扱う。
+pub fn format_from_path(path: &Path) -> Option<&'static SupportedFormat> {
+    let path = path.to_str().map(pdf::source_path).unwrap_or(path);
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(format_from_extension)
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    format_from_path(path).is_some()
+}
+
+pub fn decoder_for(path: &Path) -> Option<FormatDecoder> {
+    format_from_path(path).map(|format| format.decoder)
+}
+
+/// `image` クレートではなくlibheifでデコードする画像か
+pub fn is_heif(path: &Path) -> bool {
+    decoder_for(path) == Some(FormatDecoder::Heif)
+}
+
+/// ポスターフレームを画像として扱う動画か (`video` フィーチャー)
+pub fn is_video(path: &Path) -> bool {
+    decoder_for(path) == Some(FormatDecoder::Video)
+}
+
+/// 中身から形式を判定し、判定できなければ (TGAなど) 拡張子から決める。
+/// HEIF・RAW・SVG・PDF・動画は `image` クレートで読めない (RAWはTIFFと判定される) ので、`open_image` を使う。
+pub fn image_reader(path: &str) -> Result<ImageReader<BufReader<File>>, String> {
+    let mut reader = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| e.to_string())?;
+    if reader.format().is_none() {
+        match format_from_path(Path::new(path)).map(|format| format.decoder) {
+            Some(FormatDecoder::Image(format)) => reader.set_format(format),
+            _ => return Err(format!("Unsupported image format: {}", path)),
+        }
+    }
+    Ok(reader)
+}
+
+/// 書庫の中の画像を取り出して、メモリ上で読むためのリーダーを作る。`image` クレートで読める形式だけに対応する。
+pub fn archive_reader(archive: &Path, entry: &str) -> Result<ImageReader<Cursor<Vec<u8>>>, String> {
+    let format = match decoder_for(Path::new(entry)) {
+        Some(FormatDecoder::Image(format)) => format,
+        _ => return Err(format!("Unsupported image format in archive: {}", entry)),
+    };
+    let data = archive::read_entry(archive, entry)?;
+    memory_reader(data, format)
+}
+
+fn memory_reader(data: Vec<u8>, format: ImageFormat) -> Result<ImageReader<Cursor<Vec<u8>>>, String> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format().map_err(|e| e.to_string())?;
+    if reader.format().is_none() {
+        reader.set_format(format);
+    }
+    Ok(reader)
+}
+
+/// 書庫の中の画像の大きさをまとめて調べる。書庫は1回だけ読む。大きさの分からない画像は含まれない。
+pub fn archive_dimensions(archive: &Path, entries: &[String]) -> Result<HashMap<String, (u32, u32)>, String> {
+    let names: HashSet<String> = entries.iter()
+        .filter(|entry| matches!(decoder_for(Path::new(entry.as_str())), Some(FormatDecoder::Image(_))))
+        .cloned()
+        .collect();
+    let mut dimensions = HashMap::new();
+    archive::for_each_entry(archive, &names, |name, data| {
+        let Some(FormatDecoder::Image(format)) = decoder_for(Path::new(name)) else { return };
+        if let Ok(size) = memory_reader(data, format).and_then(|reader| reader.into_dimensions().map_err(|e| e.to_string())) {
+            dimensions.insert(name.to_string(), size);
+        }
+    })?;
+    Ok(dimensions)
+}
+
+pub fn open_image(path: &str) -> Result<DynamicImage, String> {
+    if let Some((archive, entry)) = archive::split_path(Path::new(path)) {
+        return archive_reader(&archive, &entry)?.decode().map_err(|e| e.to_string());
+    }
+    match decoder_for(Path::new(path)) {
+        Some(FormatDecoder::Heif) => heif::decode(path),
+        Some(FormatDecoder::Raw) => raw::decode(path),
+        Some(FormatDecoder::Svg) => svg::render(path, 1.0),
+        Some(FormatDecoder::Pdf) => pdf::render(path),
+        Some(FormatDecoder::Video) => video::poster_frame(path),
+        _ => image_reader(path)?.decode().map_err(|e| e.to_string()),
+    }
+}
+
+/// 画素をデコードせずに幅と高さを調べる。
+pub fn image_dimensions(path: &str) -> Result<(u32, u32), String> {
+    if let Some((archive, entry)) = archive::split_path(Path::new(path)) {
+        return archive_reader(&archive, &entry)?.into_dimensions().map_err(|e| e.to_string());
+    }
+    match decoder_for(Path::new(path)) {
+        Some(FormatDecoder::Heif) => heif::dimensions(path),
+        Some(FormatDecoder::Raw) => raw::dimensions(path),
+        Some(FormatDecoder::Svg) => svg::dimensions(path),
+        Some(FormatDecoder::Pdf) => pdf::dimensions(path),
+        Some(FormatDecoder::Video) => video::dimensions(path),
+        _ => image_reader(path)?.into_dimensions().map_err(|e| e.to_string()),
+    }
+}
+
+/// SVGやPDFを描画するときの画素数の上限。ズームしすぎてメモリを使い切らないようにする。
+pub const MAX_RASTER_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// `width` x `height` の図を `scale` 倍で描画するときの画素の幅と高さ、実際に使う倍率を返す。
+/// 画素数が `MAX_RASTER_PIXELS` を超える場合は縦横比を保ったまま収まるように縮める。
+pub fn raster_size(width: f32, height: f32, scale: f32) -> (u32, u32, f32) {
+    let pixels = (width * scale) as f64 * (height * scale) as f64;
+    let limited = pixels > MAX_RASTER_PIXELS as f64;
+    let scale = if limited { scale * (MAX_RASTER_PIXELS as f64 / pixels).sqrt() as f32 } else { scale };
+    // 上限に合わせた場合は切り捨てて、端数で上限を超えないようにする
+    let round = |side: f32| {
+        let side = if limited { side.floor() } else { side.ceil() };
+        (side as u64).clamp(1, MAX_RASTER_PIXELS) as u32
+    };
+    (round(width * scale), round(height * scale), scale)
+}
+
+/// `get_supported_formats` の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct SupportedFormats {
+    pub images: &'static [SupportedFormat],
+    /// 仮想フォルダとして開く書庫の拡張子
+    pub archive_extensions: Vec<&'static str>,
+}
+
+#[tauri::command]
+pub fn get_supported_formats() -> SupportedFormats {
+    SupportedFormats {
+        images: supported_formats(),
+        archive_extensions: archive::archive_extensions(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(format_from_extension("JPG").unwrap().decoder, FormatDecoder::Image(ImageFormat::Jpeg));
+        assert_eq!(format_from_extension("tif").unwrap().decoder, FormatDecoder::Image(ImageFormat::Tiff));
+        assert_eq!(format_from_extension("pgm").unwrap().decoder, FormatDecoder::Image(ImageFormat::Pnm));
+        assert!(format_from_extension("txt").is_none());
+        assert!(is_supported(Path::new("/textures/wall.DDS")));
+        assert!(!is_supported(Path::new("/textures/README")));
+        assert_eq!(decoder_for(Path::new("DSC_0001.ARW")), Some(FormatDecoder::Raw));
+    }
+
+    #[test]
+    fn test_open_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(6, 4, Rgb([200, 100, 50])));
+        for extension in ["tiff", "ico", "tga", "ppm", "qoi", "exr"] {
+            let path = temp_dir.path().join(format!("image.{}", extension));
+            // OpenEXRは浮動小数点、ICOの中のPNGはRGBAでないと読めない
+            match extension {
+                "exr" => DynamicImage::ImageRgb32F(image.to_rgb32f()).save(&path).unwrap(),
+                "ico" => DynamicImage::ImageRgba8(image.to_rgba8()).save(&path).unwrap(),
+                _ => image.save(&path).unwrap(),
+            }
+            let decoded = open_image(path.to_str().unwrap()).unwrap_or_else(|e| panic!("{}: {}", extension, e));
+            assert_eq!((decoded.width(), decoded.height()), (6, 4), "{}", extension);
+        }
+    }
+
+    #[test]
+    fn test_open_image_by_extension() {
+        // TGAはシグネチャが無いので拡張子で判定する
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("texture.tga");
+        RgbImage::from_pixel(3, 3, Rgb([1, 2, 3])).save(&path).unwrap();
+        assert_eq!(image_reader(path.to_str().unwrap()).unwrap().format(), Some(ImageFormat::Tga));
+
+        let unknown = temp_dir.path().join("texture.bin");
+        std::fs::copy(&path, &unknown).unwrap();
+        assert!(open_image(unknown.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_raster_size() {
+        assert_eq!(raster_size(40.0, 20.0, 2.5), (100, 50, 2.5));
+        assert_eq!(raster_size(10.5, 0.1, 1.0), (11, 1, 1.0));
+
+        let (width, height, scale) = raster_size(40.0, 20.0, 10000.0);
+        assert!(width as u64 * height as u64 <= MAX_RASTER_PIXELS);
+        assert!(width as u64 * height as u64 > MAX_RASTER_PIXELS * 99 / 100);
+        assert_eq!(width / height, 2);
+        assert!(scale < 10000.0);
+    }
+
+    #[test]
+    fn test_supported_formats_serialization() {
+        let json = serde_json::to_value(get_supported_formats()).unwrap();
+        let tiff = json["images"].as_array().unwrap().iter().find(|format| format["name"] == "TIFF").unwrap();
+        assert_eq!(tiff["extensions"], serde_json::json!(["tif", "tiff"]));
+        assert_eq!(tiff["browser_native"], false);
+        assert!(tiff.get("decoder").is_none());
+        assert_eq!(json["archive_extensions"], serde_json::json!(["zip", "cbz", "tar", "cbt", "7z", "cb7"]));
+    }
+
+    #[test]
+    fn test_optional_formats() {
+        assert_eq!(is_supported(Path::new("photo.HEIC")), cfg!(feature = "heic"));
+        assert_eq!(is_heif(Path::new("photo.heif")), cfg!(feature = "heic"));
+        assert_eq!(is_supported(Path::new("asset.avif")), cfg!(feature = "avif"));
+        assert!(!is_heif(Path::new("photo.jpg")));
+        assert_eq!(is_video(Path::new("clip.webm")), cfg!(feature = "video"));
+        assert!(!is_video(Path::new("photo.jpg")));
+        assert_eq!(is_supported(Path::new("diagram.svg")), cfg!(feature = "svg"));
+        assert_eq!(is_supported(Path::new("manual.pdf#page=2")), cfg!(feature = "pdf"));
+        assert_eq!(is_supported(Path::new("clip.MP4")), cfg!(feature = "video"));
+    }
+
+    #[cfg(feature = "heic")]
+    #[test]
+    fn test_open_heic() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources/test_image.heic");
+        let path = path.to_str().unwrap();
+        assert_eq!(image_dimensions(path).unwrap(), (64, 64));
+        assert!(image_reader(path).is_err());
+        let decoded = open_image(path).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_open_avif() {
+        // 左半分が赤、右半分が青の16x8
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources/test_image.avif");
+        let path = path.to_str().unwrap();
+        assert_eq!(image_dimensions(path).unwrap(), (16, 8));
+        let decoded = open_image(path).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (16, 8));
+        let [red, _, blue] = decoded.get_pixel(2, 4).0;
+        assert!(red > 200 && blue < 50, "{:?}", decoded.get_pixel(2, 4));
+        let [red, _, blue] = decoded.get_pixel(13, 4).0;
+        assert!(red < 50 && blue > 200, "{:?}", decoded.get_pixel(13, 4));
+    }
+}
@@ -0,0 +1,129 @@
+use image::DynamicImage;
+
+#[cfg(feature = "heic")]
+use image::{RgbImage, RgbaImage};
+#[cfg(feature = "heic")]
+use libheif_rs::{ColorSpace, HeifContext, ImageHandle, ItemId, LibHeif, RgbChroma};
+
+pub const HEIC_UNSUPPORTED: &str = "HEIC/HEIF requires the heic feature";
+
+/// libheifでRGB(A)にデコードする。回転・反転はlibheifが適用する。
+#[cfg(feature = "heic")]
+fn decode_handle(handle: &ImageHandle) -> Result<DynamicImage, String> {
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+    let image = LibHeif::new()
+        .decode(handle, ColorSpace::Rgb(chroma), None)
+        .map_err(|e| e.to_string())?;
+    let plane = image.planes().interleaved.ok_or("HEIF image has no interleaved plane")?;
+    let channels = if has_alpha { 4 } else { 3 };
+
+    // 行末にパディングがあるので、幅の分だけ詰め直す
+    let row_bytes = plane.width as usize * channels;
+    let mut pixels = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+    let image = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    image.ok_or_else(|| "Invalid HEIF image buffer".to_string())
+}
+
+#[cfg(feature = "heic")]
+pub fn decode(path: &str) -> Result<DynamicImage, String> {
+    let context = HeifContext::read_from_file(path).map_err(|e| e.to_string())?;
+    let handle = context.primary_image_handle().map_err(|e| e.to_string())?;
+    decode_handle(&handle)
+}
+
+/// 埋め込みサムネイルの長辺が `size` 以上ならそれを、無ければ本体をデコードする。
+#[cfg(feature = "heic")]
+pub fn decode_thumbnail(path: &str, size: u32) -> Result<DynamicImage, String> {
+    let context = HeifContext::read_from_file(path).map_err(|e| e.to_string())?;
+    let handle = context.primary_image_handle().map_err(|e| e.to_string())?;
+    let mut ids: Vec<ItemId> = vec![0; handle.number_of_thumbnails()];
+    let count = handle.thumbnail_ids(&mut ids);
+    let thumbnail = ids[..count].iter()
+        .filter_map(|id| handle.thumbnail(*id).ok())
+        .filter(|thumbnail| thumbnail.width().max(thumbnail.height()) >= size)
+        .min_by_key(|thumbnail| thumbnail.width() * thumbnail.height());
+    match thumbnail {
+        Some(thumbnail) => decode_handle(&thumbnail),
+        None => decode_handle(&handle),
+    }
+}
+
+#[cfg(feature = "heic")]
+pub fn dimensions(path: &str) -> Result<(u32, u32), String> {
+    let context = HeifContext::read_from_file(path).map_err(|e| e.to_string())?;
+    let handle = context.primary_image_handle().map_err(|e| e.to_string())?;
+    Ok((handle.width(), handle.height()))
+}
+
+/// 埋め込みのICCプロファイル。nclxだけの場合は `None`。
+#[cfg(feature = "heic")]
+pub fn icc_profile(path: &str) -> Option<Vec<u8>> {
+    let context = HeifContext::read_from_file(path).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    handle.color_profile_raw().map(|profile| profile.data)
+}
+
+#[cfg(feature = "heic")]
+pub fn icc_profile_from_bytes(data: &[u8]) -> Option<Vec<u8>> {
+    let context = HeifContext::read_from_bytes(data).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    handle.color_profile_raw().map(|profile| profile.data)
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn decode(_path: &str) -> Result<DynamicImage, String> {
+    Err(HEIC_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn decode_thumbnail(_path: &str, _size: u32) -> Result<DynamicImage, String> {
+    Err(HEIC_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn dimensions(_path: &str) -> Result<(u32, u32), String> {
+    Err(HEIC_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn icc_profile(_path: &str) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn icc_profile_from_bytes(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(all(test, feature = "heic"))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fixture() -> String {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources/test_image.heic").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_decode_heic() {
+        let path = fixture();
+        assert_eq!(dimensions(&path).unwrap(), (64, 64));
+        let image = decode(&path).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 64));
+    }
+
+    #[test]
+    fn test_decode_heic_thumbnail() {
+        // 埋め込みサムネイルが無いので本体をデコードする
+        let image = decode_thumbnail(&fixture(), 32).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 64));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::decoder::decode_for_thumbnail;
+use image::DynamicImage;
+use serde::Serialize;
+
+/// ヒストグラムを求めるときに縮小する長辺の大きさ
+pub const HISTOGRAM_SIZE: u32 = 512;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelStats {
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+    /// 0になっている画素の割合 (%)
+    pub clipped_shadows: f64,
+    /// 255になっている画素の割合 (%)
+    pub clipped_highlights: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Histogram {
+    /// 0-255の各値の画素数
+    pub red: Vec<u32>,
+    pub green: Vec<u32>,
+    pub blue: Vec<u32>,
+    /// Rec. 709の係数で求めた輝度
+    pub luminance: Vec<u32>,
+    pub red_stats: ChannelStats,
+    pub green_stats: ChannelStats,
+    pub blue_stats: ChannelStats,
+    pub luminance_stats: ChannelStats,
+    /// どれかのチャンネルが白飛び・黒つぶれしている画素の割合 (%)
+    pub clipped_highlights: f64,
+    pub clipped_shadows: f64,
+    /// 集計した画素数。完全に透明な画素は数えない。
+    pub pixel_count: u64,
+    /// 集計に使った縮小後の大きさ
+    pub width: u32,
+    pub height: u32,
+}
+
+fn percentage(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 }
+}
+
+fn channel_stats(histogram: &[u32]) -> ChannelStats {
+    let total: u64 = histogram.iter().map(|&count| count as u64).sum();
+    let sum: u64 = histogram.iter().enumerate().map(|(value, &count)| value as u64 * count as u64).sum();
+    let mut used = histogram.iter().enumerate().filter(|(_, &count)| count > 0).map(|(value, _)| value as u8);
+    let min = used.next().unwrap_or(0);
+    let max = used.next_back().unwrap_or(min);
+    ChannelStats {
+        min,
+        max,
+        mean: if total == 0 { 0.0 } else { sum as f64 / total as f64 },
+        clipped_shadows: percentage(histogram[0] as u64, total),
+        clipped_highlights: percentage(histogram[255] as u64, total),
+    }
+}
+
+pub fn compute_histogram(image: &DynamicImage) -> Histogram {
+    let mut channels = [[0u32; 256]; 4];
+    let (mut clipped_highlights, mut clipped_shadows) = (0u64, 0u64);
+    let rgba = image.to_rgba8();
+    for pixel in rgba.pixels() {
+        let [red, green, blue, alpha] = pixel.0;
+        if alpha == 0 {
+            continue;
+        }
+        let luminance = (0.2126 * red as f32 + 0.7152 * green as f32 + 0.0722 * blue as f32).round() as u8;
+        for (channel, value) in [red, green, blue, luminance].into_iter().enumerate() {
+            channels[channel][value as usize] += 1;
+        }
+        if red == 255 || green == 255 || blue == 255 {
+            clipped_highlights += 1;
+        }
+        if red == 0 || green == 0 || blue == 0 {
+            clipped_shadows += 1;
+        }
+    }
+    let pixel_count: u64 = channels[3].iter().map(|&count| count as u64).sum();
+    let [red, green, blue, luminance] = channels.map(|histogram| histogram.to_vec());
+    Histogram {
+        red_stats: channel_stats(&red),
+        green_stats: channel_stats(&green),
+        blue_stats: channel_stats(&blue),
+        luminance_stats: channel_stats(&luminance),
+        red,
+        green,
+        blue,
+        luminance,
+        clipped_highlights: percentage(clipped_highlights, pixel_count),
+        clipped_shadows: percentage(clipped_shadows, pixel_count),
+        pixel_count,
+        width: rgba.width(),
+        height: rgba.height(),
+    }
+}
+
+/// サムネイルと同じ方法で縮小デコードした画像からヒストグラムを求める。
+pub fn image_histogram_impl(path: &str) -> Result<Histogram, String> {
+    let image = decode_for_thumbnail(path, HISTOGRAM_SIZE)?;
+    let image = if image.width().max(image.height()) > HISTOGRAM_SIZE {
+        image.thumbnail(HISTOGRAM_SIZE, HISTOGRAM_SIZE)
+    } else {
+        image
+    };
+    Ok(compute_histogram(&image))
+}
+
+#[tauri::command]
+pub async fn image_histogram(path: String) -> Result<Histogram, String> {
+    tauri::async_runtime::spawn_blocking(move || image_histogram_impl(&path))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage, RgbImage};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_compute_histogram() {
+        // 左半分は白、右半分は (0, 128, 64)、最後の列は透明
+        let image = RgbaImage::from_fn(5, 2, |x, _| match x {
+            0 | 1 => Rgba([255, 255, 255, 255]),
+            4 => Rgba([10, 10, 10, 0]),
+            _ => Rgba([0, 128, 64, 255]),
+        });
+        let histogram = compute_histogram(&DynamicImage::ImageRgba8(image));
+        assert_eq!(histogram.pixel_count, 8);
+        assert_eq!(histogram.red.len(), 256);
+        assert_eq!((histogram.red[255], histogram.red[0]), (4, 4));
+        assert_eq!(histogram.green[128], 4);
+
+        assert_eq!(histogram.red_stats, ChannelStats { min: 0, max: 255, mean: 127.5, clipped_shadows: 50.0, clipped_highlights: 50.0 });
+        assert_eq!((histogram.blue_stats.min, histogram.blue_stats.max), (64, 255));
+        assert_eq!(histogram.luminance_stats.max, 255);
+        assert_eq!(histogram.luminance[96], 4);
+        assert_eq!((histogram.clipped_highlights, histogram.clipped_shadows), (50.0, 50.0));
+    }
+
+    #[test]
+    fn test_image_histogram() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("large.png");
+        RgbImage::from_pixel(1024, 256, image::Rgb([100, 100, 100])).save(&path).unwrap();
+        let histogram = image_histogram_impl(path.to_str().unwrap()).unwrap();
+        assert_eq!((histogram.width, histogram.height), (512, 128));
+        assert_eq!(histogram.luminance[100] as u64, histogram.pixel_count);
+        assert_eq!(histogram.luminance_stats.mean, 100.0);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::formats::open_image;
+use crate::{archive, color, pdf};
+use image::DynamicImage;
+use lru::LruCache;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use log::debug;
+
+pub const DEFAULT_CACHE_BYTES: usize = 512 * 1024 * 1024;
+
+struct CachedImage {
+    image: Arc<DynamicImage>,
+    /// 表示用にsRGBへ変換した画像。変換が要らなければ `image` と同じもの。
+    display: Arc<DynamicImage>,
+    /// EXIFの向き。最初に問い合わせたときに読む。
+    orientation: Arc<OnceLock<u32>>,
+    modified: Option<SystemTime>,
+    bytes: usize,
+}
+
+struct CacheEntries {
+    entries: LruCache<String, CachedImage>,
+    used_bytes: usize,
+}
+
+/// デコード済み画像のLRUキャッシュ。件数ではなくピクセルデータの合計サイズで上限を決める。
+/// ファイルの更新日時が変わったエントリは無効として扱う。
+pub struct DecodedImageCache {
+    capacity_bytes: usize,
+    inner: Mutex<CacheEntries>,
+}
+
+impl DecodedImageCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        DecodedImageCache {
+            capacity_bytes,
+            inner: Mutex::new(CacheEntries {
+                entries: LruCache::unbounded(),
+                used_bytes: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<Arc<DynamicImage>> {
+        self.get_entry(path).map(|(image, _)| image)
+    }
+
+    pub fn get_display(&self, path: &str) -> Option<Arc<DynamicImage>> {
+        self.get_entry(path).map(|(_, display)| display)
+    }
+
+    /// 元の画像と表示用の画像
+    fn get_entry(&self, path: &str) -> Option<(Arc<DynamicImage>, Arc<DynamicImage>)> {
+        let modified = file_modified(path);
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get(path) {
+            Some(cached) if cached.modified == modified => Some((cached.image.clone(), cached.display.clone())),
+            Some(_) => {
+                if let Some(stale) = inner.entries.pop(path) {
+                    inner.used_bytes -= stale.bytes;
+                }
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// キャッシュにある画像のEXIFの向き。エントリに残しておき、最初だけ `read` で読む。
+    /// 有効なエントリが無ければ `None`。
+    pub fn orientation(&self, path: &str, read: impl FnOnce() -> u32) -> Option<u32> {
+        let modified = file_modified(path);
+        let orientation = {
+            let inner = self.inner.lock().unwrap();
+            inner.entries.peek(path)
+                .filter(|cached| cached.modified == modified)
+                .map(|cached| cached.orientation.clone())?
+        };
+        // 読む間はロックを保持しない
+        Some(*orientation.get_or_init(read))
+    }
+
+    /// LRUの順序を変えずに、有効なエントリがあるかを調べる。
+    pub fn contains(&self, path: &str) -> bool {
+        let modified = file_modified(path);
+        let inner = self.inner.lock().unwrap();
+        inner.entries.peek(path).is_some_and(|cached| cached.modified == modified)
+    }
+
+    pub fn insert(&self, path: &str, image: DynamicImage) -> Arc<DynamicImage> {
+        self.insert_entry(path, image).0
+    }
+
+    /// 表示用の変換はここで一度だけ行う。返り値は元の画像と表示用の画像。
+    fn insert_entry(&self, path: &str, image: DynamicImage) -> (Arc<DynamicImage>, Arc<DynamicImage>) {
+        let display = color::to_srgb(path, &image).map(Arc::new);
+        let image = Arc::new(image);
+        let bytes = image.as_bytes().len() + display.as_ref().map_or(0, |display| display.as_bytes().len());
+        let display = display.unwrap_or_else(|| image.clone());
+        if bytes > self.capacity_bytes {
+            debug!("Image too large to cache: {} ({} bytes)", path, bytes);
+            return (image, display);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(previous) = inner.entries.pop(path) {
+            inner.used_bytes -= previous.bytes;
+        }
+        while inner.used_bytes + bytes > self.capacity_bytes {
+            match inner.entries.pop_lru() {
+                Some((evicted_path, evicted)) => {
+                    debug!("Evicting decoded image: {}", evicted_path);
+                    inner.used_bytes -= evicted.bytes;
+                }
+                None => break,
+            }
+        }
+        inner.entries.put(path.to_string(), CachedImage {
+            image: image.clone(),
+            display: display.clone(),
+            orientation: Arc::new(OnceLock::new()),
+            modified: file_modified(path),
+            bytes,
+        });
+        inner.used_bytes += bytes;
+        (image, display)
+    }
+
+    /// キャッシュに無ければデコードして追加する。デコード中はロックを保持しない。
+    pub fn get_or_decode(&self, path: &str) -> Result<Arc<DynamicImage>, String> {
+        self.get_or_decode_entry(path).map(|(image, _)| image)
+    }
+
+    /// `get_or_decode` と同じだが、埋め込みのプロファイルに従ってsRGBに変換した画像を返す。
+    pub fn get_or_decode_display(&self, path: &str) -> Result<Arc<DynamicImage>, String> {
+        self.get_or_decode_entry(path).map(|(_, display)| display)
+    }
+
+    /// 元の画像と表示用の画像を両方返す。
+    pub fn get_or_decode_entry(&self, path: &str) -> Result<(Arc<DynamicImage>, Arc<DynamicImage>), String> {
+        if let Some(entry) = self.get_entry(path) {
+            return Ok(entry);
+        }
+        let image = open_image(path)?;
+        Ok(self.insert_entry(path, image))
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.inner.lock().unwrap().used_bytes
+    }
+}
+
+fn file_modified(path: &str) -> Option<SystemTime> {
+    archive::modified(pdf::source_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+    use tempfile::TempDir;
+
+    fn create_image(temp_dir: &TempDir, name: &str, size: u32) -> String {
+        let path = temp_dir.path().join(name);
+        RgbaImage::new(size, size).save(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_get_or_decode() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = create_image(&temp_dir, "a.png", 8);
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+
+        assert!(!cache.contains(&path));
+        let image = cache.get_or_decode(&path).unwrap();
+        assert_eq!(image.width(), 8);
+        assert!(cache.contains(&path));
+        assert_eq!(cache.used_bytes(), 8 * 8 * 4);
+        assert!(Arc::ptr_eq(&image, &cache.get_or_decode(&path).unwrap()));
+    }
+
+    #[test]
+    fn test_eviction_by_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = create_image(&temp_dir, "a.png", 8);
+        let b = create_image(&temp_dir, "b.png", 8);
+        let c = create_image(&temp_dir, "c.png", 8);
+        // 2枚分だけ入る容量
+        let cache = DecodedImageCache::new(8 * 8 * 4 * 2);
+
+        cache.get_or_decode(&a).unwrap();
+        cache.get_or_decode(&b).unwrap();
+        cache.get(&a);
+        cache.get_or_decode(&c).unwrap();
+
+        assert!(cache.contains(&a));
+        assert!(!cache.contains(&b), "Least recently used image should be evicted");
+        assert!(cache.contains(&c));
+        assert_eq!(cache.used_bytes(), 8 * 8 * 4 * 2);
+
+        let large = create_image(&temp_dir, "large.png", 32);
+        cache.get_or_decode(&large).unwrap();
+        assert!(!cache.contains(&large), "Images larger than the cache should not be stored");
+    }
+
+    #[test]
+    fn test_display_image() {
+        use crate::export::{embed_metadata, encode_image, ExportFormat, ExportOptions};
+        use image::{Rgb, RgbImage};
+        use moxcms::ColorProfile;
+
+        let temp_dir = TempDir::new().unwrap();
+        let untagged = create_image(&temp_dir, "untagged.png", 8);
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+        let display = cache.get_or_decode_display(&untagged).unwrap();
+        assert!(Arc::ptr_eq(&display, &cache.get(&untagged).unwrap()));
+        assert_eq!(cache.used_bytes(), 8 * 8 * 4);
+
+        // Display P3のPNGは変換済みの画像も一緒に置く
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([200, 150, 100])));
+        let encoded = encode_image(&image, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let tagged = temp_dir.path().join("p3.png");
+        std::fs::write(&tagged, embed_metadata(encoded, ExportFormat::Png, Some(&p3), None).unwrap()).unwrap();
+        let tagged = tagged.to_str().unwrap();
+
+        let raw = cache.get_or_decode(tagged).unwrap();
+        assert_eq!(raw.to_rgb8().get_pixel(0, 0).0, [200, 150, 100]);
+        let display = cache.get_display(tagged).unwrap();
+        assert_ne!(display.to_rgb8().get_pixel(0, 0).0, [200, 150, 100]);
+        assert!(Arc::ptr_eq(&display, &cache.get_or_decode_display(tagged).unwrap()));
+        assert_eq!(cache.used_bytes(), 8 * 8 * 4 + 8 * 8 * 3 * 2);
+    }
+
+    #[test]
+    fn test_orientation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = create_image(&temp_dir, "a.png", 8);
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+        assert_eq!(cache.orientation(&path, || 6), None);
+
+        cache.get_or_decode(&path).unwrap();
+        assert_eq!(cache.orientation(&path, || 6), Some(6));
+        assert_eq!(cache.orientation(&path, || panic!("Orientation should be read only once")), Some(6));
+
+        // 入れ直したエントリでは読み直す
+        cache.insert(&path, DynamicImage::ImageRgba8(RgbaImage::new(8, 8)));
+        assert_eq!(cache.orientation(&path, || 3), Some(3));
+    }
+
+    #[test]
+    fn test_stale_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = create_image(&temp_dir, "a.png", 8);
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+        cache.get_or_decode(&path).unwrap();
+
+        let modified = SystemTime::now() + std::time::Duration::from_secs(60);
+        filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(modified)).unwrap();
+        assert!(!cache.contains(&path));
+        assert!(cache.get(&path).is_none());
+        assert_eq!(cache.used_bytes(), 0);
+    }
+}
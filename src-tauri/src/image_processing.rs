@@ -1,33 +1,245 @@
-use crate::utils::get_cache_path;
-use image::ImageOutputFormat;
+use crate::archive::split_path;
+use crate::decoder::decode_for_thumbnail;
+use crate::models::{AppState, ThumbnailFormat, ThumbnailSettings};
+use crate::thumbnail_queue::{ThumbnailPriority, ThumbnailQueue, ThumbnailResult, CANCELLED};
+use crate::utils::get_variant_cache_path;
+use image::{DynamicImage, ImageOutputFormat};
 use base64::{engine::general_purpose, Engine as _};
+use serde::Serialize;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tauri::{State, Window};
+use log::error;
+
+pub const THUMBNAIL_SIZE: u32 = 100;
+
+pub const THUMBNAIL_EVENT: &str = "thumbnail-generated";
+pub const THUMBNAIL_BATCH_COMPLETE_EVENT: &str = "thumbnail-batch-complete";
+
+#[derive(Clone, Serialize)]
+pub struct ThumbnailBatchItem {
+    pub batch_id: String,
+    pub path: String,
+    pub thumbnail: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ThumbnailBatchSummary {
+    pub batch_id: String,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+pub enum ThumbnailBatchEvent {
+    Item(ThumbnailBatchItem),
+    Complete(ThumbnailBatchSummary),
+}
 
 #[tauri::command]
-pub async fn generate_thumbnail(path: String) -> Result<String, String> {
-    let cache_path = get_cache_path(&path);
-    
-    if cache_path.exists() {
-        let cached_thumbnail = fs::read(&cache_path).map_err(|e| e.to_string())?;
-        return Ok(format!("data:image/webp;base64,{}", general_purpose::STANDARD.encode(&cached_thumbnail)));
+pub async fn generate_thumbnail(path: String, priority: Option<ThumbnailPriority>, state: State<'_, AppState>) -> Result<String, String> {
+    let settings = state.thumbnail_settings.lock().unwrap().clone();
+    request_thumbnail(&state.thumbnail_queue, &settings, path, priority.unwrap_or_default()).await
+}
+
+/// キャッシュ済みならキューを通さずに返す。
+pub async fn request_thumbnail(queue: &ThumbnailQueue, settings: &ThumbnailSettings, path: String, priority: ThumbnailPriority) -> ThumbnailResult {
+    if let Some(cached) = read_cached_thumbnail(&path, settings) {
+        return cached;
     }
+    queue.submit(path, priority).await
+}
 
-    let img = image::open(&path).map_err(|e| e.to_string())?;
-    let thumbnail = img.thumbnail(100, 100);
-    
+/// フォルダを離れたときに、そのフォルダの未処理のサムネイル要求を取り消す。
+#[tauri::command]
+pub fn cancel_thumbnails(folder: String, state: State<'_, AppState>) -> usize {
+    state.thumbnail_queue.cancel_folder(&folder)
+}
+
+/// 複数のサムネイルをまとめて要求する。結果は完成した順に `thumbnail-generated` イベントで
+/// 呼び出し元のウィンドウに送られ、全件終わると `thumbnail-batch-complete` が送られる。
+#[tauri::command]
+pub async fn generate_thumbnails(batch_id: String, paths: Vec<String>, priority: Option<ThumbnailPriority>, window: Window, state: State<'_, AppState>) -> Result<usize, String> {
+    let count = paths.len();
+    let settings = state.thumbnail_settings.lock().unwrap().clone();
+    run_thumbnail_batch(&state.thumbnail_queue, &settings, batch_id, paths, priority.unwrap_or_default(), move |event| {
+        let result = match event {
+            ThumbnailBatchEvent::Item(item) => window.emit(THUMBNAIL_EVENT, item),
+            ThumbnailBatchEvent::Complete(summary) => window.emit(THUMBNAIL_BATCH_COMPLETE_EVENT, summary),
+        };
+        if let Err(e) = result {
+            error!("Failed to emit thumbnail event: {:?}", e);
+        }
+    });
+    Ok(count)
+}
+
+/// キャッシュ済みのものはすぐに、それ以外はキューで生成してから `emit` に渡す。
+pub fn run_thumbnail_batch<F>(queue: &ThumbnailQueue, settings: &ThumbnailSettings, batch_id: String, paths: Vec<String>, priority: ThumbnailPriority, emit: F)
+where
+    F: Fn(ThumbnailBatchEvent) + Send + Sync + 'static,
+{
+    let emit = Arc::new(emit);
+    let remaining = Arc::new(AtomicUsize::new(paths.len()));
+    let failed = Arc::new(AtomicUsize::new(0));
+    let total = paths.len();
+
+    let finish = {
+        let emit = emit.clone();
+        let batch_id = batch_id.clone();
+        move |path: String, result: ThumbnailResult| {
+            let (thumbnail, error) = match result {
+                Ok(thumbnail) => (Some(thumbnail), None),
+                Err(e) => {
+                    failed.fetch_add(1, Ordering::SeqCst);
+                    (None, Some(e))
+                }
+            };
+            emit(ThumbnailBatchEvent::Item(ThumbnailBatchItem {
+                batch_id: batch_id.clone(),
+                path,
+                thumbnail,
+                error,
+            }));
+            if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let failed = failed.load(Ordering::SeqCst);
+                emit(ThumbnailBatchEvent::Complete(ThumbnailBatchSummary {
+                    batch_id: batch_id.clone(),
+                    succeeded: total - failed,
+                    failed,
+                }));
+            }
+        }
+    };
+    let finish = Arc::new(finish);
+
+    if total == 0 {
+        emit(ThumbnailBatchEvent::Complete(ThumbnailBatchSummary { batch_id, succeeded: 0, failed: 0 }));
+        return;
+    }
+
+    for path in paths {
+        if let Some(cached) = read_cached_thumbnail(&path, settings) {
+            finish(path, cached);
+            continue;
+        }
+        match queue.enqueue(path.clone(), priority) {
+            Ok(receiver) => {
+                let finish = finish.clone();
+                tauri::async_runtime::spawn(async move {
+                    let result = receiver.await.unwrap_or_else(|_| Err(CANCELLED.to_string()));
+                    finish(path, result);
+                });
+            }
+            Err(e) => finish(path, Err(e)),
+        }
+    }
+}
+
+/// 透過の有無はデコードするまで分からないので、どちらの拡張子のキャッシュも探す。
+fn read_cached_thumbnail(path: &str, settings: &ThumbnailSettings) -> Option<ThumbnailResult> {
+    let cache_path = [false, true].into_iter()
+        .map(|has_alpha| thumbnail_cache_path(path, settings, has_alpha))
+        .find(|cache_path| cache_path.exists())?;
+    Some(fs::read(&cache_path)
+        .map(|cached_thumbnail| to_data_url(&cached_thumbnail))
+        .map_err(|e| e.to_string()))
+}
+
+/// サムネイルのキャッシュのキー。アニメーションのプレビューも同じキーを使う。
+/// 書庫の中の画像は書庫が更新されたら作り直すよう、書庫の更新日時もキーに含める。
+pub fn thumbnail_cache_key(path: &str, settings: &ThumbnailSettings) -> String {
+    let mut variant = settings.cache_key();
+    if let Some((archive, _)) = split_path(Path::new(path)) {
+        let modified = fs::metadata(archive).and_then(|meta| meta.modified()).ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_secs());
+        variant.push_str(&format!("-archive{}", modified));
+    }
+    variant
+}
+
+fn thumbnail_cache_path(path: &str, settings: &ThumbnailSettings, has_alpha: bool) -> PathBuf {
+    get_variant_cache_path(path, &thumbnail_cache_key(path, settings), settings.format.extension(has_alpha))
+}
+
+/// JPEG指定でも透過のある画像はPNGになるので、MIMEタイプは中身から判定する。
+pub fn to_data_url(buffer: &[u8]) -> String {
+    let mime_type = image::guess_format(buffer)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream");
+    format!("data:{};base64,{}", mime_type, general_purpose::STANDARD.encode(buffer))
+}
+
+pub fn encode_thumbnail(thumbnail: &DynamicImage, settings: &ThumbnailSettings) -> Result<Vec<u8>, String> {
+    let has_alpha = thumbnail.color().has_alpha();
+    // 16bitや浮動小数点の画像はどの形式でも8bitに落とす
+    let thumbnail = if has_alpha {
+        DynamicImage::ImageRgba8(thumbnail.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+    };
     let mut buffer = Vec::new();
-    thumbnail.write_to(&mut std::io::Cursor::new(&mut buffer), ImageOutputFormat::WebP).map_err(|e| e.to_string())?;
+    let mut cursor = std::io::Cursor::new(&mut buffer);
+    match settings.format {
+        ThumbnailFormat::Webp => thumbnail.write_to(&mut cursor, ImageOutputFormat::WebP).map_err(|e| e.to_string())?,
+        ThumbnailFormat::WebpLossy => encode_lossy_webp(&thumbnail, settings.quality, &mut buffer)?,
+        ThumbnailFormat::Jpeg if !has_alpha => thumbnail.write_to(&mut cursor, ImageOutputFormat::Jpeg(settings.quality)).map_err(|e| e.to_string())?,
+        ThumbnailFormat::Jpeg | ThumbnailFormat::Png => thumbnail.write_to(&mut cursor, ImageOutputFormat::Png).map_err(|e| e.to_string())?,
+    }
+    Ok(buffer)
+}
+
+#[cfg(feature = "webp-lossy")]
+pub fn encode_lossy_webp(thumbnail: &DynamicImage, quality: u8, buffer: &mut Vec<u8>) -> Result<(), String> {
+    use image::codecs::webp::{WebPEncoder, WebPQuality};
+    WebPEncoder::new_with_quality(buffer, WebPQuality::lossy(quality))
+        .encode(thumbnail.as_bytes(), thumbnail.width(), thumbnail.height(), thumbnail.color())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "webp-lossy"))]
+pub fn encode_lossy_webp(_thumbnail: &DynamicImage, _quality: u8, _buffer: &mut Vec<u8>) -> Result<(), String> {
+    Err(crate::models::LOSSY_WEBP_UNSUPPORTED.to_string())
+}
+
+pub fn resize_thumbnail(img: &DynamicImage, settings: &ThumbnailSettings) -> DynamicImage {
+    let thumbnail = match settings.filter.filter_type() {
+        Some(filter) => img.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, filter),
+        None => img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+    };
+    match settings.sharpen {
+        Some(sigma) if sigma > 0.0 => thumbnail.unsharpen(sigma, 1),
+        _ => thumbnail,
+    }
+}
+
+/// サムネイルを生成してキャッシュに書き込む。デコードを伴うのでワーカースレッドから呼ぶ。
+pub fn create_thumbnail(path: &str, settings: &ThumbnailSettings) -> Result<String, String> {
+    if let Some(cached) = read_cached_thumbnail(path, settings) {
+        return cached;
+    }
+    let img = decode_for_thumbnail(path, THUMBNAIL_SIZE)?;
+    let thumbnail = resize_thumbnail(&img, settings);
+    let buffer = encode_thumbnail(&thumbnail, settings)?;
+    let cache_path = thumbnail_cache_path(path, settings, thumbnail.color().has_alpha());
     
     fs::write(&cache_path, &buffer).map_err(|e| e.to_string())?;
     
-    Ok(format!("data:image/webp;base64,{}", general_purpose::STANDARD.encode(&buffer)))
+    Ok(to_data_url(&buffer))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{ResizeFilter, LOSSY_WEBP_UNSUPPORTED};
+    use crate::thumbnail_queue::{ThumbnailQueue, DEFAULT_THUMBNAIL_QUEUE_CAPACITY, DEFAULT_THUMBNAIL_WORKERS};
     use std::path::PathBuf;
-    use std::sync::Once;
+    use std::sync::{mpsc, Mutex, Once};
+    use std::time::Duration;
 
     static INIT: Once = Once::new();
 
@@ -44,23 +256,168 @@ mod tests {
             .join(filename)
     }
 
-    #[tokio::test]
-    async fn test_generate_thumbnail_jpg() {
+    #[test]
+    fn test_generate_thumbnail_jpg() {
         initialize();
         let image_path = get_test_image_path("test_image.jpg");
-        let result = generate_thumbnail(image_path.to_str().unwrap().to_string()).await;
+        let result = create_thumbnail(image_path.to_str().unwrap(), &ThumbnailSettings::default());
         assert!(result.is_ok(), "Thumbnail generation failed for JPEG: {:?}", result.err());
         let thumbnail = result.unwrap();
         assert!(thumbnail.starts_with("data:image/webp;base64,"));
     }
 
-    #[tokio::test]
-    async fn test_generate_thumbnail_png() {
+    #[test]
+    fn test_generate_thumbnail_png() {
         initialize();
         let image_path = get_test_image_path("test_image.png");
-        let result = generate_thumbnail(image_path.to_str().unwrap().to_string()).await;
+        let result = create_thumbnail(image_path.to_str().unwrap(), &ThumbnailSettings::default());
         assert!(result.is_ok(), "Thumbnail generation failed for PNG: {:?}", result.err());
         let thumbnail = result.unwrap();
         assert!(thumbnail.starts_with("data:image/webp;base64,"));
     }
+
+    #[tokio::test]
+    async fn test_generate_thumbnail_through_queue() {
+        initialize();
+        let queue = ThumbnailQueue::new(DEFAULT_THUMBNAIL_WORKERS, DEFAULT_THUMBNAIL_QUEUE_CAPACITY, |path| create_thumbnail(path, &ThumbnailSettings::default()));
+        let image_path = get_test_image_path("test_image.png").to_str().unwrap().to_string();
+        let result = queue.submit(image_path, ThumbnailPriority::Visible).await;
+        assert!(result.is_ok(), "Thumbnail generation failed through queue: {:?}", result.err());
+
+        let result = queue.submit("/nonexistent/image.png".to_string(), ThumbnailPriority::Visible).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_generate_thumbnail_batch() {
+        initialize();
+        let queue = ThumbnailQueue::new(DEFAULT_THUMBNAIL_WORKERS, DEFAULT_THUMBNAIL_QUEUE_CAPACITY, |path| create_thumbnail(path, &ThumbnailSettings::default()));
+        let paths = vec![
+            get_test_image_path("test_image.jpg").to_str().unwrap().to_string(),
+            get_test_image_path("test_image.png").to_str().unwrap().to_string(),
+            "/nonexistent/image.png".to_string(),
+        ];
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        run_thumbnail_batch(&queue, &ThumbnailSettings::default(), "batch-1".to_string(), paths.clone(), ThumbnailPriority::Visible, move |event| {
+            tx.lock().unwrap().send(event).unwrap();
+        });
+
+        let mut items = Vec::new();
+        let summary = loop {
+            match rx.recv_timeout(Duration::from_secs(30)).unwrap() {
+                ThumbnailBatchEvent::Item(item) => items.push(item),
+                ThumbnailBatchEvent::Complete(summary) => break summary,
+            }
+        };
+        assert_eq!(summary.batch_id, "batch-1");
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(items.len(), 3);
+        for item in &items {
+            assert_eq!(item.batch_id, "batch-1");
+            if item.path == paths[2] {
+                assert!(item.thumbnail.is_none() && item.error.is_some());
+            } else {
+                assert!(item.thumbnail.as_ref().unwrap().starts_with("data:image/webp;base64,"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_thumbnail_skips_queue() {
+        initialize();
+        let settings = ThumbnailSettings { quality: 61, ..ThumbnailSettings::default() };
+        let image_path = get_test_image_path("test_image.png").to_str().unwrap().to_string();
+        let expected = create_thumbnail(&image_path, &settings).unwrap();
+
+        let generated = Arc::new(AtomicUsize::new(0));
+        let counter = generated.clone();
+        let queue = ThumbnailQueue::new(1, 1, move |path| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(format!("Should have used the cache: {}", path))
+        });
+        assert_eq!(request_thumbnail(&queue, &settings, image_path.clone(), ThumbnailPriority::Visible).await.unwrap(), expected);
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        run_thumbnail_batch(&queue, &settings, "cached".to_string(), vec![image_path], ThumbnailPriority::Visible, move |event| {
+            tx.lock().unwrap().send(event).unwrap();
+        });
+        match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ThumbnailBatchEvent::Item(item) => assert_eq!(item.thumbnail.unwrap(), expected),
+            ThumbnailBatchEvent::Complete(_) => panic!("Expected the cached thumbnail first"),
+        }
+        assert_eq!(generated.load(Ordering::SeqCst), 0);
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_resize_thumbnail_filters() {
+        let img = image::open(get_test_image_path("test_image.png")).unwrap();
+        let expected = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        for filter in [ResizeFilter::Fast, ResizeFilter::Nearest, ResizeFilter::Triangle, ResizeFilter::CatmullRom, ResizeFilter::Lanczos3] {
+            for sharpen in [None, Some(0.5)] {
+                let thumbnail = resize_thumbnail(&img, &ThumbnailSettings { filter, sharpen, ..ThumbnailSettings::default() });
+                assert_eq!((thumbnail.width(), thumbnail.height()), (expected.width(), expected.height()), "Unexpected size for {:?}", filter);
+            }
+        }
+    }
+
+    #[test]
+    fn test_thumbnail_cache_per_settings() {
+        initialize();
+        let image_path = get_test_image_path("test_image.jpg").to_str().unwrap().to_string();
+        let settings = ThumbnailSettings { filter: ResizeFilter::Lanczos3, sharpen: Some(0.5), ..ThumbnailSettings::default() };
+        create_thumbnail(&image_path, &settings).unwrap();
+        assert!(thumbnail_cache_path(&image_path, &settings, false).exists());
+        assert_ne!(thumbnail_cache_path(&image_path, &settings, false), thumbnail_cache_path(&image_path, &ThumbnailSettings::default(), false));
+    }
+
+    #[test]
+    fn test_thumbnail_formats() {
+        initialize();
+        let image_path = get_test_image_path("test_image.png").to_str().unwrap().to_string();
+        let jpeg = ThumbnailSettings { format: ThumbnailFormat::Jpeg, quality: 70, ..ThumbnailSettings::default() };
+        assert!(create_thumbnail(&image_path, &jpeg).unwrap().starts_with("data:image/jpeg;base64,"));
+        let png = ThumbnailSettings { format: ThumbnailFormat::Png, ..ThumbnailSettings::default() };
+        assert!(create_thumbnail(&image_path, &png).unwrap().starts_with("data:image/png;base64,"));
+        assert_eq!(thumbnail_cache_path(&image_path, &png, false).extension().unwrap(), "png");
+
+        // 透過のある画像はJPEG指定でもPNGになる
+        let transparent = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        let encoded = encode_thumbnail(&transparent, &jpeg).unwrap();
+        assert!(to_data_url(&encoded).starts_with("data:image/png;base64,"));
+        // キャッシュの拡張子も書き込んだ形式に合わせ、次回はそれを読む
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let transparent_path = temp_dir.path().join("transparent.png");
+        transparent.save(&transparent_path).unwrap();
+        let transparent_path = transparent_path.to_str().unwrap();
+        let thumbnail = create_thumbnail(transparent_path, &jpeg).unwrap();
+        assert!(thumbnail_cache_path(transparent_path, &jpeg, true).exists());
+        assert!(!thumbnail_cache_path(transparent_path, &jpeg, false).exists());
+        assert_eq!(read_cached_thumbnail(transparent_path, &jpeg).unwrap().unwrap(), thumbnail);
+
+        let lossy = ThumbnailSettings { format: ThumbnailFormat::WebpLossy, ..ThumbnailSettings::default() };
+        let result = encode_thumbnail(&DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)), &lossy);
+        if cfg!(feature = "webp-lossy") {
+            assert!(to_data_url(&result.unwrap()).starts_with("data:image/webp;base64,"));
+        } else {
+            assert_eq!(result.unwrap_err(), LOSSY_WEBP_UNSUPPORTED);
+        }
+    }
+
+    #[test]
+    fn test_generate_empty_thumbnail_batch() {
+        let queue = ThumbnailQueue::new(1, 1, |path| create_thumbnail(path, &ThumbnailSettings::default()));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        run_thumbnail_batch(&queue, &ThumbnailSettings::default(), "empty".to_string(), Vec::new(), ThumbnailPriority::Visible, move |event| {
+            tx.lock().unwrap().send(event).unwrap();
+        });
+        match rx.recv_timeout(Duration::from_secs(1)).unwrap() {
+            ThumbnailBatchEvent::Complete(summary) => assert_eq!((summary.succeeded, summary.failed), (0, 0)),
+            ThumbnailBatchEvent::Item(_) => panic!("Empty batch should not emit items"),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+use crate::export::load_source_metadata;
+use crate::image_cache::DecodedImageCache;
+use crate::models::AppState;
+use image::{DynamicImage, GenericImageView};
+use serde::Serialize;
+use tauri::State;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PixelSample {
+    /// EXIFの向きを反映した、表示されている向きでの座標
+    pub x: u32,
+    pub y: u32,
+    /// 表示されている値。埋め込みのプロファイルに従ってsRGBに変換し、8bitに直したもの。
+    pub rgba: [u8; 4],
+    /// `#rrggbb`。透過している場合は `#rrggbbaa`。
+    pub hex: String,
+    /// 色相 (0-360)、彩度と輝度 (0-100)
+    pub hsl: [f64; 3],
+    /// sRGBに変換する前の値を8bitに直したもの
+    pub source_rgba: [u8; 4],
+    /// 元の画像の色の種類 (`Rgb16` など)
+    pub color_type: String,
+    /// 16bitや浮動小数点の画像の、変換前のチャンネルの値
+    pub raw: Option<Vec<f64>>,
+}
+
+pub fn to_hex([red, green, blue, alpha]: [u8; 4]) -> String {
+    if alpha == 255 {
+        format!("#{:02x}{:02x}{:02x}", red, green, blue)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", red, green, blue, alpha)
+    }
+}
+
+pub fn to_hsl([red, green, blue, _]: [u8; 4]) -> [f64; 3] {
+    let [red, green, blue] = [red, green, blue].map(|value| value as f64 / 255.0);
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return [0.0, 0.0, lightness * 100.0];
+    }
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == red {
+        60.0 * ((green - blue) / delta).rem_euclid(6.0)
+    } else if max == green {
+        60.0 * ((blue - red) / delta + 2.0)
+    } else {
+        60.0 * ((red - green) / delta + 4.0)
+    };
+    [hue, saturation * 100.0, lightness * 100.0]
+}
+
+fn raw_values(image: &DynamicImage, x: u32, y: u32) -> Option<Vec<f64>> {
+    let values: Vec<f64> = match image {
+        DynamicImage::ImageLuma16(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        DynamicImage::ImageLumaA16(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        DynamicImage::ImageRgb16(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        DynamicImage::ImageRgba16(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        DynamicImage::ImageRgb32F(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        DynamicImage::ImageRgba32F(buffer) => buffer.get_pixel(x, y).0.iter().map(|&value| value as f64).collect(),
+        _ => return None,
+    };
+    Some(values)
+}
+
+/// 向きを反映した後の座標 `(x, y)` が、大きさ `width` x `height` の元の画像のどの画素にあたるか。
+/// `export::apply_orientation` の逆の変換。範囲外なら `None`。
+pub fn source_coordinates(x: u32, y: u32, width: u32, height: u32, orientation: u32) -> Option<(u32, u32)> {
+    let (display_width, display_height) = if (5..=8).contains(&orientation) { (height, width) } else { (width, height) };
+    if x >= display_width || y >= display_height {
+        return None;
+    }
+    Some(match orientation {
+        2 => (width - 1 - x, y),
+        3 => (width - 1 - x, height - 1 - y),
+        4 => (x, height - 1 - y),
+        5 => (y, x),
+        6 => (y, height - 1 - x),
+        7 => (width - 1 - y, height - 1 - x),
+        8 => (width - 1 - y, x),
+        _ => (x, y),
+    })
+}
+
+/// `source` の画素 `(x, y)` を調べる。`display` は `source` をsRGBに変換した同じ大きさの画像。
+pub fn sample_pixel(source: &DynamicImage, display: &DynamicImage, x: u32, y: u32) -> Result<PixelSample, String> {
+    if x >= source.width() || y >= source.height() {
+        return Err(format!("Pixel ({}, {}) is outside the image ({}x{})", x, y, source.width(), source.height()));
+    }
+    let rgba = display.get_pixel(x, y).0;
+    Ok(PixelSample {
+        x,
+        y,
+        rgba,
+        hex: to_hex(rgba),
+        hsl: to_hsl(rgba),
+        source_rgba: source.get_pixel(x, y).0,
+        color_type: format!("{:?}", source.color()),
+        raw: raw_values(source, x, y),
+    })
+}
+
+/// 座標は表示されている向きでの画素単位。EXIFの向きを逆にたどって元の画像の画素を調べる。
+/// 画像と向きは表示用と同じキャッシュに残すので、続けて調べるときはデコードもEXIFの読み込みもし直さない。
+pub fn inspect_pixel_impl(cache: &DecodedImageCache, path: &str, x: u32, y: u32) -> Result<PixelSample, String> {
+    let (source, display) = cache.get_or_decode_entry(path)?;
+    let read_orientation = || load_source_metadata(path).orientation;
+    // 大きすぎてキャッシュに入らなかった画像は毎回読む
+    let orientation = cache.orientation(path, read_orientation).unwrap_or_else(read_orientation);
+    let (source_x, source_y) = source_coordinates(x, y, source.width(), source.height(), orientation)
+        .ok_or_else(|| format!("Pixel ({}, {}) is outside the image", x, y))?;
+    let sample = sample_pixel(&source, &display, source_x, source_y)?;
+    Ok(PixelSample { x, y, ..sample })
+}
+
+#[tauri::command]
+pub async fn inspect_pixel(path: String, x: u32, y: u32, state: State<'_, AppState>) -> Result<PixelSample, String> {
+    let cache = state.image_cache.clone();
+    tauri::async_runtime::spawn_blocking(move || inspect_pixel_impl(&cache, &path, x, y))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_cache::DEFAULT_CACHE_BYTES;
+    use crate::export::{apply_orientation, embed_metadata, encode_image, ExportFormat, ExportOptions};
+    use exif::experimental::Writer;
+    use exif::{Field, In, Tag, Value};
+    use image::{ImageBuffer, Rgb, RgbImage, Rgba, RgbaImage};
+    use moxcms::ColorProfile;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_color_conversions() {
+        assert_eq!(to_hex([255, 128, 0, 255]), "#ff8000");
+        assert_eq!(to_hex([255, 128, 0, 64]), "#ff800040");
+        assert_eq!(to_hsl([255, 0, 0, 255]), [0.0, 100.0, 50.0]);
+        assert_eq!(to_hsl([0, 0, 255, 255]), [240.0, 100.0, 50.0]);
+        assert_eq!(to_hsl([255, 255, 255, 255]), [0.0, 0.0, 100.0]);
+        let [hue, saturation, lightness] = to_hsl([64, 128, 96, 255]);
+        assert!((hue - 150.0).abs() < 0.5 && (saturation - 33.3).abs() < 0.5 && (lightness - 37.6).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_sample_pixel() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba([1, 2, 3, 255])));
+        let sample = sample_pixel(&image, &image, 3, 2).unwrap();
+        assert_eq!((sample.rgba, sample.hex.as_str(), sample.color_type.as_str(), sample.raw), ([1, 2, 3, 255], "#010203", "Rgba8", None));
+        assert!(sample_pixel(&image, &image, 4, 0).is_err());
+
+        let deep = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([65535u16, 257, 0])));
+        let sample = sample_pixel(&deep, &deep, 1, 1).unwrap();
+        assert_eq!(sample.rgba, [255, 1, 0, 255]);
+        assert_eq!(sample.raw, Some(vec![65535.0, 257.0, 0.0]));
+
+        let float = DynamicImage::ImageRgb32F(ImageBuffer::from_pixel(1, 1, Rgb([1.5f32, 0.5, 0.0])));
+        assert_eq!(sample_pixel(&float, &float, 0, 0).unwrap().raw, Some(vec![1.5, 0.5, 0.0]));
+    }
+
+    #[test]
+    fn test_source_coordinates() {
+        let source = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0, 255])));
+        for orientation in 1..=8 {
+            let oriented = apply_orientation(source.clone(), orientation);
+            for (x, y, pixel) in oriented.pixels() {
+                let (source_x, source_y) = source_coordinates(x, y, 3, 2, orientation).unwrap();
+                assert_eq!(source.get_pixel(source_x, source_y), pixel, "orientation {} at ({}, {})", orientation, x, y);
+            }
+            let (width, height) = oriented.dimensions();
+            assert!(source_coordinates(width, 0, 3, 2, orientation).is_none());
+            assert!(source_coordinates(0, height, 3, 2, orientation).is_none());
+        }
+    }
+
+    #[test]
+    fn test_inspect_oriented_tagged_image() {
+        let temp_dir = TempDir::new().unwrap();
+        // 左上だけ赤い横長の画像を「90度回転して表示」とし、Display P3のプロファイルを付ける
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 4, |x, y| if (x, y) == (0, 0) { Rgb([200, 50, 50]) } else { Rgb([100, 150, 200]) }));
+        let mut writer = Writer::new();
+        let orientation = Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) };
+        writer.push_field(&orientation);
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let encoded = encode_image(&image, &ExportOptions::new(ExportFormat::Png)).unwrap();
+        let encoded = embed_metadata(encoded, ExportFormat::Png, Some(&p3), Some(exif.get_ref())).unwrap();
+        let path = temp_dir.path().join("rotated.png");
+        std::fs::write(&path, encoded).unwrap();
+        let path = path.to_str().unwrap();
+
+        // 表示では4x8になり、元の左上は右上に来る
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+        let sample = inspect_pixel_impl(&cache, path, 3, 0).unwrap();
+        assert_eq!((sample.x, sample.y, sample.source_rgba), (3, 0, [200, 50, 50, 255]));
+        assert_ne!(sample.rgba, sample.source_rgba, "Displayed value should be converted to sRGB");
+        assert_eq!(sample.hex, to_hex(sample.rgba));
+        assert!(inspect_pixel_impl(&cache, path, 3, 7).is_ok());
+        assert!(inspect_pixel_impl(&cache, path, 4, 0).is_err());
+    }
+
+    #[test]
+    fn test_inspect_pixel_cached() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.png");
+        RgbaImage::from_fn(8, 8, |x, y| Rgba([x as u8 * 10, y as u8 * 10, 0, 255])).save(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let cache = DecodedImageCache::new(DEFAULT_CACHE_BYTES);
+        assert_eq!(inspect_pixel_impl(&cache, path, 2, 5).unwrap().rgba, [20, 50, 0, 255]);
+        assert!(cache.contains(path));
+        assert_eq!(inspect_pixel_impl(&cache, path, 7, 7).unwrap().hex, "#464600");
+    }
+}
@@ -3,29 +3,118 @@ mod image_processing;
 mod config;
 mod models;
 mod utils;
-use log::LevelFilter;
-use tauri::Manager;
+mod single_instance;
+mod session;
+mod navigation;
+mod image_cache;
+mod prefetch;
+mod thumbnail_queue;
+mod decoder;
+mod formats;
+mod heif;
+mod raw;
+mod svg;
+mod pdf;
+mod video;
+mod archive;
+mod reading;
+mod animation;
+mod export;
+mod batch;
+mod edit;
+mod histogram;
+mod inspector;
+mod color;
+use log::{error, LevelFilter};
+use tauri::{Manager, RunEvent, WindowEvent};
 
 fn main() {
     env_logger::Builder::from_default_env()
         .filter_level(LevelFilter::Debug)
         .init();
+
+    // 既に起動している場合は引数を転送して終了する
+    let startup_env = config::StartupEnv::from_env();
+    let listener = match single_instance::get_lock_path(&startup_env)
+        .and_then(|lock_path| single_instance::acquire(&lock_path, &startup_env.args))
+    {
+        Ok(Some(listener)) => Some(listener),
+        Ok(None) => return,
+        Err(e) => {
+            error!("Single instance check failed: {}", e);
+            None
+        }
+    };
+
+    let app_state = models::AppState::new();
+    *app_state.thumbnail_settings.lock().unwrap() = config::load_thumbnail_settings(&startup_env);
+
     tauri::Builder::default()
-        .setup(|app| {
+        .setup(move |app| {
+            if let Some(listener) = listener {
+                single_instance::start(listener, app.handle());
+            }
             let window = app.get_window("main").expect("Failed to get main window");
             #[cfg(debug_assertions)]
             window.open_devtools();
             Ok(())
         })
-        .manage(models::AppState::new())
+        .on_window_event(|event| {
+            if let WindowEvent::Destroyed = event.event() {
+                let state = event.window().state::<models::AppState>();
+                navigation::close_image_list(event.window().label(), &state);
+            }
+        })
+        .register_uri_scheme_protocol(prefetch::DECODED_SCHEME, |app, request| {
+            let state = app.state::<models::AppState>();
+            prefetch::decoded_image_response(request.uri(), &state.prefetcher)
+        })
+        .manage(app_state)
         .invoke_handler(tauri::generate_handler![
             file_system::get_directory_contents,
             file_system::get_root_folders,
             file_system::get_full_image_list,
+            formats::get_supported_formats,
             image_processing::generate_thumbnail,
+            image_processing::generate_thumbnails,
+            image_processing::cancel_thumbnails,
+            video::get_video_info,
+            animation::get_animation_info,
+            animation::get_animation_frame,
+            animation::generate_animated_thumbnail,
             config::get_startup_info,
             config::save_last_folder,
+            config::get_thumbnail_settings,
+            config::set_thumbnail_settings,
+            config::get_reading_progress,
+            config::save_reading_progress,
+            session::update_session,
+            session::set_session_position,
+            session::get_session,
+            navigation::open_image_list,
+            navigation::navigate,
+            prefetch::prepare_decoded_image,
+            reading::get_spreads,
+            export::export_image,
+            batch::start_batch_job,
+            batch::cancel_batch_job,
+            edit::get_edit_recipe,
+            edit::save_edit_recipe,
+            edit::render_edit_preview,
+            edit::save_edited_image,
+            histogram::image_histogram,
+            inspector::inspect_pixel,
+            color::get_color_profile,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                let state = app_handle.state::<models::AppState>();
+                let session = state.session.lock().unwrap().clone();
+                if let Err(e) = config::save_session(&config::StartupEnv::from_env(), &session) {
+                    error!("Failed to save session: {}", e);
+                }
+            }
+        });
 }
@@ -1,6 +1,13 @@
 use serde::{Serialize, Deserialize};
+use image::imageops::FilterType;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use crate::image_cache::{DecodedImageCache, DEFAULT_CACHE_BYTES};
+use crate::navigation::Playlist;
+use crate::prefetch::Prefetcher;
+use crate::image_processing::create_thumbnail;
+use crate::batch::BatchJobs;
+use crate::thumbnail_queue::{ThumbnailQueue, DEFAULT_THUMBNAIL_QUEUE_CAPACITY, DEFAULT_THUMBNAIL_WORKERS};
 
 #[derive(Clone, Serialize)]
 pub struct FileItem {
@@ -9,51 +16,246 @@ pub struct FileItem {
     pub is_dir: bool,
     pub date_modified: u64,
     pub size: u64,
+    /// 同じ名前のJPEGとまとめて表示しているRAWファイル
+    pub raw_pair: Option<String>,
+    /// 動画ファイルか。長さと解像度は `get_video_info` で後から取得する。
+    pub is_video: bool,
 }
 
+/// 表示中の画像リストと位置。終了時に設定ファイルへ保存され、次回起動時に復元される。
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ImageState {
     pub current_index: usize,
     pub images: Vec<String>,
+    #[serde(default = "default_zoom")]
+    pub zoom: f64,
+    #[serde(default)]
+    pub sort_by: SortBy,
+    #[serde(default)]
+    pub sort_order: SortOrder,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct AppConfig {
-    pub last_folder: Option<String>,
+fn default_zoom() -> f64 {
+    1.0
+}
+
+impl Default for ImageState {
+    fn default() -> Self {
+        ImageState {
+            current_index: 0,
+            images: Vec::new(),
+            zoom: default_zoom(),
+            sort_by: SortBy::default(),
+            sort_order: SortOrder::default(),
+        }
+    }
+}
+
+impl ImageState {
+    pub fn current_image(&self) -> Option<&String> {
+        self.images.get(self.current_index)
+    }
+
+    /// 位置がリストの範囲内で、倍率が正の有限値であることを確かめる。
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.images.is_empty() && self.current_index >= self.images.len() {
+            return Err(format!("Index {} out of range", self.current_index));
+        }
+        validate_zoom(self.zoom)
+    }
+}
+
+pub fn validate_zoom(zoom: f64) -> Result<(), String> {
+    if zoom.is_finite() && zoom > 0.0 {
+        Ok(())
+    } else {
+        Err(format!("Invalid zoom: {}", zoom))
+    }
 }
 
 pub struct AppState {
     pub image_paths: Mutex<HashMap<String, String>>,
+    pub session: Mutex<ImageState>,
+    /// ウィンドウのラベルごとの画像リスト
+    pub playlists: Mutex<HashMap<String, Playlist>>,
+    pub image_cache: Arc<DecodedImageCache>,
+    pub prefetcher: Prefetcher,
+    pub thumbnail_queue: ThumbnailQueue,
+    pub thumbnail_settings: Arc<Mutex<ThumbnailSettings>>,
+    /// 実行中の一括変換ジョブ
+    pub batch_jobs: BatchJobs,
 }
 
 impl AppState {
     pub fn new() -> Self {
+        let image_cache = Arc::new(DecodedImageCache::new(DEFAULT_CACHE_BYTES));
+        let thumbnail_settings = Arc::new(Mutex::new(ThumbnailSettings::default()));
+        let settings = thumbnail_settings.clone();
         AppState {
             image_paths: Mutex::new(HashMap::new()),
+            session: Mutex::new(ImageState::default()),
+            playlists: Mutex::new(HashMap::new()),
+            prefetcher: Prefetcher::new(image_cache.clone()),
+            image_cache,
+            thumbnail_queue: ThumbnailQueue::new(DEFAULT_THUMBNAIL_WORKERS, DEFAULT_THUMBNAIL_QUEUE_CAPACITY, move |path| {
+                let settings = settings.lock().unwrap().clone();
+                create_thumbnail(path, &settings)
+            }),
+            thumbnail_settings,
+            batch_jobs: BatchJobs::default(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum SortBy {
+    #[default]
     Name,
     Type,
     Date,
     Size,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum SortOrder {
+    #[default]
     Asc,
     Desc,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct StartupInfo {
-    pub folder: String,
-    pub file: Option<String>,
+/// サムネイル縮小時のリサンプリングフィルター
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    /// `DynamicImage::thumbnail` による高速な縮小
+    #[default]
+    Fast,
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn filter_type(&self) -> Option<FilterType> {
+        match self {
+            ResizeFilter::Fast => None,
+            ResizeFilter::Nearest => Some(FilterType::Nearest),
+            ResizeFilter::Triangle => Some(FilterType::Triangle),
+            ResizeFilter::CatmullRom => Some(FilterType::CatmullRom),
+            ResizeFilter::Lanczos3 => Some(FilterType::Lanczos3),
+        }
+    }
+}
+
+/// サムネイルのキャッシュに書き込む形式。JPEGは透過できないので、透過のある画像はPNGで書き込む。
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailFormat {
+    /// 可逆WebP
+    #[default]
+    Webp,
+    /// 非可逆WebP。`webp-lossy` フィーチャーが必要。
+    WebpLossy,
+    Jpeg,
+    Png,
+}
+
+/// `WebpLossy` を選んだが `webp-lossy` フィーチャーが無い場合のエラー
+pub const LOSSY_WEBP_UNSUPPORTED: &str = "Lossy WebP requires the webp-lossy feature";
+
+impl ThumbnailFormat {
+    /// 実際に書き込む形式の拡張子
+    pub fn extension(&self, has_alpha: bool) -> &'static str {
+        match self {
+            ThumbnailFormat::Webp | ThumbnailFormat::WebpLossy => "webp",
+            ThumbnailFormat::Jpeg if !has_alpha => "jpg",
+            ThumbnailFormat::Jpeg | ThumbnailFormat::Png => "png",
+        }
+    }
+
+    pub fn is_lossy(&self) -> bool {
+        matches!(self, ThumbnailFormat::WebpLossy | ThumbnailFormat::Jpeg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThumbnailSettings {
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    /// 縮小後にかけるアンシャープマスクのシグマ。`None` ならかけない。
+    #[serde(default)]
+    pub sharpen: Option<f32>,
+    #[serde(default)]
+    pub format: ThumbnailFormat,
+    /// 非可逆形式の品質 (1-100)
+    #[serde(default = "default_thumbnail_quality")]
+    pub quality: u8,
+}
+
+fn default_thumbnail_quality() -> u8 {
+    80
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        ThumbnailSettings {
+            filter: ResizeFilter::default(),
+            sharpen: None,
+            format: ThumbnailFormat::default(),
+            quality: default_thumbnail_quality(),
+        }
+    }
+}
+
+impl ThumbnailSettings {
+    /// キャッシュのファイル名に含める設定の識別子。既定の設定では空文字列になる。
+    pub fn cache_key(&self) -> String {
+        let mut key = String::new();
+        if self.filter != ResizeFilter::Fast {
+            key.push_str(&format!("{:?}", self.filter).to_lowercase());
+        }
+        if let Some(sigma) = self.sharpen {
+            key.push_str(&format!("-sharpen{}", sigma));
+        }
+        if self.format != ThumbnailFormat::Webp {
+            key.push_str(&format!("-{:?}", self.format).to_lowercase());
+        }
+        if self.format.is_lossy() {
+            key.push_str(&format!("-q{}", self.quality));
+        }
+        key
+    }
+}
+
+/// 見開き表示でのページの並び
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadingDirection {
+    #[default]
+    LeftToRight,
+    /// 右綴じ (日本の漫画など)。見開きの右側に先のページを置く。
+    RightToLeft,
+}
+
+/// 書庫やフォルダごとの読みかけの位置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadingProgress {
+    /// 最後に表示していたページのパス
+    pub page: String,
+    #[serde(default)]
+    pub direction: ReadingDirection,
+    /// 保存した時刻 (UNIX秒)。古いものから消すのに使う。
+    #[serde(default)]
+    pub updated: u64,
 }
 
 #[cfg(test)]
@@ -68,6 +270,8 @@ mod tests {
             is_dir: false,
             date_modified: 1234567890,
             size: 1024,
+            raw_pair: None,
+            is_video: false,
         };
 
         let serialized = serde_json::to_string(&file_item).unwrap();
@@ -107,18 +311,29 @@ mod tests {
     }
 
     #[test]
-    fn test_startup_info_serialization() {
-        let startup_info = StartupInfo {
-            folder: "/home/user".to_string(),
-            file: Some("/home/user/image.jpg".to_string()),
-        };
+    fn test_image_state_defaults() {
+        let state: ImageState = serde_json::from_str(r#"{"current_index":1,"images":["a.jpg","b.jpg"]}"#).unwrap();
+        assert_eq!(state.zoom, 1.0);
+        assert_eq!(state.sort_by, SortBy::Name);
+        assert_eq!(state.sort_order, SortOrder::Asc);
+        assert_eq!(state.current_image(), Some(&"b.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_thumbnail_settings_cache_key() {
+        assert_eq!(ThumbnailSettings::default().cache_key(), "");
 
-        let serialized = serde_json::to_string(&startup_info).unwrap();
-        assert!(serialized.contains("/home/user"));
-        assert!(serialized.contains("/home/user/image.jpg"));
+        let settings: ThumbnailSettings = serde_json::from_str(r#"{"filter":"catmull_rom","sharpen":0.5}"#).unwrap();
+        assert_eq!(settings.filter, ResizeFilter::CatmullRom);
+        assert_eq!(settings.cache_key(), "catmullrom-sharpen0.5");
+        let lanczos = ThumbnailSettings { filter: ResizeFilter::Lanczos3, ..settings.clone() };
+        assert_ne!(settings.cache_key(), lanczos.cache_key());
 
-        let deserialized: StartupInfo = serde_json::from_str(&serialized).unwrap();
-        assert_eq!(deserialized.folder, "/home/user");
-        assert_eq!(deserialized.file, Some("/home/user/image.jpg".to_string()));
+        let jpeg: ThumbnailSettings = serde_json::from_str(r#"{"format":"jpeg"}"#).unwrap();
+        assert_eq!(jpeg.quality, 80);
+        assert_eq!(jpeg.cache_key(), "-jpeg-q80");
+        assert_ne!(jpeg.cache_key(), ThumbnailSettings { quality: 60, ..jpeg.clone() }.cache_key());
+        // 可逆形式では品質はキーに含めない
+        assert_eq!(ThumbnailSettings { quality: 60, ..ThumbnailSettings::default() }.cache_key(), "");
     }
 }
\ No newline at end of file
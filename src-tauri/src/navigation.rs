@@ -0,0 +1,346 @@
+use crate::file_system::list_images;
+use crate::formats::{decoder_for, FormatDecoder};
+use crate::{archive, pdf};
+use crate::utils::is_image;
+use crate::models::{AppState, SortBy, SortOrder};
+use crate::prefetch::PREFETCH_RADIUS;
+use serde::{Serialize, Deserialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tauri::{State, Window};
+use log::debug;
+
+const NO_IMAGE_LIST: &str = "No image list opened for this window";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Navigation {
+    Next,
+    Prev,
+    First,
+    Last,
+    Random,
+    JumpTo { index: usize },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NavigationResult {
+    pub index: usize,
+    pub total: usize,
+    pub path: String,
+    /// 先読み用の前後の画像。リストの端では反対側に回り込む。
+    pub previous: Option<String>,
+    pub next: Option<String>,
+}
+
+/// 画像リストの読み込み元。ロックを持たずにリストを読み直せるように `Playlist` から取り出して使う。
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistSource {
+    folder: PathBuf,
+    sort_by: SortBy,
+    sort_order: SortOrder,
+}
+
+/// 読み込んだ画像リストと、読み込んだ時点のフォルダの更新日時
+pub struct Listing {
+    images: Vec<String>,
+    folder_modified: Option<SystemTime>,
+}
+
+impl PlaylistSource {
+    /// PDFの場合は、そのページを画像として並べる。
+    pub fn load(&self) -> Result<Listing, String> {
+        let folder_modified = folder_modified(&self.folder);
+        let images = if decoder_for(&self.folder) == Some(FormatDecoder::Pdf) {
+            pdf::page_paths(&self.folder.to_string_lossy())?
+        } else {
+            list_images(&self.folder, &self.sort_by, &self.sort_order)?
+        };
+        Ok(Listing { images, folder_modified })
+    }
+}
+
+/// ウィンドウごとに保持する並び替え済みの画像リスト。
+/// フォルダの更新日時が変わっていれば、移動の前にリストを読み直す。
+pub struct Playlist {
+    source: PlaylistSource,
+    images: Vec<String>,
+    current: usize,
+    folder_modified: Option<SystemTime>,
+}
+
+impl Playlist {
+    pub fn open(path: &Path, sort_by: SortBy, sort_order: SortOrder) -> Result<Self, String> {
+        // 書庫の中のパスは存在しないので、画像かどうかで判断する
+        let is_file = if archive::split_path(path).is_some() { is_image(path) } else { path.is_file() };
+        let (folder, selected) = if decoder_for(path) == Some(FormatDecoder::Pdf) {
+            (pdf::source_path(&path.to_string_lossy()).to_path_buf(), Some(path.to_string_lossy().into_owned()))
+        } else if is_file {
+            let folder = path.parent().ok_or("Invalid file path")?;
+            (folder.to_path_buf(), Some(path.to_string_lossy().into_owned()))
+        } else {
+            (path.to_path_buf(), None)
+        };
+
+        let source = PlaylistSource { folder, sort_by, sort_order };
+        let listing = source.load()?;
+        let mut playlist = Playlist {
+            source,
+            images: listing.images,
+            current: 0,
+            folder_modified: listing.folder_modified,
+        };
+        if let Some(selected) = selected {
+            playlist.current = playlist.images.iter().position(|image| *image == selected).unwrap_or(0);
+        }
+        Ok(playlist)
+    }
+
+    pub fn source(&self) -> &PlaylistSource {
+        &self.source
+    }
+
+    /// リストを読んだ後にフォルダが変更されたか
+    pub fn is_stale(&self) -> bool {
+        folder_modified(&self.source.folder) != self.folder_modified
+    }
+
+    /// フォルダが変更されていればリストを読み直す。
+    pub fn refresh(&mut self) -> Result<(), String> {
+        if self.is_stale() {
+            let listing = self.source.load()?;
+            self.replace_images(listing);
+        }
+        Ok(())
+    }
+
+    /// 読み直したリストに差し替え、表示中の画像の位置を合わせる。
+    /// 表示中の画像が削除されていた場合は同じ位置(末尾を越えれば最後)の画像に移る。
+    pub fn replace_images(&mut self, listing: Listing) {
+        debug!("Reloading image list for {:?}", self.source.folder);
+        let current_path = self.images.get(self.current).cloned();
+        self.images = listing.images;
+        self.folder_modified = listing.folder_modified;
+        self.current = current_path
+            .and_then(|path| self.images.iter().position(|image| *image == path))
+            .unwrap_or_else(|| self.current.min(self.images.len().saturating_sub(1)));
+    }
+
+    pub fn navigate(&mut self, navigation: Navigation) -> Result<NavigationResult, String> {
+        self.refresh()?;
+        let total = self.images.len();
+        if total == 0 {
+            return Err("No images in folder".to_string());
+        }
+
+        self.current = match navigation {
+            Navigation::Next => (self.current + 1) % total,
+            Navigation::Prev => (self.current + total - 1) % total,
+            Navigation::First => 0,
+            Navigation::Last => total - 1,
+            Navigation::Random => random_index(self.current, total),
+            Navigation::JumpTo { index } => {
+                if index >= total {
+                    return Err(format!("Index {} out of range", index));
+                }
+                index
+            }
+        };
+        self.current_result()
+    }
+
+    pub fn current_result(&self) -> Result<NavigationResult, String> {
+        let total = self.images.len();
+        let path = self.images.get(self.current).ok_or("No images in folder")?.clone();
+        let (previous, next) = if total > 1 {
+            (
+                Some(self.images[(self.current + total - 1) % total].clone()),
+                Some(self.images[(self.current + 1) % total].clone()),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(NavigationResult {
+            index: self.current,
+            total,
+            path,
+            previous,
+            next,
+        })
+    }
+
+    /// 前後 `radius` 枚の画像を、近い順(同じ距離なら次の画像を先)に返す。
+    pub fn neighbours(&self, radius: usize) -> Vec<String> {
+        let total = self.images.len();
+        let mut neighbours: Vec<String> = Vec::new();
+        for distance in 1..=radius.min(total.saturating_sub(1)) {
+            for index in [(self.current + distance) % total, (self.current + total - distance) % total] {
+                let path = &self.images[index];
+                if index != self.current && !neighbours.contains(path) {
+                    neighbours.push(path.clone());
+                }
+            }
+        }
+        neighbours
+    }
+
+    pub fn images(&self) -> &[String] {
+        &self.images
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+}
+
+fn folder_modified(folder: &Path) -> Option<SystemTime> {
+    archive::modified(folder)
+}
+
+fn random_index(current: usize, total: usize) -> usize {
+    if total <= 1 {
+        return 0;
+    }
+    // 現在の画像以外から選ぶ
+    let random = RandomState::new().build_hasher().finish() as usize;
+    (current + 1 + random % (total - 1)) % total
+}
+
+/// フォルダの読み込みはロックの外で行い、他のウィンドウの操作を待たせない。
+#[tauri::command]
+pub async fn open_image_list(path: String, sort_by: SortBy, sort_order: SortOrder, window: Window, state: State<'_, AppState>) -> Result<NavigationResult, String> {
+    let playlist = tauri::async_runtime::spawn_blocking(move || Playlist::open(Path::new(&path), sort_by, sort_order))
+        .await
+        .map_err(|e| e.to_string())??;
+    let result = playlist.current_result();
+    state.prefetcher.request(playlist.neighbours(PREFETCH_RADIUS));
+    state.playlists.lock().unwrap().insert(window.label().to_string(), playlist);
+    result
+}
+
+#[tauri::command]
+pub async fn navigate(navigation: Navigation, window: Window, state: State<'_, AppState>) -> Result<NavigationResult, String> {
+    let label = window.label().to_string();
+    let stale = {
+        let playlists = state.playlists.lock().unwrap();
+        let playlist = playlists.get(&label).ok_or(NO_IMAGE_LIST)?;
+        playlist.is_stale().then(|| playlist.source().clone())
+    };
+    if let Some(source) = stale {
+        let reload = source.clone();
+        let listing = tauri::async_runtime::spawn_blocking(move || reload.load())
+            .await
+            .map_err(|e| e.to_string())??;
+        // 読み込み中に別のリストが開かれていれば差し替えない
+        let mut playlists = state.playlists.lock().unwrap();
+        if let Some(playlist) = playlists.get_mut(&label).filter(|playlist| *playlist.source() == source) {
+            playlist.replace_images(listing);
+        }
+    }
+
+    let mut playlists = state.playlists.lock().unwrap();
+    let playlist = playlists.get_mut(&label).ok_or(NO_IMAGE_LIST)?;
+    let result = playlist.navigate(navigation)?;
+    state.prefetcher.request(playlist.neighbours(PREFETCH_RADIUS));
+    Ok(result)
+}
+
+pub fn close_image_list(label: &str, state: &AppState) {
+    state.playlists.lock().unwrap().remove(label);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use filetime::{set_file_mtime, FileTime};
+    use tempfile::TempDir;
+
+    fn create_test_folder(names: &[&str]) -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        for name in names {
+            fs::write(temp_dir.path().join(name), "").unwrap();
+        }
+        temp_dir
+    }
+
+    fn file_name(path: &str) -> &str {
+        Path::new(path).file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn test_navigate() {
+        let temp_dir = create_test_folder(&["a.jpg", "b.png", "c.gif", "notes.txt"]);
+        let selected = temp_dir.path().join("b.png");
+        let mut playlist = Playlist::open(&selected, SortBy::Name, SortOrder::Asc).unwrap();
+
+        let result = playlist.current_result().unwrap();
+        assert_eq!(result.index, 1);
+        assert_eq!(result.total, 3);
+        assert_eq!(file_name(result.previous.as_ref().unwrap()), "a.jpg");
+        assert_eq!(file_name(result.next.as_ref().unwrap()), "c.gif");
+
+        assert_eq!(file_name(&playlist.navigate(Navigation::Next).unwrap().path), "c.gif");
+        assert_eq!(file_name(&playlist.navigate(Navigation::Next).unwrap().path), "a.jpg");
+        assert_eq!(file_name(&playlist.navigate(Navigation::Prev).unwrap().path), "c.gif");
+        assert_eq!(file_name(&playlist.navigate(Navigation::First).unwrap().path), "a.jpg");
+        assert_eq!(file_name(&playlist.navigate(Navigation::Last).unwrap().path), "c.gif");
+        assert_eq!(file_name(&playlist.navigate(Navigation::JumpTo { index: 1 }).unwrap().path), "b.png");
+        assert!(playlist.navigate(Navigation::JumpTo { index: 3 }).is_err());
+
+        let random = playlist.navigate(Navigation::Random).unwrap();
+        assert_ne!(random.index, 1, "Random should move away from the current image");
+    }
+
+    #[test]
+    fn test_navigate_after_folder_changes() {
+        let temp_dir = create_test_folder(&["a.jpg", "c.jpg", "e.jpg"]);
+        let mut playlist = Playlist::open(&temp_dir.path().join("c.jpg"), SortBy::Name, SortOrder::Asc).unwrap();
+
+        // 更新日時の分解能に左右されないよう、フォルダの更新日時を明示的に進める
+        let touch = |seconds: i64| set_file_mtime(temp_dir.path(), FileTime::from_unix_time(FileTime::now().unix_seconds() + seconds, 0)).unwrap();
+        fs::write(temp_dir.path().join("b.jpg"), "").unwrap();
+        touch(10);
+        let result = playlist.navigate(Navigation::Next).unwrap();
+        assert_eq!(result.total, 4);
+        assert_eq!(file_name(&result.path), "e.jpg");
+
+        fs::remove_file(temp_dir.path().join("e.jpg")).unwrap();
+        touch(20);
+        playlist.refresh().unwrap();
+        let result = playlist.current_result().unwrap();
+        assert_eq!(result.total, 3);
+        assert_eq!(file_name(&result.path), "c.jpg");
+    }
+
+    #[test]
+    fn test_neighbours() {
+        let temp_dir = create_test_folder(&["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"]);
+        let playlist = Playlist::open(&temp_dir.path().join("a.jpg"), SortBy::Name, SortOrder::Asc).unwrap();
+        let neighbours: Vec<String> = playlist.neighbours(2).iter().map(|path| file_name(path).to_string()).collect();
+        assert_eq!(neighbours, vec!["b.jpg", "e.jpg", "c.jpg", "d.jpg"]);
+
+        let temp_dir = create_test_folder(&["a.jpg", "b.jpg"]);
+        let playlist = Playlist::open(&temp_dir.path().join("a.jpg"), SortBy::Name, SortOrder::Asc).unwrap();
+        let neighbours: Vec<String> = playlist.neighbours(2).iter().map(|path| file_name(path).to_string()).collect();
+        assert_eq!(neighbours, vec!["b.jpg"]);
+    }
+
+    #[test]
+    fn test_navigate_empty_folder() {
+        let temp_dir = create_test_folder(&["notes.txt"]);
+        let mut playlist = Playlist::open(temp_dir.path(), SortBy::Name, SortOrder::Asc).unwrap();
+        assert!(playlist.current_result().is_err());
+        assert!(playlist.navigate(Navigation::Next).is_err());
+    }
+
+    #[test]
+    fn test_navigation_deserialization() {
+        let navigation: Navigation = serde_json::from_str(r#"{"type":"jump_to","index":4}"#).unwrap();
+        assert!(matches!(navigation, Navigation::JumpTo { index: 4 }));
+        let navigation: Navigation = serde_json::from_str(r#"{"type":"prev"}"#).unwrap();
+        assert!(matches!(navigation, Navigation::Prev));
+    }
+}
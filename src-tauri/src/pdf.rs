@@ -0,0 +1,181 @@
+use image::DynamicImage;
+use std::path::Path;
+
+#[cfg(feature = "pdf")]
+use crate::formats::raster_size;
+#[cfg(feature = "pdf")]
+use image::RgbaImage;
+#[cfg(feature = "pdf")]
+use pdfium_render::prelude::*;
+#[cfg(feature = "pdf")]
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+pub const PDF_UNSUPPORTED: &str = "PDF requires the pdf feature";
+
+/// 表示用に描画するときの解像度
+pub const PDF_RENDER_DPI: f32 = 150.0;
+
+const PAGE_SUFFIX: &str = "#page=";
+
+/// PDFのページを画像のパスとして扱うための `<PDFのパス>#page=<1始まりのページ番号>` を作る。
+pub fn page_path(path: &str, index: usize) -> String {
+    format!("{}{}{}", path, PAGE_SUFFIX, index + 1)
+}
+
+/// ページ指定付きのパスをPDFのパスと0始まりのページ番号に分ける。ページ指定が無ければ最初のページ。
+pub fn split_page_path(path: &str) -> (&str, usize) {
+    match path.rsplit_once(PAGE_SUFFIX) {
+        Some((pdf, page)) => match page.parse::<usize>() {
+            Ok(page) if page > 0 => (pdf, page - 1),
+            _ => (path, 0),
+        },
+        None => (path, 0),
+    }
+}
+
+/// ページ指定を除いた、実際のファイルのパス
+pub fn source_path(path: &str) -> &Path {
+    Path::new(split_page_path(path).0)
+}
+
+#[cfg(feature = "pdf")]
+fn bind() -> Result<Pdfium, String> {
+    // アプリと同じ場所に置いたPDFiumを優先し、無ければシステムのものを使う
+    let bindings = Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
+        .or_else(|_| Pdfium::bind_to_system_library())
+        .map_err(|e| e.to_string())?;
+    Ok(Pdfium::new(bindings))
+}
+
+#[cfg(feature = "pdf")]
+static PDFIUM: OnceLock<Result<Mutex<Pdfium>, String>> = OnceLock::new();
+
+/// PDFiumはプロセスで一度だけ読み込む。同時には使えないので、使っている間はロックを保持する。
+#[cfg(feature = "pdf")]
+fn pdfium() -> Result<MutexGuard<'static, Pdfium>, String> {
+    let pdfium = PDFIUM.get_or_init(|| bind().map(Mutex::new)).as_ref().map_err(|e| e.clone())?;
+    Ok(pdfium.lock().unwrap())
+}
+
+#[cfg(feature = "pdf")]
+fn with_page<T>(path: &str, f: impl FnOnce(&PdfPage) -> Result<T, String>) -> Result<T, String> {
+    let (pdf, index) = split_page_path(path);
+    let pdfium = pdfium()?;
+    let document = pdfium.load_pdf_from_file(pdf, None).map_err(|e| e.to_string())?;
+    let index = PdfPageIndex::try_from(index).map_err(|e| e.to_string())?;
+    let page = document.pages().get(index).map_err(|e| e.to_string())?;
+    f(&page)
+}
+
+/// ページを描画するときの画素の幅と高さ。SVGと同じく `MAX_RASTER_PIXELS` に収める。
+#[cfg(feature = "pdf")]
+fn page_size(page: &PdfPage, scale: f32) -> (u32, u32) {
+    let (width, height, _) = raster_size(page.width().value, page.height().value, scale);
+    (width, height)
+}
+
+/// ページを `scale` 倍 (1.0で72dpi) で描画する。描画はCPUで行う。
+#[cfg(feature = "pdf")]
+fn render_page(page: &PdfPage, scale: f32) -> Result<DynamicImage, String> {
+    let (width, height) = page_size(page, scale);
+    let config = PdfRenderConfig::new()
+        .set_target_size(width as Pixels, height as Pixels)
+        .render_form_data(true);
+    let bitmap = page.render_with_config(&config).map_err(|e| e.to_string())?;
+    RgbaImage::from_raw(bitmap.width() as u32, bitmap.height() as u32, bitmap.as_rgba_bytes())
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "Invalid PDF page bitmap".to_string())
+}
+
+#[cfg(feature = "pdf")]
+pub fn page_count(path: &str) -> Result<usize, String> {
+    let pdfium = pdfium()?;
+    let document = pdfium.load_pdf_from_file(split_page_path(path).0, None).map_err(|e| e.to_string())?;
+    Ok(document.pages().len() as usize)
+}
+
+#[cfg(feature = "pdf")]
+pub fn render(path: &str) -> Result<DynamicImage, String> {
+    with_page(path, |page| render_page(page, PDF_RENDER_DPI / 72.0))
+}
+
+/// 長辺が `size` になるように描画する。
+#[cfg(feature = "pdf")]
+pub fn render_to_fit(path: &str, size: u32) -> Result<DynamicImage, String> {
+    with_page(path, |page| {
+        let long_side = page.width().value.max(page.height().value);
+        render_page(page, size as f32 / long_side)
+    })
+}
+
+/// `PDF_RENDER_DPI` で描画したときの大きさ。`render` の結果と同じになる。
+#[cfg(feature = "pdf")]
+pub fn dimensions(path: &str) -> Result<(u32, u32), String> {
+    with_page(path, |page| Ok(page_size(page, PDF_RENDER_DPI / 72.0)))
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn page_count(_path: &str) -> Result<usize, String> {
+    Err(PDF_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn render(_path: &str) -> Result<DynamicImage, String> {
+    Err(PDF_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn render_to_fit(_path: &str, _size: u32) -> Result<DynamicImage, String> {
+    Err(PDF_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "pdf"))]
+pub fn dimensions(_path: &str) -> Result<(u32, u32), String> {
+    Err(PDF_UNSUPPORTED.to_string())
+}
+
+/// 全ページのパスを返す。画像リストとしてページをめくるのに使う。
+pub fn page_paths(path: &str) -> Result<Vec<String>, String> {
+    let path = split_page_path(path).0;
+    Ok((0..page_count(path)?).map(|index| page_path(path, index)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_path() {
+        assert_eq!(page_path("/docs/manual.pdf", 0), "/docs/manual.pdf#page=1");
+        assert_eq!(split_page_path("/docs/manual.pdf#page=3"), ("/docs/manual.pdf", 2));
+        assert_eq!(split_page_path("/docs/manual.pdf"), ("/docs/manual.pdf", 0));
+        // 不正なページ指定はファイル名の一部として扱う
+        assert_eq!(split_page_path("/docs/a.pdf#page=0"), ("/docs/a.pdf#page=0", 0));
+        assert_eq!(source_path("/docs/manual.pdf#page=12"), Path::new("/docs/manual.pdf"));
+    }
+
+    #[cfg(not(feature = "pdf"))]
+    #[test]
+    fn test_pdf_unsupported() {
+        assert_eq!(page_paths("/docs/manual.pdf").unwrap_err(), PDF_UNSUPPORTED);
+    }
+
+    #[cfg(feature = "pdf")]
+    #[test]
+    fn test_render_pdf() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources/test_document.pdf");
+        let path = path.to_str().unwrap();
+        let pages = page_paths(path).unwrap();
+        assert_eq!(pages.len(), 2);
+
+        let thumbnail = render_to_fit(&pages[1], 100).unwrap();
+        assert_eq!(thumbnail.width().max(thumbnail.height()), 100);
+        let (width, height) = dimensions(&pages[0]).unwrap();
+        let page = render(&pages[0]).unwrap();
+        assert_eq!((page.width(), page.height()), (width, height));
+
+        // 拡大しても画素数の上限に収める
+        let huge = with_page(&pages[0], |page| render_page(page, 1000.0)).unwrap();
+        assert!(huge.width() as u64 * huge.height() as u64 <= crate::formats::MAX_RASTER_PIXELS);
+    }
+}
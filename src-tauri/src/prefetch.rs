@@ -0,0 +1,279 @@
+use crate::formats::{format_from_path, FormatDecoder};
+use crate::{archive, svg};
+use crate::image_cache::DecodedImageCache;
+use crate::models::AppState;
+use image::{DynamicImage, ImageOutputFormat};
+use lru::LruCache;
+use percent_encoding::percent_decode_str;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tauri::http::{Response, ResponseBuilder};
+use tauri::State;
+use log::{debug, error};
+
+/// 表示中の画像の前後それぞれ何枚を先読みするか
+pub const PREFETCH_RADIUS: usize = 2;
+
+/// 先読み済みの画像を返すURIスキーム
+pub const DECODED_SCHEME: &str = "decoded";
+
+/// ズームに合わせて描き直したSVGを何枚まで残しておくか
+const RENDERED_SVG_CAPACITY: usize = 4;
+
+/// SVGのパスと倍率 (`f32::to_bits`)
+type RenderedSvgKey = (String, u32);
+
+#[derive(Default)]
+struct PrefetchQueue {
+    paths: VecDeque<String>,
+    worker_started: bool,
+}
+
+/// 表示中の画像の前後をバックグラウンドでデコードし、`DecodedImageCache` に入れる。
+/// 新しい要求が来ると未処理の要求は破棄されるので、高速にめくった場合も古い画像を読み続けない。
+#[derive(Clone)]
+pub struct Prefetcher {
+    cache: Arc<DecodedImageCache>,
+    queue: Arc<(Mutex<PrefetchQueue>, Condvar)>,
+    /// `?zoom=` 付きで要求されるSVGのBMP
+    rendered_svgs: Arc<Mutex<LruCache<RenderedSvgKey, Arc<Vec<u8>>>>>,
+}
+
+impl Prefetcher {
+    pub fn new(cache: Arc<DecodedImageCache>) -> Self {
+        Prefetcher {
+            cache,
+            queue: Arc::new((Mutex::new(PrefetchQueue::default()), Condvar::new())),
+            rendered_svgs: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(RENDERED_SVG_CAPACITY).unwrap()))),
+        }
+    }
+
+    /// `paths` は優先度の高い順に並べる。
+    pub fn request(&self, paths: Vec<String>) {
+        let (lock, condvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        queue.paths = paths.into_iter().filter(|path| !self.cache.contains(path)).collect();
+        if !queue.worker_started {
+            queue.worker_started = true;
+            let cache = self.cache.clone();
+            let worker_queue = self.queue.clone();
+            thread::spawn(move || prefetch_worker(cache, worker_queue));
+        }
+        condvar.notify_one();
+    }
+
+    pub fn cache(&self) -> &Arc<DecodedImageCache> {
+        &self.cache
+    }
+
+    /// `decoded://` で返せるように、WebViewが表示できない形式をデコードしてキャッシュに入れる。
+    /// SVGは `zoom` が指定されていればその倍率で描き直す。
+    pub fn prepare(&self, path: &str, zoom: Option<f32>) -> Result<(), String> {
+        let format = format_from_path(Path::new(path));
+        match (format.map(|format| format.decoder), zoom) {
+            (Some(FormatDecoder::Svg), Some(zoom)) => {
+                let key = (path.to_string(), zoom.to_bits());
+                if !self.rendered_svgs.lock().unwrap().contains(&key) {
+                    let bmp = encode_bmp(&svg::render(path, zoom)?)?;
+                    self.rendered_svgs.lock().unwrap().put(key, Arc::new(bmp));
+                }
+            }
+            _ if format.is_some_and(|format| !format.browser_native) => {
+                self.cache.get_or_decode_display(path)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn rendered_svg(&self, path: &str, zoom: f32) -> Option<Arc<Vec<u8>>> {
+        self.rendered_svgs.lock().unwrap().get(&(path.to_string(), zoom.to_bits())).cloned()
+    }
+}
+
+/// `decoded://` の画像を表示する前に呼ぶ。プロトコルの応答はUIのスレッドで行われるので、
+/// デコードや描画はここで済ませておき、応答ではキャッシュにあるものだけを返す。
+#[tauri::command]
+pub async fn prepare_decoded_image(path: String, zoom: Option<f32>, state: State<'_, AppState>) -> Result<(), String> {
+    let prefetcher = state.prefetcher.clone();
+    tauri::async_runtime::spawn_blocking(move || prefetcher.prepare(&path, zoom))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn prefetch_worker(cache: Arc<DecodedImageCache>, queue: Arc<(Mutex<PrefetchQueue>, Condvar)>) {
+    let (lock, condvar) = &*queue;
+    loop {
+        let path = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                if let Some(path) = queue.paths.pop_front() {
+                    break path;
+                }
+                queue = condvar.wait(queue).unwrap();
+            }
+        };
+        if cache.contains(&path) {
+            continue;
+        }
+        debug!("Prefetching {}", path);
+        if let Err(e) = cache.get_or_decode(&path) {
+            error!("Failed to prefetch {}: {}", path, e);
+        }
+    }
+}
+
+/// `decoded://localhost/<パス>` (Windowsでは `https://decoded.localhost/<パス>`) に応答する。
+/// 先読み済みならデコード済みのピクセルをBMPとしてそのまま返し、そうでなければ元のファイルを返す。
+/// WebViewが表示できない形式 (TIFFなど) とズーム付きのSVGは、`prepare_decoded_image` で用意したものだけを返す。
+pub fn decoded_image_response(uri: &str, prefetcher: &Prefetcher) -> Result<Response, Box<dyn Error>> {
+    let path = path_from_uri(uri).ok_or("Invalid URI")?;
+    let format = format_from_path(Path::new(&path));
+    if let (Some(FormatDecoder::Svg), Some(zoom)) = (format.map(|format| format.decoder), zoom_from_uri(uri)) {
+        return match prefetcher.rendered_svg(&path, zoom) {
+            Some(bmp) => Ok(ResponseBuilder::new()
+                .mimetype("image/bmp")
+                .body(bmp.to_vec())?),
+            None => not_prepared(&path),
+        };
+    }
+    match prefetcher.cache().get_display(&path) {
+        Some(image) => Ok(ResponseBuilder::new()
+            .mimetype("image/bmp")
+            .body(encode_bmp(&image)?)?),
+        None if format.is_some_and(|format| !format.browser_native) => not_prepared(&path),
+        None => match archive::read_file(Path::new(&path)) {
+            Ok(content) => Ok(ResponseBuilder::new()
+                .mimetype(format.map_or("application/octet-stream", |format| format.mime_type))
+                .body(content)?),
+            Err(_) => not_found(),
+        },
+    }
+}
+
+fn not_prepared(path: &str) -> Result<Response, Box<dyn Error>> {
+    debug!("Decoded image is not prepared: {}", path);
+    not_found()
+}
+
+fn not_found() -> Result<Response, Box<dyn Error>> {
+    Ok(ResponseBuilder::new()
+        .status(404)
+        .mimetype("text/plain")
+        .body(Vec::new())?)
+}
+
+fn path_from_uri(uri: &str) -> Option<String> {
+    let encoded = uri.split_once("localhost/")?.1;
+    let encoded = encoded.split(['?', '#']).next().unwrap_or(encoded);
+    Some(percent_decode_str(encoded).decode_utf8_lossy().into_owned())
+}
+
+fn zoom_from_uri(uri: &str) -> Option<f32> {
+    let query = uri.split_once('?')?.1;
+    let query = query.split('#').next().unwrap_or(query);
+    query.split('&')
+        .find_map(|param| param.strip_prefix("zoom="))
+        .and_then(|zoom| zoom.parse().ok())
+}
+
+fn encode_bmp(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    let mut cursor = std::io::Cursor::new(&mut buffer);
+    match image {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image.write_to(&mut cursor, ImageOutputFormat::Bmp),
+        _ => DynamicImage::ImageRgba8(image.to_rgba8()).write_to(&mut cursor, ImageOutputFormat::Bmp),
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_cache::DEFAULT_CACHE_BYTES;
+    use image::RgbImage;
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_prefetch_neighbours() {
+        let temp_dir = TempDir::new().unwrap();
+        let paths: Vec<String> = ["a.png", "b.png", "c.png"].iter()
+            .map(|name| {
+                let path = temp_dir.path().join(name);
+                RgbImage::new(4, 4).save(&path).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        let prefetcher = Prefetcher::new(Arc::new(DecodedImageCache::new(DEFAULT_CACHE_BYTES)));
+
+        prefetcher.request(paths.clone());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !paths.iter().all(|path| prefetcher.cache().contains(path)) {
+            assert!(Instant::now() < deadline, "Prefetch did not finish in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_decode_unsupported_by_browser() {
+        let temp_dir = TempDir::new().unwrap();
+        let tiff = temp_dir.path().join("scan.tiff");
+        RgbImage::new(5, 3).save(&tiff).unwrap();
+        let png = temp_dir.path().join("photo.png");
+        RgbImage::new(5, 3).save(&png).unwrap();
+        let prefetcher = Prefetcher::new(Arc::new(DecodedImageCache::new(DEFAULT_CACHE_BYTES)));
+
+        let uri = |path: &Path| format!("decoded://localhost/{}", percent_encoding::utf8_percent_encode(path.to_str().unwrap(), percent_encoding::NON_ALPHANUMERIC));
+        // 応答ではデコードしない
+        let response = decoded_image_response(&uri(&tiff), &prefetcher).unwrap();
+        assert_eq!(response.status(), 404);
+        assert!(!prefetcher.cache().contains(tiff.to_str().unwrap()));
+
+        prefetcher.prepare(tiff.to_str().unwrap(), None).unwrap();
+        assert!(prefetcher.cache().contains(tiff.to_str().unwrap()));
+        let response = decoded_image_response(&uri(&tiff), &prefetcher).unwrap();
+        assert!(response.body().starts_with(b"BM"));
+
+        prefetcher.prepare(png.to_str().unwrap(), None).unwrap();
+        assert!(!prefetcher.cache().contains(png.to_str().unwrap()));
+        let response = decoded_image_response(&uri(&png), &prefetcher).unwrap();
+        assert_eq!(response.body(), &fs::read(&png).unwrap());
+
+        let svg = format!("{}?zoom=2", uri(&temp_dir.path().join("icon.svg")));
+        assert_eq!(decoded_image_response(&svg, &prefetcher).unwrap().status(), 404);
+    }
+
+    #[test]
+    fn test_path_from_uri() {
+        assert_eq!(path_from_uri("decoded://localhost/%2Fhome%2Fuser%2Fa%20b.jpg").unwrap(), "/home/user/a b.jpg");
+        assert_eq!(path_from_uri("https://decoded.localhost/C%3A%5Cimages%5Ca.png?t=1").unwrap(), "C:\\images\\a.png");
+        assert!(path_from_uri("decoded://").is_none());
+        assert_eq!(path_from_uri("decoded://localhost/%2Fa.svg?zoom=2").unwrap(), "/a.svg");
+    }
+
+    #[test]
+    fn test_zoom_from_uri() {
+        assert_eq!(zoom_from_uri("decoded://localhost/%2Fa.svg?zoom=2.5"), Some(2.5));
+        assert_eq!(zoom_from_uri("https://decoded.localhost/a.svg?t=1&zoom=3#x"), Some(3.0));
+        assert_eq!(zoom_from_uri("decoded://localhost/%2Fa.svg"), None);
+    }
+
+    #[test]
+    fn test_encode_bmp() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(3, 2));
+        let bmp = encode_bmp(&image).unwrap();
+        assert!(bmp.starts_with(b"BM"));
+        let decoded = image::load_from_memory(&bmp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (3, 2));
+
+        let image = DynamicImage::ImageLuma16(image::ImageBuffer::new(2, 2));
+        assert!(encode_bmp(&image).unwrap().starts_with(b"BM"));
+    }
+}
@@ -0,0 +1,486 @@
+use crate::decoder::decode_jpeg_scaled;
+use crate::formats::{decoder_for, FormatDecoder};
+use image::{DynamicImage, ImageFormat};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// 壊れたファイルで無限ループしないよう、たどるIFDの数に上限を設ける
+const MAX_IFDS: usize = 64;
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 0x00FE;
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_PHOTOMETRIC: u16 = 0x0106;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+/// センサーの生データを表すPhotometricInterpretation (CFA, LinearRaw)
+const RAW_PHOTOMETRICS: [u32; 2] = [32803, 34892];
+
+/// RAFのヘッダーの、埋め込みJPEGの位置と長さ
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+const RAF_JPEG_OFFSET: usize = 84;
+
+/// RAWファイルに埋め込まれたJPEGプレビュー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preview {
+    pub offset: usize,
+    pub length: usize,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Preview {
+    fn long_side(&self) -> u32 {
+        self.width.max(self.height)
+    }
+}
+
+pub fn is_raw(path: &Path) -> bool {
+    decoder_for(path) == Some(FormatDecoder::Raw)
+}
+
+/// RAWファイルの必要な範囲だけを読む。IFDとプレビューを探すのにファイル全体は読まない。
+struct Source<R> {
+    reader: R,
+    len: u64,
+}
+
+impl<R: Read + Seek> Source<R> {
+    fn new(mut reader: R) -> Option<Self> {
+        let len = reader.seek(SeekFrom::End(0)).ok()?;
+        Some(Source { reader, len })
+    }
+
+    /// ファイルの範囲外を指していれば `None`
+    fn read_at(&mut self, offset: usize, length: usize) -> Option<Vec<u8>> {
+        if offset.checked_add(length)? as u64 > self.len {
+            return None;
+        }
+        self.reader.seek(SeekFrom::Start(offset as u64)).ok()?;
+        let mut buffer = vec![0; length];
+        self.reader.read_exact(&mut buffer).ok()?;
+        Some(buffer)
+    }
+}
+
+struct Tiff<'a, R> {
+    source: &'a mut Source<R>,
+    big_endian: bool,
+}
+
+impl<'a, R: Read + Seek> Tiff<'a, R> {
+    fn new(source: &'a mut Source<R>) -> Option<Self> {
+        let big_endian = match source.read_at(0, 2)?.as_slice() {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+        Some(Tiff { source, big_endian })
+    }
+
+    fn u16_in(&self, data: &[u8], offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_in(&self, data: &[u8], offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn u16_at(&mut self, offset: usize) -> Option<u16> {
+        let data = self.source.read_at(offset, 2)?;
+        self.u16_in(&data, 0)
+    }
+
+    fn u32_at(&mut self, offset: usize) -> Option<u32> {
+        let data = self.source.read_at(offset, 4)?;
+        self.u32_in(&data, 0)
+    }
+
+    /// SHORTとLONG (IFDを含む) の値だけを読む。4バイトに収まる値はエントリに直接入っている。
+    fn values(&mut self, entry: &[u8]) -> Vec<u32> {
+        let (Some(value_type), Some(count)) = (self.u16_in(entry, 2), self.u32_in(entry, 4)) else {
+            return Vec::new();
+        };
+        let size = match value_type {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Vec::new(),
+        };
+        let Some(length) = (count as usize).checked_mul(size) else { return Vec::new() };
+        let data = if length <= 4 {
+            entry[8..8 + length].to_vec()
+        } else {
+            match self.u32_in(entry, 8).and_then(|offset| self.source.read_at(offset as usize, length)) {
+                Some(data) => data,
+                None => return Vec::new(),
+            }
+        };
+        data.chunks_exact(size)
+            .filter_map(|value| match size {
+                2 => self.u16_in(value, 0).map(u32::from),
+                _ => self.u32_in(value, 0),
+            })
+            .collect()
+    }
+
+    /// IFD0から次のIFDとSubIFDをたどり、JPEGのデータを指しているものを集める。
+    fn jpeg_streams(&mut self) -> Vec<(usize, usize)> {
+        let mut streams = Vec::new();
+        let mut pending: Vec<usize> = self.u32_at(4).map(|offset| offset as usize).into_iter().collect();
+        let mut visited = Vec::new();
+        while let Some(ifd) = pending.pop() {
+            if ifd == 0 || visited.contains(&ifd) || visited.len() >= MAX_IFDS {
+                continue;
+            }
+            visited.push(ifd);
+            let Some(count) = self.u16_at(ifd) else { continue };
+            let Some(entries) = self.source.read_at(ifd + 2, count as usize * 12) else { continue };
+
+            let mut tags: HashMap<u16, Vec<u32>> = HashMap::new();
+            for entry in entries.chunks_exact(12) {
+                if let Some(tag) = self.u16_in(entry, 0) {
+                    tags.insert(tag, self.values(entry));
+                }
+            }
+            let first = |tag: u16| tags.get(&tag).and_then(|values| values.first().copied());
+
+            if let Some(sub_ifds) = tags.get(&TAG_SUB_IFDS) {
+                pending.extend(sub_ifds.iter().map(|offset| *offset as usize));
+            }
+            if let Some(next) = self.u32_at(ifd + 2 + count as usize * 12) {
+                pending.push(next as usize);
+            }
+
+            let is_raw_data = first(TAG_PHOTOMETRIC).is_some_and(|value| RAW_PHOTOMETRICS.contains(&value))
+                || (first(TAG_NEW_SUBFILE_TYPE) == Some(0) && first(TAG_COMPRESSION) == Some(7));
+            if is_raw_data {
+                continue;
+            }
+            if let (Some(offset), Some(length)) = (first(TAG_JPEG_OFFSET), first(TAG_JPEG_LENGTH)) {
+                streams.push((offset as usize, length as usize));
+            }
+            // 1ストリップのJPEG圧縮の画像 (CR2のIFD0など)
+            if matches!(first(TAG_COMPRESSION), Some(6) | Some(7)) {
+                if let (Some([offset]), Some([length])) = (
+                    tags.get(&TAG_STRIP_OFFSETS).map(Vec::as_slice),
+                    tags.get(&TAG_STRIP_BYTE_COUNTS).map(Vec::as_slice),
+                ) {
+                    streams.push((*offset as usize, *length as usize));
+                }
+            }
+        }
+        streams
+    }
+}
+
+/// `start` から `length` バイトのJPEGのマーカーを読み、ベースラインかプログレッシブの画像なら幅と高さを返す。
+/// RAWの生データに使われるロスレスJPEGなどは `None` になる。
+fn jpeg_dimensions<R: Read + Seek>(source: &mut Source<R>, start: usize, length: usize) -> Option<(u32, u32)> {
+    let end = start.checked_add(length)?;
+    let mut read = |offset: usize, length: usize| {
+        if offset + length > end {
+            return None;
+        }
+        source.read_at(offset, length)
+    };
+    if read(start, 2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut offset = start + 2;
+    loop {
+        let header = read(offset, 2)?;
+        if header[0] != 0xFF {
+            return None;
+        }
+        let marker = header[1];
+        if marker == 0xFF {
+            offset += 1;
+            continue;
+        }
+        if matches!(marker, 0x01 | 0xD0..=0xD7) {
+            offset += 2;
+            continue;
+        }
+        let segment = read(offset + 2, 2)?;
+        let length = u16::from_be_bytes([segment[0], segment[1]]) as usize;
+        match marker {
+            0xC0..=0xC2 => {
+                let frame = read(offset + 5, 4)?;
+                let height = u16::from_be_bytes([frame[0], frame[1]]) as u32;
+                let width = u16::from_be_bytes([frame[2], frame[3]]) as u32;
+                return (width > 0 && height > 0).then_some((width, height));
+            }
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xDA => return None,
+            _ => offset += 2 + length,
+        }
+    }
+}
+
+/// 埋め込みプレビューを小さい順に返す。
+pub fn previews<R: Read + Seek>(reader: R) -> Vec<Preview> {
+    let Some(mut source) = Source::new(reader) else { return Vec::new() };
+    let streams = match source.read_at(0, RAF_JPEG_OFFSET + 8) {
+        Some(header) if header.starts_with(RAF_MAGIC) => {
+            let offset = u32::from_be_bytes(header[RAF_JPEG_OFFSET..RAF_JPEG_OFFSET + 4].try_into().unwrap());
+            let length = u32::from_be_bytes(header[RAF_JPEG_OFFSET + 4..RAF_JPEG_OFFSET + 8].try_into().unwrap());
+            vec![(offset as usize, length as usize)]
+        }
+        _ => Tiff::new(&mut source).map(|mut tiff| tiff.jpeg_streams()).unwrap_or_default(),
+    };
+
+    let mut previews: Vec<Preview> = streams.into_iter()
+        .filter_map(|(offset, length)| {
+            let (width, height) = jpeg_dimensions(&mut source, offset, length)?;
+            Some(Preview { offset, length, width, height })
+        })
+        .collect();
+    previews.sort_by_key(|preview| (preview.width as u64 * preview.height as u64, preview.offset));
+    previews.dedup_by_key(|preview| preview.offset);
+    previews
+}
+
+/// プレビューの範囲だけを読む
+fn preview_data<R: Read + Seek>(reader: &mut R, preview: &Preview) -> Result<Vec<u8>, String> {
+    reader.seek(SeekFrom::Start(preview.offset as u64)).map_err(|e| e.to_string())?;
+    let mut data = vec![0; preview.length];
+    reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+fn largest_preview<R: Read + Seek>(reader: R) -> Result<Preview, String> {
+    previews(reader).pop().ok_or_else(|| "No embedded preview in RAW file".to_string())
+}
+
+/// 長辺が `size` 以上の最も小さいプレビューを縮小デコードする。足りなければ最も大きいものを使う。
+pub fn decode_thumbnail(path: &str, size: u32) -> Result<DynamicImage, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let previews = previews(&mut file);
+    let preview = previews.iter()
+        .find(|preview| preview.long_side() >= size)
+        .or(previews.last())
+        .ok_or("No embedded preview in RAW file")?;
+    decode_jpeg_scaled(Cursor::new(preview_data(&mut file, preview)?), size)
+}
+
+/// `raw-demosaic` フィーチャーが無い場合は、最も大きい埋め込みプレビューを返す。
+#[cfg(not(feature = "raw-demosaic"))]
+pub fn decode(path: &str) -> Result<DynamicImage, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let preview = largest_preview(&mut file)?;
+    image::load_from_memory_with_format(&preview_data(&mut file, &preview)?, ImageFormat::Jpeg).map_err(|e| e.to_string())
+}
+
+/// センサーのデータから現像する。`simple_decode_8bit` の大きさ0は縮小しないという指定。
+#[cfg(feature = "raw-demosaic")]
+pub fn decode(path: &str) -> Result<DynamicImage, String> {
+    let developed = imagepipe::simple_decode_8bit(path, 0, 0).map_err(|e| e.to_string())?;
+    image::RgbImage::from_raw(developed.width as u32, developed.height as u32, developed.data)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "Invalid developed RAW image".to_string())
+}
+
+/// 最も大きい埋め込みプレビューの大きさ。
+pub fn dimensions(path: &str) -> Result<(u32, u32), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let preview = largest_preview(file)?;
+    Ok((preview.width, preview.height))
+}
+
+/// 同じフォルダに同じ名前のJPEGがあるRAWファイルを、RAWのパスからJPEGのパスへの対応で返す。
+pub fn find_raw_pairs<'a, I>(paths: I) -> HashMap<PathBuf, PathBuf>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let mut jpegs: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut raws = Vec::new();
+    for path in paths {
+        let stem = path.with_extension("");
+        match decoder_for(path) {
+            Some(FormatDecoder::Image(ImageFormat::Jpeg)) => {
+                jpegs.insert(stem, path.to_path_buf());
+            }
+            Some(FormatDecoder::Raw) => raws.push((stem, path.to_path_buf())),
+            _ => {}
+        }
+    }
+    raws.into_iter()
+        .filter_map(|(stem, raw)| jpegs.get(&stem).map(|jpeg| (raw, jpeg.clone())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{Rgb, RgbImage};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn jpeg(width: u32, height: u32, color: Rgb<u8>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        JpegEncoder::new_with_quality(&mut buffer, 90)
+            .encode_image(&RgbImage::from_pixel(width, height, color))
+            .unwrap();
+        buffer
+    }
+
+    /// 12バイトのIFDエントリ (LONG 1個)
+    fn entry(tag: u16, value: u32) -> Vec<u8> {
+        let mut entry = tag.to_le_bytes().to_vec();
+        entry.extend_from_slice(&4u16.to_le_bytes());
+        entry.extend_from_slice(&1u32.to_le_bytes());
+        entry.extend_from_slice(&value.to_le_bytes());
+        entry
+    }
+
+    fn ifd(entries: &[Vec<u8>], next: u32) -> Vec<u8> {
+        let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+        for entry in entries {
+            ifd.extend_from_slice(entry);
+        }
+        ifd.extend_from_slice(&next.to_le_bytes());
+        ifd
+    }
+
+    /// IFD0にJPEGInterchangeFormatの小さいプレビュー、SubIFDにストリップの大きいプレビューと、
+    /// プレビューとして扱ってはいけないCFAの生データを持つTIFF形式のRAW
+    fn tiff_raw() -> Vec<u8> {
+        let small = jpeg(160, 120, Rgb([200, 0, 0]));
+        let large = jpeg(640, 480, Rgb([0, 0, 200]));
+        let sensor = jpeg(320, 240, Rgb([0, 200, 0]));
+
+        let ifd0_offset = 8u32;
+        let ifd0_size = 2 + 3 * 12 + 4;
+        let sub_ifd_offset = ifd0_offset + ifd0_size;
+        let sub_ifd_size = 2 + 3 * 12 + 4;
+        let cfa_ifd_offset = sub_ifd_offset + sub_ifd_size;
+        let cfa_ifd_size = 2 + 4 * 12 + 4;
+        let small_offset = cfa_ifd_offset + cfa_ifd_size;
+        let large_offset = small_offset + small.len() as u32;
+        let sensor_offset = large_offset + large.len() as u32;
+
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&ifd0_offset.to_le_bytes());
+        data.extend(ifd(&[
+            entry(TAG_SUB_IFDS, sub_ifd_offset),
+            entry(TAG_JPEG_OFFSET, small_offset),
+            entry(TAG_JPEG_LENGTH, small.len() as u32),
+        ], 0));
+        data.extend(ifd(&[
+            entry(TAG_COMPRESSION, 6),
+            entry(TAG_STRIP_OFFSETS, large_offset),
+            entry(TAG_STRIP_BYTE_COUNTS, large.len() as u32),
+        ], cfa_ifd_offset));
+        data.extend(ifd(&[
+            entry(TAG_COMPRESSION, 7),
+            entry(TAG_PHOTOMETRIC, 32803),
+            entry(TAG_STRIP_OFFSETS, sensor_offset),
+            entry(TAG_STRIP_BYTE_COUNTS, sensor.len() as u32),
+        ], 0));
+        data.extend(small);
+        data.extend(large);
+        data.extend(sensor);
+        data
+    }
+
+    fn raf_raw() -> Vec<u8> {
+        let preview = jpeg(300, 200, Rgb([0, 200, 0]));
+        let mut data = RAF_MAGIC.to_vec();
+        data.resize(100, 0);
+        data[RAF_JPEG_OFFSET..RAF_JPEG_OFFSET + 4].copy_from_slice(&100u32.to_be_bytes());
+        data[RAF_JPEG_OFFSET + 4..RAF_JPEG_OFFSET + 8].copy_from_slice(&(preview.len() as u32).to_be_bytes());
+        data.extend(preview);
+        data
+    }
+
+    #[test]
+    fn test_tiff_previews() {
+        let sizes: Vec<(u32, u32)> = previews(Cursor::new(tiff_raw())).iter().map(|preview| (preview.width, preview.height)).collect();
+        assert_eq!(sizes, vec![(160, 120), (640, 480)]);
+    }
+
+    #[test]
+    fn test_raf_previews() {
+        let previews = previews(Cursor::new(raf_raw()));
+        assert_eq!(previews.len(), 1);
+        assert_eq!((previews[0].width, previews[0].height), (300, 200));
+    }
+
+    /// 読んだバイト数を数える
+    struct CountingReader<R> {
+        inner: R,
+        read: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.inner.read(buffer)?;
+            self.read += read;
+            Ok(read)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(position)
+        }
+    }
+
+    #[test]
+    fn test_previews_read_only_headers() {
+        // センサーのデータの代わりに大きな領域を足しておく
+        let mut data = tiff_raw();
+        data.resize(data.len() + 16 * 1024 * 1024, 0);
+        let mut reader = CountingReader { inner: Cursor::new(data), read: 0 };
+        assert_eq!(previews(&mut reader).len(), 2);
+        assert!(reader.read < 4096, "Read {} bytes", reader.read);
+    }
+
+    #[test]
+    fn test_decode_raw() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("IMG_0001.CR2");
+        fs::write(&path, tiff_raw()).unwrap();
+        let path = path.to_str().unwrap();
+        assert!(is_raw(Path::new(path)));
+
+        // サムネイルには十分な大きさの最も小さいプレビューを使う
+        let thumbnail = decode_thumbnail(path, 100).unwrap();
+        assert!(thumbnail.to_rgb8().get_pixel(10, 10)[0] > 150);
+        let thumbnail = decode_thumbnail(path, 200).unwrap();
+        assert!(thumbnail.to_rgb8().get_pixel(10, 10)[2] > 150);
+
+        assert_eq!(dimensions(path).unwrap(), (640, 480));
+        if !cfg!(feature = "raw-demosaic") {
+            let image = decode(path).unwrap();
+            assert_eq!((image.width(), image.height()), (640, 480));
+        }
+    }
+
+    #[test]
+    fn test_no_preview() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("broken.nef");
+        fs::write(&path, b"MM\0*\0\0\0\x08\0\0\0\0\0\0").unwrap();
+        assert!(decode_thumbnail(path.to_str().unwrap(), 100).is_err());
+        assert!(previews(Cursor::new(b"not a raw file")).is_empty());
+    }
+
+    #[test]
+    fn test_find_raw_pairs() {
+        let paths: Vec<PathBuf> = ["/photos/DSC_0001.NEF", "/photos/DSC_0001.JPG", "/photos/DSC_0002.nef", "/photos/DSC_0003.jpg", "/other/DSC_0002.jpg"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let pairs = find_raw_pairs(paths.iter().map(PathBuf::as_path));
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[Path::new("/photos/DSC_0001.NEF")], Path::new("/photos/DSC_0001.JPG"));
+    }
+}
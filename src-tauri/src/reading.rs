@@ -0,0 +1,145 @@
+use crate::archive::split_path;
+use crate::file_system::list_images;
+use crate::formats::{archive_dimensions, image_dimensions};
+use crate::models::{ReadingDirection, SortBy, SortOrder};
+use serde::Serialize;
+use std::path::Path;
+
+/// 幅が高さのこの倍数を超えるページは、見開き1枚分のスキャンとみなして単独で表示する。
+/// 正方形に近いページを見開きと誤らないよう、少し余裕を持たせる。
+pub const WIDE_PAGE_ASPECT: f64 = 1.2;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Spread {
+    /// 画面の左から順に並べたページ
+    pub pages: Vec<String>,
+    /// 読む順で最初のページの、画像リストでの位置
+    pub first_index: usize,
+}
+
+fn is_wide_page(dimensions: Option<(u32, u32)>) -> bool {
+    dimensions.is_some_and(|(width, height)| width as f64 > height as f64 * WIDE_PAGE_ASPECT)
+}
+
+/// 読む順に並んだページを見開きにまとめる。横長のページと、`cover_alone` なら最初のページ (表紙) は単独にする。
+/// 大きさの分からないページは縦長として扱う。
+pub fn group_spreads(pages: &[String], dimensions: &[Option<(u32, u32)>], direction: ReadingDirection, cover_alone: bool) -> Vec<Spread> {
+    let is_wide = |index: usize| is_wide_page(dimensions.get(index).copied().flatten());
+    let mut spreads = Vec::new();
+    let mut index = 0;
+    while index < pages.len() {
+        let alone = is_wide(index) || (cover_alone && index == 0);
+        let count = if !alone && index + 1 < pages.len() && !is_wide(index + 1) { 2 } else { 1 };
+        let mut spread: Vec<String> = pages[index..index + count].to_vec();
+        if direction == ReadingDirection::RightToLeft {
+            spread.reverse();
+        }
+        spreads.push(Spread { pages: spread, first_index: index });
+        index += count;
+    }
+    spreads
+}
+
+/// フォルダや書庫の画像を見開きにまとめる。ページの大きさを調べるため、書庫ではすべてのページを1回の読み込みで取り出す。
+pub fn get_spreads_impl(path: &str, sort_by: &SortBy, sort_order: &SortOrder, direction: ReadingDirection, cover_alone: bool) -> Result<Vec<Spread>, String> {
+    let pages = list_images(Path::new(path), sort_by, sort_order)?;
+    let dimensions: Vec<Option<(u32, u32)>> = match split_path(Path::new(path)) {
+        Some((archive, _)) => {
+            let entries: Vec<String> = pages.iter()
+                .map(|page| split_path(Path::new(page)).map(|(_, entry)| entry).unwrap_or_default())
+                .collect();
+            let sizes = archive_dimensions(&archive, &entries)?;
+            entries.iter().map(|entry| sizes.get(entry).copied()).collect()
+        }
+        None => pages.iter().map(|page| image_dimensions(page).ok()).collect(),
+    };
+    Ok(group_spreads(&pages, &dimensions, direction, cover_alone))
+}
+
+#[tauri::command]
+pub async fn get_spreads(path: String, sort_by: SortBy, sort_order: SortOrder, direction: ReadingDirection, cover_alone: Option<bool>) -> Result<Vec<Spread>, String> {
+    tauri::async_runtime::spawn_blocking(move || get_spreads_impl(&path, &sort_by, &sort_order, direction, cover_alone.unwrap_or(true)))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn pages(count: usize) -> Vec<String> {
+        (1..=count).map(|page| format!("{:03}.jpg", page)).collect()
+    }
+
+    fn spread_pages(spreads: &[Spread]) -> Vec<Vec<&str>> {
+        spreads.iter().map(|spread| spread.pages.iter().map(String::as_str).collect()).collect()
+    }
+
+    #[test]
+    fn test_group_spreads() {
+        let portrait = Some((700, 1000));
+        let wide = Some((1400, 1000));
+        let dimensions = [portrait, portrait, portrait, wide, portrait, None];
+
+        let spreads = group_spreads(&pages(6), &dimensions, ReadingDirection::LeftToRight, true);
+        assert_eq!(spread_pages(&spreads), vec![
+            vec!["001.jpg"],
+            vec!["002.jpg", "003.jpg"],
+            vec!["004.jpg"],
+            vec!["005.jpg", "006.jpg"],
+        ]);
+        assert_eq!(spreads.iter().map(|spread| spread.first_index).collect::<Vec<_>>(), vec![0, 1, 3, 4]);
+
+        // 右綴じでは先のページが右に来る
+        let spreads = group_spreads(&pages(6), &dimensions, ReadingDirection::RightToLeft, false);
+        assert_eq!(spread_pages(&spreads), vec![
+            vec!["002.jpg", "001.jpg"],
+            vec!["003.jpg"],
+            vec!["004.jpg"],
+            vec!["006.jpg", "005.jpg"],
+        ]);
+
+        let spreads = group_spreads(&pages(3), &[portrait; 3], ReadingDirection::LeftToRight, false);
+        assert_eq!(spread_pages(&spreads), vec![vec!["001.jpg", "002.jpg"], vec!["003.jpg"]]);
+        assert!(group_spreads(&[], &[], ReadingDirection::LeftToRight, true).is_empty());
+    }
+
+    #[test]
+    fn test_get_spreads() {
+        let temp_dir = TempDir::new().unwrap();
+        for (name, width) in [("1.png", 7), ("2.png", 7), ("3.png", 7), ("4.png", 14)] {
+            RgbImage::new(width, 10).save(temp_dir.path().join(name)).unwrap();
+        }
+        let spreads = get_spreads_impl(temp_dir.path().to_str().unwrap(), &SortBy::Name, &SortOrder::Asc, ReadingDirection::RightToLeft, true).unwrap();
+        let names: Vec<Vec<String>> = spreads.iter()
+            .map(|spread| spread.pages.iter().map(|page| Path::new(page).file_name().unwrap().to_string_lossy().into_owned()).collect())
+            .collect();
+        assert_eq!(names, vec![vec!["1.png"], vec!["3.png", "2.png"], vec!["4.png"]]);
+    }
+
+    #[test]
+    fn test_get_spreads_in_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = temp_dir.path().join("book.cbz");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        // 少し横長 (1.1倍) のページは見開きにせず、横長 (1.4倍) のページだけを単独にする
+        for (name, width) in [("1.png", 7), ("2.png", 11), ("3.png", 7), ("4.png", 14), ("notes.txt", 0)] {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            if width > 0 {
+                let mut data = std::io::Cursor::new(Vec::new());
+                RgbImage::new(width, 10).write_to(&mut data, image::ImageOutputFormat::Png).unwrap();
+                zip.write_all(data.get_ref()).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+
+        let spreads = get_spreads_impl(archive.to_str().unwrap(), &SortBy::Name, &SortOrder::Asc, ReadingDirection::LeftToRight, false).unwrap();
+        let names: Vec<Vec<String>> = spreads.iter()
+            .map(|spread| spread.pages.iter().map(|page| Path::new(page).file_name().unwrap().to_string_lossy().into_owned()).collect())
+            .collect();
+        assert_eq!(names, vec![vec!["1.png", "2.png"], vec!["3.png"], vec!["4.png"]]);
+    }
+}
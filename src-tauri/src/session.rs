@@ -0,0 +1,96 @@
+use crate::models::{validate_zoom, AppState, ImageState};
+use tauri::State;
+
+#[tauri::command]
+pub fn update_session(session: ImageState, state: State<'_, AppState>) -> Result<(), String> {
+    update_session_impl(session, &state)
+}
+
+fn update_session_impl(mut session: ImageState, state: &AppState) -> Result<(), String> {
+    session.validate()?;
+    if session.images.is_empty() {
+        session.current_index = 0;
+    }
+    *state.session.lock().unwrap() = session;
+    Ok(())
+}
+
+/// 画像リストは送り直さずに、表示位置と倍率だけを更新する。
+#[tauri::command]
+pub fn set_session_position(current_index: usize, zoom: f64, state: State<'_, AppState>) -> Result<(), String> {
+    set_session_position_impl(current_index, zoom, &state)
+}
+
+fn set_session_position_impl(current_index: usize, zoom: f64, state: &AppState) -> Result<(), String> {
+    let mut session = state.session.lock().unwrap();
+    if current_index >= session.images.len() {
+        return Err(format!("Index {} out of range", current_index));
+    }
+    validate_zoom(zoom)?;
+    session.current_index = current_index;
+    session.zoom = zoom;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_session(state: State<'_, AppState>) -> ImageState {
+    state.session.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{SortBy, SortOrder};
+
+    fn test_session() -> ImageState {
+        ImageState {
+            current_index: 0,
+            images: vec!["/a.jpg".to_string(), "/b.jpg".to_string()],
+            zoom: 1.0,
+            sort_by: SortBy::Name,
+            sort_order: SortOrder::Asc,
+        }
+    }
+
+    #[test]
+    fn test_update_session() {
+        let state = AppState::new();
+        update_session_impl(test_session(), &state).unwrap();
+        set_session_position_impl(1, 1.5, &state).unwrap();
+
+        let session = state.session.lock().unwrap();
+        assert_eq!(session.current_image(), Some(&"/b.jpg".to_string()));
+        assert_eq!(session.zoom, 1.5);
+    }
+
+    #[test]
+    fn test_session_index_out_of_range() {
+        let state = AppState::new();
+        assert!(set_session_position_impl(0, 1.0, &state).is_err());
+
+        let mut session = test_session();
+        session.current_index = 2;
+        assert!(update_session_impl(session, &state).is_err());
+
+        update_session_impl(test_session(), &state).unwrap();
+        assert!(set_session_position_impl(5, 1.0, &state).is_err());
+        assert_eq!(state.session.lock().unwrap().current_index, 0);
+    }
+
+    #[test]
+    fn test_session_invalid_zoom() {
+        let state = AppState::new();
+        for zoom in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let mut session = test_session();
+            session.zoom = zoom;
+            assert!(update_session_impl(session, &state).is_err());
+        }
+
+        update_session_impl(test_session(), &state).unwrap();
+        assert!(set_session_position_impl(1, 0.0, &state).is_err());
+        assert!(set_session_position_impl(1, f64::NAN, &state).is_err());
+        let session = state.session.lock().unwrap();
+        assert_eq!(session.current_index, 0);
+        assert_eq!(session.zoom, 1.0);
+    }
+}
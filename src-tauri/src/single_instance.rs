@@ -0,0 +1,238 @@
+use crate::config::{startup_info_from_args, StartupEnv};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use log::{info, debug, error};
+
+pub const OPEN_FILE_EVENT: &str = "open-file";
+
+const LOCK_FILE_NAME: &str = "image_viewer.lock";
+const ACK: &str = "ok";
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// 待ち受け側はウィンドウの準備ができてから応答するので、接続より長めに待つ
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+/// 送信側が何も送ってこない接続で待ち受けが止まらないようにする
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+const FORWARD_RETRIES: u32 = 10;
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn get_lock_path(startup_env: &StartupEnv) -> Result<PathBuf, String> {
+    Ok(startup_env.config_dir()?.join(LOCK_FILE_NAME))
+}
+
+/// 既に起動しているインスタンスがあれば引数を転送して `None` を返す。
+/// 無ければ待ち受け用のリスナーを作成し、ポート番号をロックファイルに書き込む。
+/// 同時に起動しても一方だけが待ち受けられるよう、ロックファイルのパスから決まるポートを先に使う。
+pub fn acquire(lock_path: &Path, args: &[String]) -> Result<Option<TcpListener>, String> {
+    let port = preferred_port(lock_path);
+    for attempt in 0..=FORWARD_RETRIES {
+        if forward_args(lock_path, port, args) {
+            info!("Forwarded arguments to running instance");
+            return Ok(None);
+        }
+        match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(listener) => return register(lock_path, listener).map(Some),
+            // 別のインスタンスが先に待ち受けを始めたので、応答できるようになるのを待って転送し直す
+            Err(e) if e.kind() == ErrorKind::AddrInUse && attempt < FORWARD_RETRIES => {
+                debug!("Port {} is in use, retrying forward", port);
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => break,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    // 決まったポートを関係の無いプロセスが使っている
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).map_err(|e| e.to_string())?;
+    register(lock_path, listener).map(Some)
+}
+
+/// 動的ポートの範囲から選ぶ
+fn preferred_port(lock_path: &Path) -> u16 {
+    let mut hasher = DefaultHasher::new();
+    lock_path.hash(&mut hasher);
+    49152 + (hasher.finish() % 16384) as u16
+}
+
+fn register(lock_path: &Path, listener: TcpListener) -> Result<TcpListener, String> {
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    if let Some(parent) = lock_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(lock_path, port.to_string()).map_err(|e| e.to_string())?;
+    debug!("Single instance listener on port {}", port);
+    Ok(listener)
+}
+
+/// ロックファイルのポートと決まったポートの順に転送を試す
+fn forward_args(lock_path: &Path, preferred_port: u16, args: &[String]) -> bool {
+    let locked_port: Option<u16> = fs::read_to_string(lock_path).ok().and_then(|s| s.trim().parse().ok());
+    let mut ports = locked_port.into_iter().collect::<Vec<_>>();
+    if locked_port != Some(preferred_port) {
+        ports.push(preferred_port);
+    }
+    ports.into_iter().any(|port| forward_to(port, args))
+}
+
+fn forward_to(port: u16, args: &[String]) -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let mut stream = match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let _ = stream.set_read_timeout(Some(REPLY_TIMEOUT));
+
+    // 起動元のカレントディレクトリが異なるので絶対パスにして送る
+    let args: Vec<String> = args.iter().enumerate()
+        .map(|(i, arg)| match fs::canonicalize(arg) {
+            Ok(path) if i > 0 => path.to_string_lossy().into_owned(),
+            _ => arg.clone(),
+        })
+        .collect();
+    let message = match serde_json::to_string(&args) {
+        Ok(message) => message,
+        Err(_) => return false,
+    };
+    if writeln!(stream, "{}", message).is_err() {
+        return false;
+    }
+
+    // ポートが別のプロセスに再利用されている場合に備えて応答を確認する
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).is_ok() && reply.trim() == ACK
+}
+
+pub fn listen<F>(listener: TcpListener, on_args: F)
+where
+    F: Fn(Vec<String>) + Send + 'static,
+{
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Single instance connection failed: {:?}", e);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+            let mut line = String::new();
+            if let Err(e) = BufReader::new(&stream).read_line(&mut line) {
+                debug!("Single instance read failed: {:?}", e);
+                continue;
+            }
+            match serde_json::from_str::<Vec<String>>(&line) {
+                Ok(args) => {
+                    let _ = writeln!(stream, "{}", ACK);
+                    on_args(args);
+                }
+                Err(e) => error!("Invalid single instance message: {:?}", e),
+            }
+        }
+    });
+}
+
+/// 転送された引数を `get_startup_info` と同じ規則で解釈し、メインウィンドウに通知する。
+pub fn start(listener: TcpListener, app: AppHandle) {
+    listen(listener, move |args| {
+        match startup_info_from_args(&args) {
+            Ok(Some(startup_info)) => {
+                if let Some(window) = app.get_window("main") {
+                    let _ = window.unminimize();
+                    let _ = window.set_focus();
+                }
+                if let Err(e) = app.emit_all(OPEN_FILE_EVENT, startup_info) {
+                    error!("Failed to emit {}: {:?}", OPEN_FILE_EVENT, e);
+                }
+            }
+            Ok(None) => {
+                if let Some(window) = app.get_window("main") {
+                    let _ = window.set_focus();
+                }
+            }
+            Err(e) => error!("Invalid forwarded arguments {:?}: {}", args, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_forward_to_running_instance() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join(LOCK_FILE_NAME);
+        let image_path = temp_dir.path().join("image.png");
+        fs::write(&image_path, "").unwrap();
+
+        let listener = acquire(&lock_path, &["viewer".to_string()]).unwrap();
+        assert!(listener.is_some(), "First instance should become the primary");
+        assert!(lock_path.exists());
+
+        let (tx, rx) = mpsc::channel();
+        listen(listener.unwrap(), move |args| tx.send(args).unwrap());
+
+        let args = vec!["viewer".to_string(), image_path.to_string_lossy().into_owned()];
+        let second = acquire(&lock_path, &args).unwrap();
+        assert!(second.is_none(), "Second instance should forward its arguments");
+
+        let received = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(PathBuf::from(&received[1]), fs::canonicalize(&image_path).unwrap());
+    }
+
+    #[test]
+    fn test_forward_when_port_taken() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join(LOCK_FILE_NAME);
+
+        // 同時に起動したインスタンスが待ち受けを始めたが、ロックファイルはまだ書いていない
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, preferred_port(&lock_path))).unwrap();
+        let (tx, rx) = mpsc::channel();
+        listen(listener, move |args| tx.send(args).unwrap());
+
+        let second = acquire(&lock_path, &["viewer".to_string(), "second".to_string()]).unwrap();
+        assert!(second.is_none(), "Instance losing the bind race should forward its arguments");
+        assert!(!lock_path.exists());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap()[1], "second");
+    }
+
+    #[test]
+    fn test_silent_connection() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join(LOCK_FILE_NAME);
+        let listener = acquire(&lock_path, &["viewer".to_string()]).unwrap().unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        listen(listener, move |args| tx.send(args).unwrap());
+
+        // 何も送らない接続があっても、次の転送は読み取りのタイムアウト後に受け付ける
+        let _silent = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        assert!(acquire(&lock_path, &["viewer".to_string()]).unwrap().is_none());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec!["viewer".to_string()]);
+    }
+
+    #[test]
+    fn test_stale_lock_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock_path = temp_dir.path().join(LOCK_FILE_NAME);
+
+        // 使われていないポートを書き込んでおく
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        fs::write(&lock_path, port.to_string()).unwrap();
+
+        let listener = acquire(&lock_path, &["viewer".to_string()]).unwrap();
+        assert!(listener.is_some(), "Stale lock file should be replaced");
+        let written: u16 = fs::read_to_string(&lock_path).unwrap().parse().unwrap();
+        assert_eq!(written, listener.unwrap().local_addr().unwrap().port());
+    }
+}
@@ -0,0 +1,170 @@
+use image::DynamicImage;
+
+#[cfg(feature = "svg")]
+use image::RgbaImage;
+#[cfg(feature = "svg")]
+use resvg::{tiny_skia, usvg};
+#[cfg(feature = "svg")]
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "svg")]
+use log::debug;
+#[cfg(feature = "svg")]
+use crate::formats::raster_size;
+
+pub const SVG_UNSUPPORTED: &str = "SVG requires the svg feature";
+
+#[cfg(feature = "svg")]
+fn system_fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    }).clone()
+}
+
+/// 外部の画像やファイルを読まないようにした設定で解析する。
+/// 埋め込まれた `data:` URLの画像だけを許可し、それ以外の参照は無視する。
+#[cfg(feature = "svg")]
+fn parse(path: &str) -> Result<usvg::Tree, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let options = usvg::Options {
+        resources_dir: None,
+        fontdb: system_fonts(),
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|href, _| {
+                debug!("Ignoring external reference in SVG: {}", href);
+                None
+            }),
+        },
+        ..usvg::Options::default()
+    };
+    usvg::Tree::from_data(&data, &options).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "svg")]
+fn rasterize(tree: &usvg::Tree, scale: f32) -> Result<DynamicImage, String> {
+    let (width, height, scale) = raster_size(tree.size().width(), tree.size().height(), scale);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("Invalid SVG size")?;
+    resvg::render(tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skiaの画素は乗算済みアルファなので戻す
+    let pixels = pixmap.pixels().iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "Invalid SVG image buffer".to_string())
+}
+
+/// SVGに書かれた大きさの `scale` 倍でラスタライズする。
+#[cfg(feature = "svg")]
+pub fn render(path: &str, scale: f32) -> Result<DynamicImage, String> {
+    if !(scale.is_finite() && scale > 0.0) {
+        return Err(format!("Invalid scale: {}", scale));
+    }
+    rasterize(&parse(path)?, scale)
+}
+
+/// 長辺がちょうど `size` になるようにラスタライズする。
+#[cfg(feature = "svg")]
+pub fn render_to_fit(path: &str, size: u32) -> Result<DynamicImage, String> {
+    let tree = parse(path)?;
+    let long_side = tree.size().width().max(tree.size().height());
+    rasterize(&tree, size as f32 / long_side)
+}
+
+#[cfg(feature = "svg")]
+pub fn dimensions(path: &str) -> Result<(u32, u32), String> {
+    let size = parse(path)?.size();
+    Ok((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn render(_path: &str, _scale: f32) -> Result<DynamicImage, String> {
+    Err(SVG_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn render_to_fit(_path: &str, _size: u32) -> Result<DynamicImage, String> {
+    Err(SVG_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn dimensions(_path: &str) -> Result<(u32, u32), String> {
+    Err(SVG_UNSUPPORTED.to_string())
+}
+
+#[cfg(all(test, feature = "svg"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const CIRCLE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20">
+        <rect width="40" height="20" fill="#ff0000"/>
+        <circle cx="10" cy="10" r="5" fill="#0000ff"/>
+    </svg>"##;
+
+    fn write_svg(temp_dir: &TempDir, name: &str, content: &str) -> String {
+        let path = temp_dir.path().join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_render() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_svg(&temp_dir, "circle.svg", CIRCLE);
+        assert_eq!(dimensions(&path).unwrap(), (40, 20));
+
+        let image = render(&path, 1.0).unwrap();
+        assert_eq!((image.width(), image.height()), (40, 20));
+        assert_eq!(image.to_rgba8().get_pixel(30, 10).0, [255, 0, 0, 255]);
+        assert_eq!(image.to_rgba8().get_pixel(10, 10).0, [0, 0, 255, 255]);
+
+        let zoomed = render(&path, 2.5).unwrap();
+        assert_eq!((zoomed.width(), zoomed.height()), (100, 50));
+        assert!(render(&path, 0.0).is_err());
+
+        let thumbnail = render_to_fit(&path, 100).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (100, 50));
+    }
+
+    #[test]
+    fn test_render_limits_size() {
+        // 小さなファイルでも大きなviewBoxを持つSVGは、画素数の上限に収まるように縮めて描画する
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_svg(&temp_dir, "huge.svg", r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 200000 100000">
+            <rect width="200000" height="100000" fill="#00ff00"/>
+        </svg>"##);
+        assert_eq!(dimensions(&path).unwrap(), (200000, 100000));
+
+        let image = render(&path, 1.0).unwrap();
+        assert!(image.width() as u64 * image.height() as u64 <= crate::formats::MAX_RASTER_PIXELS);
+        assert_eq!(image.width() / image.height(), 2);
+        assert_eq!(image.to_rgba8().get_pixel(image.width() - 1, image.height() - 1).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_external_references_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let secret = temp_dir.path().join("secret.png");
+        image::RgbaImage::from_pixel(20, 20, image::Rgba([0, 255, 0, 255])).save(&secret).unwrap();
+        let content = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="20" height="20">
+                <image width="20" height="20" xlink:href="{}"/>
+                <image width="20" height="20" xlink:href="secret.png"/>
+            </svg>"#,
+            secret.display()
+        );
+        let path = write_svg(&temp_dir, "external.svg", &content);
+        let image = render(&path, 1.0).unwrap();
+        assert_eq!(image.to_rgba8().get_pixel(10, 10).0, [0, 0, 0, 0]);
+    }
+}
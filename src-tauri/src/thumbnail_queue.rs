@@ -0,0 +1,302 @@
+use serde::{Serialize, Deserialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use log::{debug, error};
+
+pub const DEFAULT_THUMBNAIL_WORKERS: usize = 4;
+pub const DEFAULT_THUMBNAIL_QUEUE_CAPACITY: usize = 1024;
+
+pub const CANCELLED: &str = "Cancelled";
+pub const QUEUE_FULL: &str = "Thumbnail queue is full";
+pub const GENERATOR_PANICKED: &str = "Thumbnail generation panicked";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailPriority {
+    Background,
+    /// 画面に表示されているアイテム。既存の呼び出し元のために既定値とする。
+    #[default]
+    Visible,
+}
+
+pub type ThumbnailResult = Result<String, String>;
+type Generator = Arc<dyn Fn(&str) -> ThumbnailResult + Send + Sync>;
+
+struct PendingJob {
+    path: String,
+    priority: ThumbnailPriority,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: Vec<PendingJob>,
+    /// 待機中・処理中のパスごとの結果の送り先。同じパスの要求は1回の生成にまとめる。
+    waiters: HashMap<String, Vec<oneshot::Sender<ThumbnailResult>>>,
+    running_workers: usize,
+    next_seq: u64,
+}
+
+/// サムネイル生成のワーカープール。
+/// 生成はブロッキング用のスレッドプールで同時に `workers` 件まで行い、
+/// 待機中の要求は優先度(表示中のアイテムが先)、次に要求順で処理する。
+#[derive(Clone)]
+pub struct ThumbnailQueue {
+    generator: Generator,
+    workers: usize,
+    capacity: usize,
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl ThumbnailQueue {
+    pub fn new<F>(workers: usize, capacity: usize, generator: F) -> Self
+    where
+        F: Fn(&str) -> ThumbnailResult + Send + Sync + 'static,
+    {
+        ThumbnailQueue {
+            generator: Arc::new(generator),
+            workers: workers.max(1),
+            capacity,
+            state: Arc::new(Mutex::new(QueueState::default())),
+        }
+    }
+
+    pub async fn submit(&self, path: String, priority: ThumbnailPriority) -> ThumbnailResult {
+        let receiver = self.enqueue(path, priority)?;
+        receiver.await.unwrap_or_else(|_| Err(CANCELLED.to_string()))
+    }
+
+    pub fn enqueue(&self, path: String, priority: ThumbnailPriority) -> Result<oneshot::Receiver<ThumbnailResult>, String> {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(waiters) = state.waiters.get_mut(&path) {
+            waiters.push(sender);
+            // 待機中なら優先度を引き上げる
+            if let Some(job) = state.pending.iter_mut().find(|job| job.path == path) {
+                job.priority = job.priority.max(priority);
+            }
+            return Ok(receiver);
+        }
+
+        if state.pending.len() >= self.capacity {
+            // 満杯の場合は、より優先度の低い最も新しい要求を取り消して場所を空ける
+            let evict = state.pending.iter().enumerate()
+                .filter(|(_, job)| job.priority < priority)
+                .min_by_key(|(_, job)| (job.priority, std::cmp::Reverse(job.seq)))
+                .map(|(index, _)| index);
+            match evict {
+                Some(index) => {
+                    let job = state.pending.swap_remove(index);
+                    debug!("Thumbnail queue full, dropping {}", job.path);
+                    notify(&mut state, &job.path, Err(CANCELLED.to_string()));
+                }
+                None => return Err(QUEUE_FULL.to_string()),
+            }
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.pending.push(PendingJob { path: path.clone(), priority, seq });
+        state.waiters.insert(path, vec![sender]);
+
+        if state.running_workers < self.workers {
+            state.running_workers += 1;
+            let queue = self.clone();
+            tauri::async_runtime::spawn_blocking(move || queue.run_worker());
+        }
+        Ok(receiver)
+    }
+
+    /// `folder` 直下の待機中の要求を取り消す。処理中のものはそのまま完了させる。
+    pub fn cancel_folder(&self, folder: &str) -> usize {
+        let folder = Path::new(folder);
+        let mut state = self.state.lock().unwrap();
+        let (cancelled, pending): (Vec<PendingJob>, Vec<PendingJob>) = state.pending.drain(..)
+            .partition(|job| Path::new(&job.path).parent() == Some(folder));
+        state.pending = pending;
+        for job in &cancelled {
+            notify(&mut state, &job.path, Err(CANCELLED.to_string()));
+        }
+        debug!("Cancelled {} thumbnail requests in {:?}", cancelled.len(), folder);
+        cancelled.len()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn run_worker(&self) {
+        loop {
+            let path = {
+                let mut state = self.state.lock().unwrap();
+                let next = state.pending.iter().enumerate()
+                    .max_by_key(|(_, job)| (job.priority, std::cmp::Reverse(job.seq)))
+                    .map(|(index, _)| index);
+                match next {
+                    Some(index) => state.pending.remove(index).path,
+                    None => {
+                        state.running_workers -= 1;
+                        return;
+                    }
+                }
+            };
+            // デコーダーがパニックしても、待っている要求に結果を返してワーカーを続ける
+            let result = panic::catch_unwind(AssertUnwindSafe(|| (self.generator)(&path)))
+                .unwrap_or_else(|payload| {
+                    error!("Thumbnail generation panicked for {}", path);
+                    Err(format!("{}: {}", GENERATOR_PANICKED, panic_message(&payload)))
+                });
+            notify(&mut self.state.lock().unwrap(), &path, result);
+        }
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+fn notify(state: &mut QueueState, path: &str, result: ThumbnailResult) {
+    for sender in state.waiters.remove(path).unwrap_or_default() {
+        let _ = sender.send(result.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// `gate` に値が送られるまで生成を止めておくジェネレーター
+    fn gated_queue(workers: usize, capacity: usize) -> (ThumbnailQueue, mpsc::Sender<()>, Arc<Mutex<Vec<String>>>) {
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let gate_rx = Mutex::new(gate_rx);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let generated = order.clone();
+        let queue = ThumbnailQueue::new(workers, capacity, move |path| {
+            gate_rx.lock().unwrap().recv_timeout(Duration::from_secs(5)).map_err(|e| e.to_string())?;
+            generated.lock().unwrap().push(path.to_string());
+            Ok(format!("thumbnail:{}", path))
+        });
+        (queue, gate_tx, order)
+    }
+
+    fn wait_for_pending(queue: &ThumbnailQueue, len: usize) {
+        for _ in 0..500 {
+            if queue.pending_len() == len {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Expected {} pending requests, found {}", len, queue.pending_len());
+    }
+
+    #[tokio::test]
+    async fn test_deduplicate_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let queue = ThumbnailQueue::new(2, 16, move |path| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(path.to_string())
+        });
+
+        let (a, b) = tokio::join!(
+            queue.submit("/images/a.jpg".to_string(), ThumbnailPriority::Visible),
+            queue.submit("/images/a.jpg".to_string(), ThumbnailPriority::Background),
+        );
+        assert_eq!(a.unwrap(), "/images/a.jpg");
+        assert_eq!(b.unwrap(), "/images/a.jpg");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_visible_requests_first() {
+        let (queue, gate, order) = gated_queue(1, 16);
+
+        // 最初の要求がワーカーを占有している間に残りを積む
+        let first = queue.enqueue("/images/0.jpg".to_string(), ThumbnailPriority::Background).unwrap();
+        wait_for_pending(&queue, 0);
+        let background = queue.enqueue("/images/1.jpg".to_string(), ThumbnailPriority::Background).unwrap();
+        let visible = queue.enqueue("/images/2.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+
+        for _ in 0..3 {
+            gate.send(()).unwrap();
+        }
+        assert!(first.await.unwrap().is_ok());
+        assert!(visible.await.unwrap().is_ok());
+        assert!(background.await.unwrap().is_ok());
+        assert_eq!(*order.lock().unwrap(), vec!["/images/0.jpg", "/images/2.jpg", "/images/1.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_folder() {
+        let (queue, gate, _order) = gated_queue(1, 16);
+
+        let running = queue.enqueue("/images/a/0.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+        wait_for_pending(&queue, 0);
+        let cancelled = queue.enqueue("/images/a/1.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+        let other = queue.enqueue("/images/b/1.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+
+        assert_eq!(queue.cancel_folder("/images/a"), 1);
+        assert_eq!(cancelled.await.unwrap(), Err(CANCELLED.to_string()));
+
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok(), "Running request should not be cancelled");
+        assert!(other.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_generator_panic() {
+        let queue = ThumbnailQueue::new(1, 16, |path| {
+            if path.ends_with("broken.jpg") {
+                panic!("malformed image");
+            }
+            Ok(path.to_string())
+        });
+
+        let broken = queue.submit("/images/broken.jpg".to_string(), ThumbnailPriority::Visible).await;
+        assert_eq!(broken.unwrap_err(), format!("{}: malformed image", GENERATOR_PANICKED));
+        // 同じパスをもう一度要求しても待ち続けず、ワーカーも止まらない
+        assert!(queue.submit("/images/broken.jpg".to_string(), ThumbnailPriority::Visible).await.is_err());
+        assert_eq!(queue.submit("/images/ok.jpg".to_string(), ThumbnailPriority::Visible).await.unwrap(), "/images/ok.jpg");
+        for _ in 0..200 {
+            if queue.state.lock().unwrap().running_workers == 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("thumbnail worker did not exit");
+    }
+
+    #[tokio::test]
+    async fn test_queue_capacity() {
+        let (queue, gate, _order) = gated_queue(1, 2);
+
+        let running = queue.enqueue("/images/0.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+        wait_for_pending(&queue, 0);
+        let older = queue.enqueue("/images/1.jpg".to_string(), ThumbnailPriority::Background).unwrap();
+        let newer = queue.enqueue("/images/2.jpg".to_string(), ThumbnailPriority::Background).unwrap();
+        assert_eq!(queue.enqueue("/images/3.jpg".to_string(), ThumbnailPriority::Background).unwrap_err(), QUEUE_FULL);
+
+        // 表示中の要求は、最も新しい低優先度の要求を押し出す
+        let visible = queue.enqueue("/images/4.jpg".to_string(), ThumbnailPriority::Visible).unwrap();
+        assert_eq!(newer.await.unwrap(), Err(CANCELLED.to_string()));
+
+        for _ in 0..3 {
+            gate.send(()).unwrap();
+        }
+        assert!(running.await.unwrap().is_ok());
+        assert!(visible.await.unwrap().is_ok());
+        assert!(older.await.unwrap().is_ok());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::formats::is_supported;
 use std::path::{Path, PathBuf};
 use sha2::{Sha256, Digest};
 use tauri::api::path::cache_dir;
@@ -10,12 +11,22 @@ pub fn get_cache_dir() -> PathBuf {
     app_cache_dir
 }
 
-pub fn get_cache_path(original_path: &str) -> PathBuf {
+/// キャッシュの作り方が変わったら上げる。以前のバージョンのキャッシュは使われなくなる。
+/// 2: 埋め込みのICCプロファイルに従ってsRGBに変換するようになった
+pub const CACHE_VERSION: u32 = 2;
+
+/// 生成時の設定ごとに別のキャッシュファイルを使う。
+pub fn get_variant_cache_path(original_path: &str, variant: &str, extension: &str) -> PathBuf {
     let mut hasher = Sha256::new();
     hasher.update(original_path);
+    if !variant.is_empty() {
+        hasher.update("\n");
+        hasher.update(variant);
+    }
+    hasher.update(format!("\nv{}", CACHE_VERSION));
     let hash = hasher.finalize();
     let hash_str = hex::encode(hash);
-    let cache_filename = format!("{}.webp", hash_str);
+    let cache_filename = format!("{}.{}", hash_str, extension);
     get_cache_dir().join(cache_filename)
 }
 
@@ -23,11 +34,7 @@ pub fn get_cache_path(original_path: &str) -> PathBuf {
 use log::debug;
 
 pub fn is_image(path: &Path) -> bool {
-    let extensions = ["jpg", "jpeg", "png", "gif", "bmp", "webp"];
-    let result = path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| extensions.contains(&ext.to_lowercase().as_str()))
-        .unwrap_or(false);
+    let result = is_supported(path);
     debug!("is_image check for {:?}: {}", path, result);
     result
 }
@@ -47,11 +54,14 @@ mod tests {
     }
 
     #[test]
-    fn test_get_cache_path() {
+    fn test_get_variant_cache_path() {
         let original_path = "/tests/resources/image.jpg";
-        let cache_path = get_cache_path(original_path);
+        let cache_path = get_variant_cache_path(original_path, "", "webp");
         assert!(cache_path.extension().unwrap() == "webp", "Expected .webp extension, got {:?}", cache_path.extension());
         assert!(cache_path.file_stem().unwrap().len() == 64, "Expected 64 character hash, got {} characters", cache_path.file_stem().unwrap().len());
+        assert_ne!(get_variant_cache_path(original_path, "lanczos3", "webp"), cache_path);
+        assert_ne!(get_variant_cache_path(original_path, "lanczos3", "webp"), get_variant_cache_path(original_path, "triangle", "webp"));
+        assert_eq!(get_variant_cache_path(original_path, "-jpeg-q80", "jpg").extension().unwrap(), "jpg");
     }
 
     #[test]
@@ -60,6 +70,7 @@ mod tests {
         let image_path = temp_dir.path().join("test.jpg");
         File::create(&image_path).unwrap();
         assert!(is_image(&image_path));
+        assert!(is_image(Path::new("scan.TIFF")));
 
         let non_image_path = temp_dir.path().join("test.txt");
         File::create(&non_image_path).unwrap();
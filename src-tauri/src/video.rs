@@ -0,0 +1,459 @@
+use image::DynamicImage;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+#[cfg(feature = "video")]
+use image::ImageFormat;
+#[cfg(feature = "video")]
+use std::process::{Command, Output, Stdio};
+#[cfg(feature = "video")]
+use std::thread::{self, JoinHandle};
+#[cfg(feature = "video")]
+use std::time::{Duration, Instant};
+
+pub const VIDEO_UNSUPPORTED: &str = "Video thumbnails require the video feature";
+
+/// `moov` ボックスとして読み込む大きさの上限
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+/// WebM/Matroskaで `Info` と `Tracks` を探す範囲
+const EBML_HEAD_SIZE: u64 = 1024 * 1024;
+
+/// ポスターフレームの位置。冒頭の暗転を避けるため、長さの1割 (最大5秒) の位置を使う。
+const POSTER_POSITION: f64 = 0.1;
+const MAX_POSTER_SECONDS: f64 = 5.0;
+
+/// 壊れたファイルなどでffmpegが終わらない場合に打ち切るまでの時間
+#[cfg(feature = "video")]
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(feature = "video")]
+const FFMPEG_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VideoInfo {
+    /// 秒
+    pub duration: f64,
+    /// 回転を反映した表示上の大きさ
+    pub width: u32,
+    pub height: u32,
+}
+
+/// コンテナのヘッダーから長さと解像度を読む。映像はデコードしないので、フィーチャーに関係なく使える。
+pub fn read_video_info(path: &str) -> Result<VideoInfo, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).map_err(|e| e.to_string())?;
+    reader.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    if magic == [0x1A, 0x45, 0xDF, 0xA3] {
+        read_ebml_info(&mut reader)
+    } else {
+        read_mp4_info(&mut reader)
+    }
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be_u64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|b| u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+}
+
+/// MP4/MOVのボックスを順に返す。`(種類, 中身)`
+fn mp4_boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let size = be_u32(data, offset)? as u64;
+        let kind = data.get(offset + 4..offset + 8)?;
+        let (header, size) = match size {
+            0 => (8, (data.len() - offset) as u64),
+            1 => (16, be_u64(data, offset + 8)?),
+            size => (8, size),
+        };
+        let end = offset.checked_add(usize::try_from(size).ok()?)?;
+        let body = data.get(offset + header..end)?;
+        offset = end;
+        Some((kind, body))
+    })
+}
+
+fn read_mp4_info<R: Read + Seek>(reader: &mut R) -> Result<VideoInfo, String> {
+    // `moov` は `mdat` の後ろにあることもあるので、トップレベルのボックスを飛ばしながら探す
+    let moov = loop {
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header[..8]).map_err(|_| "No moov box found".to_string())?;
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            reader.read_exact(&mut header[8..]).map_err(|e| e.to_string())?;
+            size = be_u64(&header, 8).unwrap_or(0);
+            header_size = 16;
+        }
+        if &header[4..8] == b"moov" {
+            let body_size = size.checked_sub(header_size).filter(|size| *size <= MAX_MOOV_SIZE).ok_or("Invalid moov box")?;
+            let mut moov = vec![0; body_size as usize];
+            reader.read_exact(&mut moov).map_err(|e| e.to_string())?;
+            break moov;
+        }
+        if size < header_size {
+            return Err("No moov box found".to_string());
+        }
+        reader.seek(SeekFrom::Current((size - header_size) as i64)).map_err(|e| e.to_string())?;
+    };
+
+    let mut duration = None;
+    let mut dimensions = None;
+    for (kind, body) in mp4_boxes(&moov) {
+        match kind {
+            b"mvhd" => duration = mvhd_duration(body),
+            b"trak" if dimensions.is_none() => {
+                dimensions = mp4_boxes(body).find(|(kind, _)| kind == b"tkhd").and_then(|(_, tkhd)| tkhd_dimensions(tkhd));
+            }
+            _ => {}
+        }
+    }
+    let (width, height) = dimensions.ok_or("No video track found")?;
+    Ok(VideoInfo { duration: duration.unwrap_or(0.0), width, height })
+}
+
+fn mvhd_duration(mvhd: &[u8]) -> Option<f64> {
+    let (timescale, duration) = if mvhd.first()? == &1 {
+        (be_u32(mvhd, 20)?, be_u64(mvhd, 24)?)
+    } else {
+        (be_u32(mvhd, 12)?, be_u32(mvhd, 16)? as u64)
+    };
+    (timescale > 0).then(|| duration as f64 / timescale as f64)
+}
+
+/// 音声トラックの大きさは0なので、大きさのあるトラックだけを映像として扱う。
+/// 変換行列が90度・270度の回転なら縦横を入れ替える。
+fn tkhd_dimensions(tkhd: &[u8]) -> Option<(u32, u32)> {
+    let matrix = if tkhd.first()? == &1 { 52 } else { 40 };
+    let a = be_u32(tkhd, matrix)? as i32;
+    let b = be_u32(tkhd, matrix + 4)? as i32;
+    let width = be_u32(tkhd, matrix + 36)? >> 16;
+    let height = be_u32(tkhd, matrix + 40)? >> 16;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    if a == 0 && b.abs() == 0x10000 {
+        Some((height, width))
+    } else {
+        Some((width, height))
+    }
+}
+
+const EBML_SEGMENT: u32 = 0x18538067;
+const EBML_INFO: u32 = 0x1549A966;
+const EBML_TIMECODE_SCALE: u32 = 0x2AD7B1;
+const EBML_DURATION: u32 = 0x4489;
+const EBML_TRACKS: u32 = 0x1654AE6B;
+const EBML_TRACK_ENTRY: u32 = 0xAE;
+const EBML_VIDEO: u32 = 0xE0;
+const EBML_PIXEL_WIDTH: u32 = 0xB0;
+const EBML_PIXEL_HEIGHT: u32 = 0xBA;
+const EBML_CLUSTER: u32 = 0x1F43B675;
+
+/// 可変長整数を読む。IDは先頭のビットを残し、サイズは外す。全ビットが1のサイズは `None` (長さ不明)。
+fn ebml_vint(data: &[u8], offset: usize, keep_marker: bool) -> Option<(Option<u64>, usize)> {
+    let first = *data.get(offset)?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+    let bytes = data.get(offset..offset + length)?;
+    let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xFF >> length) };
+    for byte in &bytes[1..] {
+        value = (value << 8) | *byte as u64;
+    }
+    let unknown = !keep_marker && value == (1u64 << (7 * length)) - 1;
+    Some(((!unknown).then_some(value), length))
+}
+
+/// 要素を順に返す。`(ID, 中身)`。長さ不明の要素は残り全部を中身とする。
+fn ebml_elements(data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let (id, id_length) = ebml_vint(data, offset, true)?;
+        let (size, size_length) = ebml_vint(data, offset + id_length, false)?;
+        let start = offset + id_length + size_length;
+        let end = match size {
+            Some(size) => start.checked_add(usize::try_from(size).ok()?)?.min(data.len()),
+            None => data.len(),
+        };
+        offset = end;
+        Some((id? as u32, data.get(start..end)?))
+    })
+}
+
+fn ebml_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// `Info` と `Tracks` はクラスターより前にあるので、先頭だけを読んで探す。
+fn read_ebml_info<R: Read>(reader: &mut R) -> Result<VideoInfo, String> {
+    let mut head = Vec::new();
+    reader.by_ref().take(EBML_HEAD_SIZE).read_to_end(&mut head).map_err(|e| e.to_string())?;
+    let segment = ebml_elements(&head)
+        .find(|(id, _)| *id == EBML_SEGMENT)
+        .map(|(_, segment)| segment)
+        .ok_or("No segment found")?;
+
+    let mut timecode_scale = 1_000_000;
+    let mut duration = None;
+    let mut dimensions = None;
+    for (id, body) in ebml_elements(segment) {
+        match id {
+            EBML_INFO => {
+                for (id, value) in ebml_elements(body) {
+                    match id {
+                        EBML_TIMECODE_SCALE => timecode_scale = ebml_uint(value),
+                        EBML_DURATION => duration = ebml_float(value),
+                        _ => {}
+                    }
+                }
+            }
+            EBML_TRACKS if dimensions.is_none() => {
+                dimensions = ebml_elements(body)
+                    .filter(|(id, _)| *id == EBML_TRACK_ENTRY)
+                    .flat_map(|(_, entry)| ebml_elements(entry))
+                    .find(|(id, _)| *id == EBML_VIDEO)
+                    .and_then(|(_, video)| {
+                        let mut width = None;
+                        let mut height = None;
+                        for (id, value) in ebml_elements(video) {
+                            match id {
+                                EBML_PIXEL_WIDTH => width = Some(ebml_uint(value) as u32),
+                                EBML_PIXEL_HEIGHT => height = Some(ebml_uint(value) as u32),
+                                _ => {}
+                            }
+                        }
+                        Some((width?, height?))
+                    });
+            }
+            EBML_CLUSTER => break,
+            _ => {}
+        }
+    }
+    let (width, height) = dimensions.ok_or("No video track found")?;
+    let duration = duration.map(|duration| duration * timecode_scale as f64 / 1e9).unwrap_or(0.0);
+    Ok(VideoInfo { duration, width, height })
+}
+
+/// 一覧の表示を待たせないよう、フロントエンドは表示された動画ごとに後から呼ぶ。
+#[tauri::command]
+pub async fn get_video_info(path: String) -> Result<VideoInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || read_video_info(&path))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+pub fn dimensions(path: &str) -> Result<(u32, u32), String> {
+    let info = read_video_info(path)?;
+    Ok((info.width, info.height))
+}
+
+fn poster_seconds(duration: f64) -> f64 {
+    (duration * POSTER_POSITION).min(MAX_POSTER_SECONDS)
+}
+
+/// ffmpegでポスターフレームを1枚取り出す。`size` を指定すると長辺がその大きさになるように縮小させる。
+#[cfg(feature = "video")]
+fn extract_poster(path: &str, size: Option<u32>) -> Result<DynamicImage, String> {
+    let seconds = read_video_info(path).map(|info| poster_seconds(info.duration)).unwrap_or(0.0);
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error", "-nostdin", "-ss", &format!("{:.3}", seconds), "-i"])
+        .arg(path)
+        .args(["-frames:v", "1"]);
+    if let Some(size) = size {
+        command.args(["-vf", &format!("scale={0}:{0}:force_original_aspect_ratio=decrease", size)]);
+    }
+    command.args(["-f", "image2pipe", "-c:v", "png", "-"]);
+    let output = output_with_timeout(&mut command, FFMPEG_TIMEOUT)?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(format!("ffmpeg failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    image::load_from_memory_with_format(&output.stdout, ImageFormat::Png).map_err(|e| e.to_string())
+}
+
+/// `Command::output` と同じだが、`timeout` を過ぎたらプロセスを終了させてエラーにする。
+#[cfg(feature = "video")]
+fn output_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output, String> {
+    let mut child = command.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    // パイプが詰まって止まらないよう、終了を待つ間も出力を読み続ける
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("ffmpeg timed out after {:?}", timeout));
+            }
+            None => thread::sleep(FFMPEG_POLL_INTERVAL),
+        }
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+#[cfg(feature = "video")]
+fn read_in_background<R: Read + Send + 'static>(reader: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut reader) = reader {
+            let _ = reader.read_to_end(&mut data);
+        }
+        data
+    })
+}
+
+#[cfg(feature = "video")]
+pub fn poster_frame(path: &str) -> Result<DynamicImage, String> {
+    extract_poster(path, None)
+}
+
+#[cfg(feature = "video")]
+pub fn poster_frame_to_fit(path: &str, size: u32) -> Result<DynamicImage, String> {
+    extract_poster(path, Some(size))
+}
+
+#[cfg(not(feature = "video"))]
+pub fn poster_frame(_path: &str) -> Result<DynamicImage, String> {
+    Err(VIDEO_UNSUPPORTED.to_string())
+}
+
+#[cfg(not(feature = "video"))]
+pub fn poster_frame_to_fit(_path: &str, _size: u32) -> Result<DynamicImage, String> {
+    Err(VIDEO_UNSUPPORTED.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn tkhd(width: u32, height: u32, rotated: bool) -> Vec<u8> {
+        let mut body = vec![0u8; 84];
+        let (a, b, c, d) = if rotated { (0, 0x10000, -0x10000i32 as u32, 0) } else { (0x10000, 0, 0, 0x10000) };
+        for (index, value) in [a, b, 0, c, d, 0, 0, 0, 0x40000000].iter().enumerate() {
+            body[40 + index * 4..44 + index * 4].copy_from_slice(&value.to_be_bytes());
+        }
+        body[76..80].copy_from_slice(&(width << 16).to_be_bytes());
+        body[80..84].copy_from_slice(&(height << 16).to_be_bytes());
+        mp4_box(b"tkhd", &body)
+    }
+
+    fn write_mp4(temp_dir: &TempDir, rotated: bool) -> String {
+        let mut mvhd = vec![0u8; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&12500u32.to_be_bytes());
+        let audio = mp4_box(b"trak", &tkhd(0, 0, false));
+        let video = mp4_box(b"trak", &tkhd(1920, 1080, rotated));
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), audio, video].concat());
+
+        // `moov` を `mdat` の後ろに置く
+        let data = [mp4_box(b"ftyp", b"isom\0\0\0\0"), mp4_box(b"mdat", &[0u8; 4096]), moov].concat();
+        let path = temp_dir.path().join(if rotated { "portrait.mov" } else { "clip.mp4" });
+        fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn ebml(id: u32, body: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let mut data = id_bytes[id.leading_zeros() as usize / 8..].to_vec();
+        // 8バイトのサイズ表現
+        data.push(0x01);
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn test_mp4_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_mp4(&temp_dir, false);
+        assert_eq!(read_video_info(&path).unwrap(), VideoInfo { duration: 12.5, width: 1920, height: 1080 });
+
+        let portrait = write_mp4(&temp_dir, true);
+        assert_eq!(dimensions(&portrait).unwrap(), (1080, 1920));
+    }
+
+    #[test]
+    fn test_webm_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let info = ebml(EBML_INFO, &[
+            ebml(EBML_TIMECODE_SCALE, &1_000_000u32.to_be_bytes()),
+            ebml(EBML_DURATION, &3500.0f64.to_be_bytes()),
+        ].concat());
+        let audio = ebml(EBML_TRACK_ENTRY, &ebml(0xE1, &[]));
+        let video = ebml(EBML_TRACK_ENTRY, &ebml(EBML_VIDEO, &[
+            ebml(EBML_PIXEL_WIDTH, &[0x02, 0x80]),
+            ebml(EBML_PIXEL_HEIGHT, &[0x01, 0xE0]),
+        ].concat()));
+        let tracks = ebml(EBML_TRACKS, &[audio, video].concat());
+        // 長さ不明のセグメント
+        let mut segment = vec![0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        segment.extend([info, tracks, ebml(EBML_CLUSTER, &[0u8; 64])].concat());
+        let data = [ebml(0x1A45DFA3, &ebml(0x4282, b"webm")), segment].concat();
+
+        let path = temp_dir.path().join("clip.webm");
+        fs::write(&path, data).unwrap();
+        assert_eq!(read_video_info(path.to_str().unwrap()).unwrap(), VideoInfo { duration: 3.5, width: 640, height: 480 });
+    }
+
+    #[test]
+    fn test_invalid_video() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("broken.mp4");
+        fs::write(&path, b"not a video").unwrap();
+        assert!(read_video_info(path.to_str().unwrap()).is_err());
+        assert_eq!(poster_seconds(12.5), 1.25);
+        assert_eq!(poster_seconds(600.0), MAX_POSTER_SECONDS);
+    }
+
+    #[cfg(all(feature = "video", unix))]
+    #[test]
+    fn test_output_with_timeout() {
+        let started = Instant::now();
+        let error = output_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(100)).unwrap_err();
+        assert!(error.contains("timed out"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // パイプの容量より大きい出力も最後まで受け取る
+        let output = output_with_timeout(Command::new("head").args(["-c", "1000000", "/dev/zero"]), Duration::from_secs(10)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.len(), 1_000_000);
+    }
+
+    #[cfg(not(feature = "video"))]
+    #[test]
+    fn test_poster_unsupported() {
+        assert_eq!(poster_frame_to_fit("clip.mp4", 100).unwrap_err(), VIDEO_UNSUPPORTED);
+    }
+}
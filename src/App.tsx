@@ -1,10 +1,11 @@
-import { useState, useEffect, useCallback, useMemo } from 'react';
+import { useState, useEffect, useCallback, useMemo, useRef } from 'react';
 import { invoke } from '@tauri-apps/api/tauri';
 import { WebviewWindow, getCurrent } from '@tauri-apps/api/window';
-import { convertFileSrc } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
 import { FolderTree } from './components/FolderTree';
 import { ImageGrid } from './components/ImageGrid';
 import { SortControls } from './components/SortControls';
+import { useImageSrc } from './utils/formats';
 
 interface FileItem {
   name: string;
@@ -14,9 +15,26 @@ interface FileItem {
   size: number;
 }
 
+interface ImageState {
+  current_index: number;
+  images: string[];
+  zoom: number;
+  sort_by: 'name' | 'type' | 'date' | 'size';
+  sort_order: 'asc' | 'desc';
+}
+
+interface NavigationResult {
+  index: number;
+  total: number;
+  path: string;
+  previous: string | null;
+  next: string | null;
+}
+
 interface StartupInfo {
   folder: string;
   file: string | null;
+  session: ImageState | null;
 }
 
 function App() {
@@ -29,6 +47,11 @@ function App() {
   const [fullImageList, setFullImageList] = useState<string[]>([]);
   const [expandedImageIndex, setExpandedImageIndex] = useState<number | null>(null);
   const [zoomLevel, setZoomLevel] = useState(1);
+  // SVGはズームに合わせてバックエンドで描き直し、拡大してもぼやけないようにする
+  const viewerImageSrc = useImageSrc(isCloneWindow ? selectedImagePath : null, {
+    prefetched: true,
+    zoom: zoomLevel * window.devicePixelRatio,
+  });
 
   useEffect(() => {
     const searchParams = new URLSearchParams(window.location.search);
@@ -39,7 +62,7 @@ function App() {
       const imagePath = searchParams.get('imagePath');
       if (imagePath) {
         setSelectedImagePath(imagePath);
-        loadImageList(imagePath);
+        loadImageList(imagePath, true);
       }
     } else {
       initializeApp();
@@ -58,6 +81,12 @@ function App() {
     }
   }, [isCloneWindow, expandedImageIndex, fullImageList, zoomLevel]);
 
+  useEffect(() => {
+    if (isCloneWindow && expandedImageIndex !== null) {
+      invoke('set_session_position', { currentIndex: expandedImageIndex, zoom: zoomLevel });
+    }
+  }, [isCloneWindow, expandedImageIndex, zoomLevel]);
+
   useEffect(() => {
     if (currentPath) {
       loadDirectory(currentPath);
@@ -69,6 +98,15 @@ function App() {
     try {
       const startupInfo: StartupInfo = await invoke('get_startup_info');
       setCurrentPath(startupInfo.folder);
+
+      // 前回のセッションを復元。状態の更新はこの関数の中では反映されないので、並び順は直接渡す
+      const restoredSortBy = startupInfo.session?.sort_by ?? sortBy;
+      const restoredSortOrder = startupInfo.session?.sort_order ?? sortOrder;
+      if (startupInfo.session) {
+        setSortBy(startupInfo.session.sort_by);
+        setSortOrder(startupInfo.session.sort_order);
+        setZoomLevel(startupInfo.session.zoom);
+      }
       
       if (startupInfo.file) {
         setSelectedImagePath(startupInfo.file);
@@ -78,8 +116,8 @@ function App() {
           invoke<FileItem[]>('get_directory_contents', { path: startupInfo.folder }),
           invoke<string[]>('get_full_image_list', { 
             path: startupInfo.file,
-            sortBy: sortBy.toLowerCase(),
-            sortOrder: sortOrder.toLowerCase()
+            sortBy: restoredSortBy.toLowerCase(),
+            sortOrder: restoredSortOrder.toLowerCase()
           })
         ]);
         
@@ -103,6 +141,23 @@ function App() {
     initializeApp();
   }, [initializeApp]);
 
+  // 2つ目以降の起動で渡されたファイルを既存のウィンドウで開く。
+  // handleImageSelect は描画ごとに作り直されるので、リスナーは最新のものを参照する
+  const handleImageSelectRef = useRef<(path: string) => Promise<void>>();
+  useEffect(() => {
+    if (isCloneWindow) return;
+    const unlisten = listen<StartupInfo>('open-file', (event) => {
+      const { folder, file } = event.payload;
+      setCurrentPath(folder);
+      if (file) {
+        handleImageSelectRef.current?.(file);
+      }
+    });
+    return () => {
+      unlisten.then(f => f());
+    };
+  }, [isCloneWindow]);
+
   const selectFolder = async () => {
     try {
       const selectedPath = await invoke<string>('select_folder');
@@ -124,7 +179,8 @@ function App() {
     }
   };
 
-  const loadImageList = async (imagePath: string) => {
+  // 前後の画像への移動はクローンウィンドウだけが行うので、バックエンドの画像リストはそこでだけ開く
+  const loadImageList = async (imagePath: string, openPlaylist = false) => {
     try {
       const result = await invoke<string[]>('get_full_image_list', { 
         path: imagePath,
@@ -132,9 +188,25 @@ function App() {
         sortOrder: sortOrder.toLowerCase()
       });
       setFullImageList(result);
+      if (openPlaylist) {
+        await invoke<NavigationResult>('open_image_list', {
+          path: imagePath,
+          sortBy: sortBy.toLowerCase(),
+          sortOrder: sortOrder.toLowerCase()
+        });
+      }
       const selectedIndex = result.findIndex(path => path === imagePath);
       setExpandedImageIndex(selectedIndex !== -1 ? selectedIndex : 0);
       setSelectedImagePath(imagePath);
+      invoke('update_session', {
+        session: {
+          current_index: selectedIndex !== -1 ? selectedIndex : 0,
+          images: result,
+          zoom: zoomLevel,
+          sort_by: sortBy,
+          sort_order: sortOrder
+        }
+      });
     } catch (error) {
       console.error('Error loading image list:', error);
     }
@@ -186,20 +258,18 @@ function App() {
     }
   }, [expandedImageIndex, fullImageList]);
 
-  const navigateImage = (direction: 'next' | 'prev') => {
+  const navigateImage = async (direction: 'next' | 'prev') => {
     if (expandedImageIndex === null) return;
-    
-    let newIndex = direction === 'next' ? expandedImageIndex + 1 : expandedImageIndex - 1;
-    
-    if (newIndex < 0) {
-      newIndex = fullImageList.length - 1;
-    } else if (newIndex >= fullImageList.length) {
-      newIndex = 0;
-    }
 
-    setExpandedImageIndex(newIndex);
-    setSelectedImagePath(fullImageList[newIndex]);
-    setZoomLevel(1);
+    try {
+      // 画像リストはバックエンドがウィンドウごとに保持している
+      const result = await invoke<NavigationResult>('navigate', { navigation: { type: direction } });
+      setExpandedImageIndex(result.index);
+      setSelectedImagePath(result.path);
+      setZoomLevel(1);
+    } catch (error) {
+      console.error('Error navigating image:', error);
+    }
   };
 
   const handleFolderSelect = (path: string) => {
@@ -237,6 +307,7 @@ function App() {
       console.error('Error opening clone window:', error);
     }
   };
+  handleImageSelectRef.current = handleImageSelect;
 
   const handleSortByChange = (newSortBy: 'name' | 'type' | 'date' | 'size') => {
     setSortBy(newSortBy);
@@ -294,7 +365,7 @@ function App() {
           overflow: 'hidden'
         }}>
           <img 
-            src={convertFileSrc(selectedImagePath)}
+            src={viewerImageSrc ?? undefined}
             alt="Selected image" 
             style={{ 
               maxWidth: '100%', 
@@ -1,5 +1,5 @@
 import React, { useState, useEffect, useCallback } from 'react';
-import { convertFileSrc } from '@tauri-apps/api/tauri';
+import { useImageSrc } from '../utils/formats';
 
 interface ExpandedImageProps {
   imagePath: string;
@@ -9,6 +9,7 @@ interface ExpandedImageProps {
 
 const ExpandedImage: React.FC<ExpandedImageProps> = ({ imagePath, onClose, onNavigate }) => {
   const [zoomLevel, setZoomLevel] = useState(1);
+  const src = useImageSrc(imagePath);
 
   const handleKeyDown = useCallback((e: KeyboardEvent) => {
     switch (e.key) {
@@ -52,7 +53,7 @@ const ExpandedImage: React.FC<ExpandedImageProps> = ({ imagePath, onClose, onNav
     <div className="fixed inset-0 bg-black bg-opacity-75 flex items-center justify-center z-50" onClick={onClose}>
       <div className="max-w-full max-h-full p-4 overflow-hidden">
         <img 
-          src={convertFileSrc(imagePath)} 
+          src={src ?? undefined} 
           alt="Expanded view" 
           className="max-w-full max-h-full object-contain transition-transform duration-200"
           style={{ transform: `scale(${zoomLevel})` }}
@@ -1,13 +1,61 @@
-import React, { useRef, useCallback, useMemo } from 'react';
-import { convertFileSrc } from '@tauri-apps/api/tauri';
+import React, { useRef, useCallback, useMemo, useEffect, useState } from 'react';
+import { invoke } from '@tauri-apps/api/tauri';
 import ExpandedImage from './ExpandedImage';
+import { useImageSrc, useSupportedFormats } from '../utils/formats';
 
 interface FileItem {
   name: string;
   path: string;
   is_dir: boolean;
+  raw_pair?: string | null;
+  is_video?: boolean;
 }
 
+const formatDuration = (seconds: number) => {
+  const total = Math.round(seconds);
+  const minutes = Math.floor(total / 60);
+  return `${minutes}:${String(total % 60).padStart(2, '0')}`;
+};
+
+interface VideoInfo {
+  duration: number;
+  width: number;
+  height: number;
+}
+
+// 一覧の取得を待たせないよう、長さと解像度は表示された項目ごとに後から読む
+const VideoBadge: React.FC<{ path: string }> = ({ path }) => {
+  const [info, setInfo] = useState<VideoInfo | null>(null);
+
+  useEffect(() => {
+    let cancelled = false;
+    invoke<VideoInfo>('get_video_info', { path })
+      .then((videoInfo) => {
+        if (!cancelled) setInfo(videoInfo);
+      })
+      .catch((error) => console.error('Failed to read video info:', error));
+    return () => {
+      cancelled = true;
+    };
+  }, [path]);
+
+  if (!info) return null;
+  return (
+    <span
+      className="absolute top-1 left-1 bg-black bg-opacity-50 text-white text-xs px-1 rounded"
+      title={`${info.width}×${info.height}`}
+    >
+      {formatDuration(info.duration)}
+    </span>
+  );
+};
+
+const GridImage: React.FC<{ path: string; name: string }> = ({ path, name }) => {
+  const src = useImageSrc(path);
+  if (!src) return <div className="w-full h-full bg-gray-100" />;
+  return <img src={src} alt={name} className="w-full h-full object-cover" />;
+};
+
 interface ImageGridProps {
   files: FileItem[];
   onFileClick: (path: string) => void;
@@ -23,6 +71,7 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
   expandedImageIndex,
   setExpandedImageIndex
 }) => {
+  const { isImage } = useSupportedFormats();
   const clickTimeoutRef = useRef<number | null>(null);
   const clickCountRef = useRef(0);
 
@@ -37,7 +86,7 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
     clickTimeoutRef.current = window.setTimeout(() => {
       if (clickCountRef.current === 1) {
         // シングルクリック
-        if (!file.is_dir && file.name.match(/\.(jpg|jpeg|png|gif|bmp|webp)$/i)) {
+        if (!file.is_dir && isImage(file.name)) {
           setExpandedImageIndex(index);
         } else if (file.is_dir) {
           onFileClick(file.path);
@@ -52,7 +101,7 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
       }
       clickCountRef.current = 0;
     }, 200); // 200ミリ秒の遅延
-  }, [files, onFileClick, onImageSelect, setExpandedImageIndex]);
+  }, [files, onFileClick, onImageSelect, setExpandedImageIndex, isImage]);
 
   const handleNavigate = useCallback((direction: 'prev' | 'next') => {
     if (expandedImageIndex === null) return;
@@ -62,7 +111,7 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
     // Skip non-image files and folders
     while (newIndex >= 0 && newIndex < files.length) {
       const file = files[newIndex];
-      if (!file.is_dir && file.name.match(/\.(jpg|jpeg|png|gif|bmp|webp)$/i)) {
+      if (!file.is_dir && isImage(file.name)) {
         break;
       }
       newIndex += direction === 'next' ? 1 : -1;
@@ -71,7 +120,7 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
     if (newIndex >= 0 && newIndex < files.length) {
       setExpandedImageIndex(newIndex);
     }
-  }, [expandedImageIndex, files, setExpandedImageIndex]);
+  }, [expandedImageIndex, files, setExpandedImageIndex, isImage]);
 
   const renderGridItem = useCallback((file: FileItem, index: number) => (
     <div
@@ -86,12 +135,8 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
           </svg>
           <p className="mt-2 text-xs text-center text-gray-600 px-2 truncate">{file.name}</p>
         </div>
-      ) : file.name.match(/\.(jpg|jpeg|png|gif|bmp|webp)$/i) ? (
-        <img 
-          src={convertFileSrc(file.path)} 
-          alt={file.name} 
-          className="w-full h-full object-cover"
-        />
+      ) : isImage(file.name) ? (
+        <GridImage path={file.path} name={file.name} />
       ) : (
         <div className="w-full h-full flex flex-col items-center justify-center bg-gray-100">
           <svg className="w-16 h-16 text-gray-400" fill="none" stroke="currentColor" viewBox="0 0 24 24" xmlns="http://www.w3.org/2000/svg">
@@ -105,8 +150,14 @@ export const ImageGrid: React.FC<ImageGridProps> = React.memo(({
           {file.name}
         </p>
       )}
+      {file.raw_pair && (
+        <span className="absolute top-1 right-1 bg-black bg-opacity-50 text-white text-xs px-1 rounded">
+          RAW
+        </span>
+      )}
+      {file.is_video && <VideoBadge path={file.path} />}
     </div>
-  ), [handleItemClick]);
+  ), [handleItemClick, isImage]);
 
   const gridItems = useMemo(() => files.map(renderGridItem), [files, renderGridItem]);
 
@@ -1,7 +1,7 @@
 import React, { useState, useEffect, useCallback } from 'react';
-import { convertFileSrc } from '@tauri-apps/api/tauri';
 import { invoke } from '@tauri-apps/api/tauri';
 import { logInfo, logError } from '../utils/logger';
+import { useImageSrc } from '../utils/formats';
 
 interface ImageViewerProps {
   initialPath: string;
@@ -13,6 +13,7 @@ const ImageViewer: React.FC<ImageViewerProps> = ({ initialPath, sortBy, sortOrde
   const [currentImagePath, setCurrentImagePath] = useState<string | null>(null);
   const [fullImageList, setFullImageList] = useState<string[]>([]);
   const [currentIndex, setCurrentIndex] = useState<number>(0);
+  const currentImageSrc = useImageSrc(currentImagePath, { prefetched: true });
 
   const loadImageList = useCallback(async (path: string) => {
     try {
@@ -27,7 +28,7 @@ const ImageViewer: React.FC<ImageViewerProps> = ({ initialPath, sortBy, sortOrde
       const selectedIndex = result.findIndex(imgPath => imgPath === path);
       logInfo('Selected index:', selectedIndex);
       setCurrentIndex(selectedIndex !== -1 ? selectedIndex : 0);
-      setCurrentImagePath(convertFileSrc(path));
+      setCurrentImagePath(path);
     } catch (error) {
       logError('Error loading image list:', error);
     }
@@ -51,7 +52,7 @@ const ImageViewer: React.FC<ImageViewerProps> = ({ initialPath, sortBy, sortOrde
     setCurrentIndex(newIndex);
     const newPath = fullImageList[newIndex];
     logInfo('New image path:', newPath);
-    setCurrentImagePath(convertFileSrc(newPath));
+    setCurrentImagePath(newPath);
   }, [currentIndex, fullImageList]);
 
   useEffect(() => {
@@ -88,13 +89,13 @@ const ImageViewer: React.FC<ImageViewerProps> = ({ initialPath, sortBy, sortOrde
 
   console.log('Current state:', { currentIndex, fullImageList: fullImageList.length, currentImagePath });
 
-  if (!currentImagePath) {
+  if (!currentImageSrc) {
     return <div>Loading...</div>;
   }
 
   return (
     <div style={{ width: '100%', height: '100%', display: 'flex', justifyContent: 'center', alignItems: 'center' }}>
-      <img src={currentImagePath} alt="Viewed image" style={{ maxWidth: '100%', maxHeight: '100%', objectFit: 'contain' }} />
+      <img src={currentImageSrc} alt="Viewed image" style={{ maxWidth: '100%', maxHeight: '100%', objectFit: 'contain' }} />
     </div>
   );
 };
@@ -0,0 +1,100 @@
+import { useEffect, useState, useCallback } from 'react';
+import { invoke, convertFileSrc } from '@tauri-apps/api/tauri';
+
+export interface SupportedFormat {
+  name: string;
+  extensions: string[];
+  mime_type: string;
+  browser_native: boolean;
+}
+
+export interface SupportedFormats {
+  images: SupportedFormat[];
+  // 仮想フォルダとして開く書庫の拡張子
+  archive_extensions: string[];
+}
+
+// 一覧は起動中に変わらないので、最初の呼び出し結果を使い回す
+let supportedFormats: Promise<SupportedFormats> | null = null;
+
+export const getSupportedFormats = (): Promise<SupportedFormats> => {
+  if (supportedFormats === null) {
+    supportedFormats = invoke<SupportedFormats>('get_supported_formats');
+  }
+  return supportedFormats;
+};
+
+const extensionOf = (name: string) => name.slice(name.lastIndexOf('.') + 1).toLowerCase();
+
+// 書庫の中の画像はファイルとして存在しないので、バックエンドから取り出してもらう
+const isInArchive = (path: string, archiveExtensions: string[]) =>
+  path.split(/[\\/]/).slice(0, -1).some(part => part.includes('.') && archiveExtensions.includes(extensionOf(part)));
+
+export const useSupportedFormats = () => {
+  const [formats, setFormats] = useState<SupportedFormat[]>([]);
+  const [archiveExtensions, setArchiveExtensions] = useState<string[]>([]);
+
+  useEffect(() => {
+    getSupportedFormats()
+      .then(({ images, archive_extensions }) => {
+        setFormats(images);
+        setArchiveExtensions(archive_extensions);
+      })
+      .catch(console.error);
+  }, []);
+
+  const isImage = useCallback((name: string) => {
+    const extension = extensionOf(name);
+    return formats.some(format => format.extensions.includes(extension));
+  }, [formats]);
+
+  // WebViewが表示できない形式はバックエンドでデコードしたものを表示する
+  const imageSrc = useCallback((path: string) => {
+    const extension = extensionOf(path);
+    const format = formats.find(format => format.extensions.includes(extension));
+    return (format && !format.browser_native) || isInArchive(path, archiveExtensions) ? convertFileSrc(path, 'decoded') : convertFileSrc(path);
+  }, [formats, archiveExtensions]);
+
+  return { formats, isImage, imageSrc };
+};
+
+interface ImageSrcOptions {
+  // 先読みした画像を使えるよう、WebViewが表示できる形式も `decoded://` で読む
+  prefetched?: boolean;
+  // SVGをこの倍率で描き直す
+  zoom?: number;
+}
+
+// `decoded://` はバックエンドで用意済みの画像しか返さないので、先にデコードを頼んでからURLを渡す。
+// 用意ができるまでは null を返す。
+export const useImageSrc = (path: string | null, { prefetched = false, zoom }: ImageSrcOptions = {}) => {
+  const { formats, imageSrc } = useSupportedFormats();
+  const [src, setSrc] = useState<string | null>(null);
+
+  useEffect(() => {
+    if (path === null || formats.length === 0) {
+      setSrc(null);
+      return;
+    }
+    const extension = extensionOf(path);
+    const format = formats.find(format => format.extensions.includes(extension));
+    const svgZoom = zoom !== undefined && /^svgz?$/.test(extension) ? zoom : undefined;
+    if (svgZoom === undefined && (!format || format.browser_native)) {
+      setSrc(prefetched ? convertFileSrc(path, 'decoded') : imageSrc(path));
+      return;
+    }
+    let cancelled = false;
+    invoke('prepare_decoded_image', { path, zoom: svgZoom ?? null })
+      .then(() => {
+        if (cancelled) return;
+        const src = convertFileSrc(path, 'decoded');
+        setSrc(svgZoom === undefined ? src : `${src}?zoom=${svgZoom}`);
+      })
+      .catch((error) => console.error('Failed to decode image:', error));
+    return () => {
+      cancelled = true;
+    };
+  }, [path, prefetched, zoom, formats, imageSrc]);
+
+  return src;
+};